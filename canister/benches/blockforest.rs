@@ -0,0 +1,105 @@
+//! A benchmark of the `BlockForest` with a few thousand forked blocks.
+//!
+//! Run with `cargo bench --bench blockforest`.
+use bitcoin::{Block, BlockHash};
+use btc::{blockforest::BlockForest, test_builder::BlockBuilder};
+use std::time::{Duration, Instant};
+
+// The length of the main chain.
+const CHAIN_LENGTH: usize = 1_000;
+
+// Every `FORK_INTERVAL` blocks of the main chain, a fork of `FORK_LENGTH` blocks is added.
+const FORK_INTERVAL: usize = 5;
+const FORK_LENGTH: usize = 10;
+
+const DELTA: u64 = 6;
+
+// Builds a main chain on top of `genesis`, along with forks that branch off of it.
+// Forks are only added where they don't prevent the main chain from becoming stable.
+fn build_blocks(genesis: &Block) -> Vec<Block> {
+    let mut blocks = vec![];
+    let mut tip = genesis.header;
+    for i in 0..CHAIN_LENGTH {
+        if i % FORK_INTERVAL == 0 && i + FORK_LENGTH + DELTA as usize <= CHAIN_LENGTH {
+            let mut fork_tip = tip;
+            for _ in 0..FORK_LENGTH {
+                let block = BlockBuilder::with_prev_header(fork_tip).build();
+                fork_tip = block.header;
+                blocks.push(block);
+            }
+        }
+
+        let block = BlockBuilder::with_prev_header(tip).build();
+        tip = block.header;
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn bench<R>(name: &str, iterations: u32, mut f: impl FnMut() -> R) -> R {
+    let mut result = None;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        result = Some(f());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<20} {:>10.3?} per iteration ({} iterations)",
+        name,
+        elapsed / iterations,
+        iterations
+    );
+    result.expect("at least one iteration")
+}
+
+fn push_all(blocks: &[Block]) -> BlockForest {
+    let mut forest = BlockForest::new(DELTA);
+    for block in blocks {
+        forest.push(block.clone());
+    }
+    forest
+}
+
+fn pop_all(mut forest: BlockForest, anchor: BlockHash) -> usize {
+    let mut anchor = anchor;
+    let mut popped = 0;
+    while let Some(block) = forest.pop(&anchor) {
+        anchor = block.block_hash();
+        popped += 1;
+    }
+    popped
+}
+
+fn main() {
+    let genesis = BlockBuilder::genesis().build();
+    let anchor = genesis.block_hash();
+    let blocks = build_blocks(&genesis);
+    println!("{} blocks, delta = {}", blocks.len(), DELTA);
+
+    let forest = bench("push", 10, || push_all(&blocks));
+
+    let chain_length = bench("get_current_chain", 100, || {
+        forest.get_current_chain(&anchor).len()
+    });
+    assert_eq!(chain_length, CHAIN_LENGTH);
+
+    let forest_proto = bench("to_proto", 10, || forest.to_proto());
+    bench("from_proto", 10, || {
+        BlockForest::from_proto(forest_proto.clone())
+    });
+
+    let mut total = Duration::default();
+    for _ in 0..10 {
+        let forest = push_all(&blocks);
+        let start = Instant::now();
+        let popped = pop_all(forest, anchor);
+        total += start.elapsed();
+        assert_eq!(popped, CHAIN_LENGTH - DELTA as usize);
+    }
+    println!(
+        "{:<20} {:>10.3?} per iteration ({} iterations)",
+        "pop (until unstable)",
+        total / 10,
+        10
+    );
+}
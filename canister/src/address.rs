@@ -0,0 +1,123 @@
+//! Parsing of the Bitcoin addresses that UTXOs are looked up by.
+//!
+//! The UTXOs of an address are those locked by its `script_pubkey`, so all the standard
+//! address types are supported: P2PKH, P2SH (including wrapped SegWit outputs such as
+//! P2SH-P2WPKH), P2WPKH, P2WSH and P2TR.
+use bitcoin::{
+    util::address::{Error, Payload},
+    Address, Network,
+};
+use std::str::FromStr;
+
+// The human-readable parts of bech32 addresses, followed by the separator.
+const BECH32_PREFIXES: [&str; 3] = ["bc1", "tb1", "bcrt1"];
+
+/// Parses a Bitcoin address.
+///
+/// Bech32 addresses are accepted in lowercase as well as in uppercase, which is often used
+/// in QR codes. As required by BIP-173, mixed-case bech32 addresses are rejected.
+pub fn parse(address: &str) -> Result<Address, Error> {
+    if is_uppercase_bech32(address) {
+        Address::from_str(&address.to_lowercase())
+    } else {
+        Address::from_str(address)
+    }
+}
+
+/// Returns true if the address can be used on the given network.
+///
+/// Testnet and signet addresses can't be told apart, so they're valid on both networks.
+/// Regtest shares its base58 prefixes with testnet, but has its own bech32 prefix.
+pub fn is_valid_for_network(address: &Address, network: Network) -> bool {
+    match network {
+        Network::Bitcoin => address.network == Network::Bitcoin,
+        Network::Testnet | Network::Signet => {
+            matches!(address.network, Network::Testnet | Network::Signet)
+        }
+        Network::Regtest => match address.payload {
+            Payload::PubkeyHash(_) | Payload::ScriptHash(_) => {
+                matches!(address.network, Network::Testnet | Network::Regtest)
+            }
+            Payload::WitnessProgram { .. } => address.network == Network::Regtest,
+        },
+    }
+}
+
+fn is_uppercase_bech32(address: &str) -> bool {
+    let lowercase = address.to_lowercase();
+    BECH32_PREFIXES
+        .iter()
+        .any(|prefix| lowercase.starts_with(prefix))
+        && !address.chars().any(|c| c.is_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_builder::addresses_of_all_types;
+    use bitcoin::{secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, PublicKey};
+
+    fn public_key() -> PublicKey {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        PublicKey::new(secp.generate_keypair(&mut rng).1)
+    }
+
+    #[test]
+    fn parses_all_address_types() {
+        for network in [Network::Bitcoin, Network::Testnet, Network::Regtest].iter() {
+            for address in addresses_of_all_types(*network) {
+                // NOTE: The network isn't compared, as regtest and testnet share the
+                // base58 prefixes.
+                assert_eq!(
+                    parse(&address.to_string()).unwrap().script_pubkey(),
+                    address.script_pubkey()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bech32_is_case_insensitive() {
+        let address = Address::p2wpkh(&public_key(), Network::Bitcoin).unwrap();
+        let lowercase = address.to_string();
+        assert!(lowercase.starts_with("bc1"));
+
+        assert_eq!(parse(&lowercase.to_uppercase()).unwrap(), address);
+
+        // Mixed-case addresses are invalid.
+        let mixed_case = format!("BC1{}", &lowercase[3..]);
+        assert!(parse(&mixed_case).is_err());
+    }
+
+    #[test]
+    fn network_prefixes() {
+        use Network::*;
+
+        for address_network in [Bitcoin, Testnet, Signet, Regtest].iter() {
+            for address in addresses_of_all_types(*address_network) {
+                let is_bech32 = matches!(address.payload, Payload::WitnessProgram { .. });
+
+                // Base58 addresses only distinguish mainnet from the other networks, while
+                // bech32 addresses also distinguish regtest.
+                let valid_networks = match (address_network, is_bech32) {
+                    (Bitcoin, _) => vec![Bitcoin],
+                    (_, false) => vec![Testnet, Signet, Regtest],
+                    (Testnet, true) | (Signet, true) => vec![Testnet, Signet],
+                    (Regtest, true) => vec![Regtest],
+                };
+
+                let address = parse(&address.to_string()).unwrap();
+                for network in [Bitcoin, Testnet, Signet, Regtest].iter() {
+                    assert_eq!(
+                        is_valid_for_network(&address, *network),
+                        valid_networks.contains(network),
+                        "{} on {}",
+                        address,
+                        network
+                    );
+                }
+            }
+        }
+    }
+}
@@ -4,17 +4,35 @@ use bitcoin::{
     TxOut, Txid,
 };
 
+/// Converts a `BlockHeader` into a protobuf struct.
+pub fn header_to_proto(header: &BlockHeader) -> proto::BlockHeader {
+    proto::BlockHeader {
+        version: header.version,
+        prev_blockhash: header.prev_blockhash.to_vec(),
+        merkle_root: header.merkle_root.to_vec(),
+        time: header.time,
+        bits: header.bits,
+        nonce: header.nonce,
+    }
+}
+
+/// Converts a protobuf block header into a `BlockHeader`.
+/// Returns `None` if one of its hashes isn't 32 bytes long.
+pub fn header_from_proto(header: &proto::BlockHeader) -> Option<BlockHeader> {
+    Some(BlockHeader {
+        version: header.version,
+        prev_blockhash: BlockHash::from_hash(Hash::from_slice(&header.prev_blockhash).ok()?),
+        merkle_root: TxMerkleNode::from_hash(Hash::from_slice(&header.merkle_root).ok()?),
+        time: header.time,
+        bits: header.bits,
+        nonce: header.nonce,
+    })
+}
+
 /// Converts a `Block` into a protobuf struct.
 pub fn to_proto(block: &Block) -> proto::Block {
     proto::Block {
-        header: Some(proto::BlockHeader {
-            version: block.header.version,
-            prev_blockhash: block.header.prev_blockhash.to_vec(),
-            merkle_root: block.header.merkle_root.to_vec(),
-            time: block.header.time,
-            bits: block.header.bits,
-            nonce: block.header.nonce,
-        }),
+        header: Some(header_to_proto(&block.header)),
         txdata: block
             .txdata
             .iter()
@@ -48,51 +66,45 @@ pub fn to_proto(block: &Block) -> proto::Block {
 }
 
 /// Converts a protobuf block into a `Block`.
-pub fn from_proto(block: &proto::Block) -> Block {
-    let header = block.header.as_ref().expect("Block header must exist");
-
-    Block {
-        header: BlockHeader {
-            version: header.version,
-            prev_blockhash: BlockHash::from_hash(Hash::from_slice(&header.prev_blockhash).unwrap()),
-            merkle_root: TxMerkleNode::from_hash(Hash::from_slice(&header.merkle_root).unwrap()),
-            time: header.time,
-            bits: header.bits,
-            nonce: header.nonce,
-        },
+/// Returns `None` if the block is malformed, e.g., if its header or an outpoint is missing.
+pub fn from_proto(block: &proto::Block) -> Option<Block> {
+    Some(Block {
+        header: header_from_proto(block.header.as_ref()?)?,
         txdata: block
             .txdata
             .iter()
-            .map(|t| Transaction {
-                version: t.version,
-                lock_time: t.lock_time,
-                input: t
-                    .input
-                    .iter()
-                    .map(|i| {
-                        let prev_output = i.previous_output.as_ref().unwrap();
-                        TxIn {
-                            previous_output: OutPoint::new(
-                                Txid::from_hash(Hash::from_slice(&prev_output.txid).unwrap()),
-                                prev_output.vout,
-                            ),
-                            script_sig: Script::from(i.script_sig.clone()),
-                            sequence: i.sequence,
-                            witness: i.witness.clone(),
-                        }
-                    })
-                    .collect(),
-                output: t
-                    .output
-                    .iter()
-                    .map(|o| TxOut {
-                        value: o.value,
-                        script_pubkey: Script::from(o.script_pubkey.clone()),
-                    })
-                    .collect(),
+            .map(|t| {
+                Some(Transaction {
+                    version: t.version,
+                    lock_time: t.lock_time,
+                    input: t
+                        .input
+                        .iter()
+                        .map(|i| {
+                            let prev_output = i.previous_output.as_ref()?;
+                            Some(TxIn {
+                                previous_output: OutPoint::new(
+                                    Txid::from_hash(Hash::from_slice(&prev_output.txid).ok()?),
+                                    prev_output.vout,
+                                ),
+                                script_sig: Script::from(i.script_sig.clone()),
+                                sequence: i.sequence,
+                                witness: i.witness.clone(),
+                            })
+                        })
+                        .collect::<Option<_>>()?,
+                    output: t
+                        .output
+                        .iter()
+                        .map(|o| TxOut {
+                            value: o.value,
+                            script_pubkey: Script::from(o.script_pubkey.clone()),
+                        })
+                        .collect(),
+                })
             })
-            .collect(),
-    }
+            .collect::<Option<_>>()?,
+    })
 }
 
 #[cfg(test)]
@@ -106,13 +118,42 @@ mod test {
         let genesis = BlockBuilder::genesis()
             .with_transaction(TransactionBuilder::coinbase().build())
             .build();
-        assert_eq!(genesis, from_proto(&to_proto(&genesis)));
+        assert_eq!(Some(genesis.clone()), from_proto(&to_proto(&genesis)));
 
         for _ in 0..100 {
             let block = BlockBuilder::with_prev_header(genesis.header)
                 .with_transaction(TransactionBuilder::coinbase().build())
                 .build();
-            assert_eq!(block, from_proto(&to_proto(&block)));
+            assert_eq!(Some(block.clone()), from_proto(&to_proto(&block)));
         }
     }
+
+    #[test]
+    fn from_malformed_proto() {
+        let block = BlockBuilder::genesis()
+            .with_transaction(
+                TransactionBuilder::with_input(OutPoint::new(Txid::default(), 0)).build(),
+            )
+            .build();
+
+        let mut without_header = to_proto(&block);
+        without_header.header = None;
+        assert_eq!(from_proto(&without_header), None);
+
+        let mut short_prev_blockhash = to_proto(&block);
+        short_prev_blockhash.header.as_mut().unwrap().prev_blockhash = vec![0; 31];
+        assert_eq!(from_proto(&short_prev_blockhash), None);
+
+        let mut without_outpoint = to_proto(&block);
+        without_outpoint.txdata[0].input[0].previous_output = None;
+        assert_eq!(from_proto(&without_outpoint), None);
+
+        let mut short_txid = to_proto(&block);
+        short_txid.txdata[0].input[0]
+            .previous_output
+            .as_mut()
+            .unwrap()
+            .txid = vec![0; 33];
+        assert_eq!(from_proto(&short_txid), None);
+    }
 }
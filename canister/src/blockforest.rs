@@ -1,334 +1,317 @@
 use crate::{block, proto};
-use bitcoin::{Block, BlockHash};
+use bitcoin::{util::uint::Uint256, Block, BlockHash};
+use std::collections::HashMap;
+
+/// How the forest chooses between competing chains.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForkChoice {
+    /// Chains are compared by their number of blocks.
+    Length,
+    /// Chains are compared by their cumulative proof of work, as derived from the
+    /// difficulty targets of their blocks.
+    Chainwork,
+}
 
 /// A data structure for maintaining all unstable blocks.
 ///
 /// A block `b` is considered stable if:
 ///   depth(block) ≥ delta
 ///   ∀ b', height(b') = height(b): depth(b) - depth(b’) ≥ delta
+///
+/// With `ForkChoice::Chainwork`, depths are measured in proof of work rather than in
+/// blocks, with `delta` being expressed in multiples of the work of `b`.
+///
+/// Blocks are indexed by their hash, along with the depth of the tree of their successors,
+/// so that pushing and popping blocks and looking up the current chain don't require
+/// scanning the whole forest.
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct BlockForest {
     delta: u64,
-    trees: Vec<BlockTree>,
+
+    fork_choice: ForkChoice,
+
+    // The blocks in the forest, keyed by their hash.
+    blocks: HashMap<BlockHash, Node>,
+
+    // The hashes of the blocks in the forest, keyed by the hash of their predecessor.
+    // The predecessor itself isn't necessarily in the forest.
+    successors: HashMap<BlockHash, Vec<BlockHash>>,
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+struct Node {
+    block: Block,
+
+    // The length of the longest chain of successors of the block.
+    depth: u64,
+
+    // The work of the block plus the work of the heaviest chain of its successors.
+    chainwork: Uint256,
 }
 
 impl BlockForest {
     pub fn new(delta: u64) -> Self {
         Self {
             delta,
-            trees: vec![],
+            fork_choice: ForkChoice::Length,
+            blocks: HashMap::new(),
+            successors: HashMap::new(),
         }
     }
 
+    /// Sets how the forest chooses between competing chains. Defaults to `ForkChoice::Length`.
+    pub fn set_fork_choice(&mut self, fork_choice: ForkChoice) {
+        self.fork_choice = fork_choice;
+    }
+
     /// Pop a block that is a successor to the `anchor` iff the block is stable.
     pub fn pop(&mut self, anchor: &BlockHash) -> Option<Block> {
-        let (mut attached_trees, detached_trees): (Vec<_>, Vec<_>) =
-            std::mem::take(&mut self.trees)
-                .into_iter()
-                .partition(|t| t.root().header.prev_blockhash == *anchor);
-
-        // Sort the attached trees by depth.
-        attached_trees.sort_by(|a, b| a.depth().partial_cmp(&b.depth()).unwrap());
-
-        match attached_trees.last() {
-            Some(deepest_tree) => {
-                if deepest_tree.depth() < self.delta {
-                    // Need a depth of at least >= delta
-                    self.trees = concat(attached_trees, detached_trees);
-                    return None;
+        let attached = self.successors.get(anchor)?;
+
+        // Find the deepest successor of the anchor, along with the depth of the
+        // second deepest one, if any. Depths include the successors themselves.
+        let mut deepest: Option<(&BlockHash, Uint256)> = None;
+        let mut second_deepest_depth = None;
+        for block_hash in attached {
+            let depth = self.weight(block_hash);
+            match deepest {
+                Some((_, deepest_depth)) if depth <= deepest_depth => {
+                    second_deepest_depth = second_deepest_depth.max(Some(depth));
                 }
-
-                if attached_trees.len() >= 2 {
-                    if let Some(second_deepest_tree) = attached_trees.get(attached_trees.len() - 2)
-                    {
-                        if deepest_tree.depth() - second_deepest_tree.depth() < self.delta {
-                            // Difference must be >= delta
-                            self.trees = concat(attached_trees, detached_trees);
-                            return None;
-                        }
-                    }
+                _ => {
+                    second_deepest_depth = deepest.map(|(_, depth)| depth);
+                    deepest = Some((block_hash, depth));
                 }
+            }
+        }
 
-                // The deepest tree is delta-stable.
-                // Pop the root of the tree, remove all other attached_trees.
-                let deepest_tree = attached_trees.pop().unwrap();
-                let (stable_block, subtrees) = deepest_tree.pop();
+        let (deepest_hash, deepest_depth) = deepest?;
+        let block_weight = self.block_weight(deepest_hash);
+        let delta = block_weight * Uint256::from_u64(self.delta).unwrap();
+        if deepest_depth < block_weight + delta {
+            // Need a depth of at least >= delta
+            return None;
+        }
 
-                self.trees = concat(detached_trees, subtrees);
-                Some(stable_block)
+        if let Some(second_deepest_depth) = second_deepest_depth {
+            if deepest_depth < second_deepest_depth + delta {
+                // Difference must be >= delta
+                return None;
             }
-            None => {
-                self.trees = concat(attached_trees, detached_trees);
-                None
+        }
+
+        // The deepest successor is delta-stable.
+        // Pop it, and remove all the other successors of the anchor along with their trees.
+        let stable_hash = *deepest_hash;
+        for block_hash in self.successors.remove(anchor).unwrap_or_default() {
+            if block_hash != stable_hash {
+                self.remove_tree(&block_hash);
             }
         }
+
+        self.blocks.remove(&stable_hash).map(|node| node.block)
     }
 
     /// Push a new block into the store.
-    pub fn push(&mut self, mut block: Block) {
+    pub fn push(&mut self, block: Block) {
         let block_hash = block.block_hash();
-        let successor_tree = self.take_tree(&block_hash);
-
-        for i in 0..self.trees.len() {
-            match self.trees[i].extend(block) {
-                Ok(()) => {
-                    if let Some(successor_tree) = successor_tree {
-                        let block = self.trees[i].find_mut(&block_hash).unwrap();
-                        block.children.push(successor_tree);
-                    }
+        if self.blocks.contains_key(&block_hash) {
+            // The block is already present in the forest. Nothing to do.
+            return;
+        }
 
-                    return;
-                }
-                Err(BlockNotPartOfTreeError(block_)) => {
-                    block = block_;
-                }
-            }
+        // Successors of the block may have been pushed before the block itself.
+        let mut depth = 0;
+        let mut chainwork = block.header.work();
+        for successor in self.successors.get(&block_hash).into_iter().flatten() {
+            let successor = &self.blocks[successor];
+            depth = depth.max(successor.depth + 1);
+            chainwork = chainwork.max(block.header.work() + successor.chainwork);
         }
 
-        let mut new_block_tree = BlockTree::new(block);
-        if let Some(successor_tree) = successor_tree {
-            new_block_tree.children.push(successor_tree);
+        let prev_blockhash = block.header.prev_blockhash;
+        self.successors
+            .entry(prev_blockhash)
+            .or_insert_with(Vec::new)
+            .push(block_hash);
+        self.blocks.insert(
+            block_hash,
+            Node {
+                block,
+                depth,
+                chainwork,
+            },
+        );
+
+        // Update the depths and chainworks of the block's predecessors.
+        let mut prev_blockhash = prev_blockhash;
+        while let Some(node) = self.blocks.get_mut(&prev_blockhash) {
+            let node_chainwork = node.block.header.work() + chainwork;
+            if node.depth > depth && node.chainwork >= node_chainwork {
+                // The predecessor already has a successor that's at least as deep, and one
+                // that's at least as heavy.
+                break;
+            }
+
+            node.depth = node.depth.max(depth + 1);
+            node.chainwork = node.chainwork.max(node_chainwork);
+            depth = node.depth;
+            chainwork = node.chainwork;
+            prev_blockhash = node.block.header.prev_blockhash;
         }
-        self.trees.push(new_block_tree);
     }
 
     /// Returns the best guess on what the "current" blockchain is.
     ///
     /// The most likely chain to be "current", we hypothesize, is the longest
     /// chain of blocks with an "uncontested" tip. As in, there exists no other
-    /// block at the same height as the tip.
+    /// block at the same height as the tip. With `ForkChoice::Chainwork`, the
+    /// heaviest chains are considered instead of the longest ones.
     pub fn get_current_chain(&self, anchor: &BlockHash) -> BlockChain {
-        // Get all the blockchains that extend the anchor.
-        let blockchains: Vec<BlockChain> = self
-            .trees
-            .iter()
-            .filter(|t| t.root().header.prev_blockhash == *anchor)
-            .map(|t| t.blockchains())
-            .flatten()
-            .collect();
-
-        if blockchains.is_empty() {
-            // No attached blockchains found.
-            // NOTE: this if condition isn't strictly required, as the following code should
-            // handle the empty case gracefully. Nonetheless, it's added out of paranoia.
-            return vec![];
-        }
-
-        // Find the length of the longest blockchain.
-        let mut longest_blockchain_len = 0;
-        for blockchain in blockchains.iter() {
-            longest_blockchain_len = longest_blockchain_len.max(blockchain.len());
-        }
-
-        // Get all the longest blockchains.
-        let longest_blockchains: Vec<BlockChain> = blockchains
-            .into_iter()
-            .filter(|bc| bc.len() == longest_blockchain_len)
-            .collect();
-
         let mut current_chain = vec![];
-        for height_idx in 0..longest_blockchain_len {
-            // If all the blocks on the same height are identical, then this block is part of the
-            // "current" chain.
-            let block = longest_blockchains[0][height_idx];
-            let block_hash = block.block_hash();
-
-            for chain in longest_blockchains.iter().skip(1) {
-                if chain[height_idx].block_hash() != block_hash {
-                    return current_chain;
+
+        // Follow the successors that all the longest blockchains have in common, i.e. the
+        // successor that's strictly deeper than all the others.
+        let mut block_hash = anchor;
+        while let Some(successors) = self.successors.get(block_hash) {
+            let mut deepest: Option<(&BlockHash, Uint256)> = None;
+            let mut contested = false;
+            for successor in successors {
+                let depth = self.weight(successor);
+                match deepest {
+                    Some((_, deepest_depth)) if depth < deepest_depth => {}
+                    Some((_, deepest_depth)) if depth == deepest_depth => contested = true,
+                    _ => {
+                        deepest = Some((successor, depth));
+                        contested = false;
+                    }
                 }
             }
 
-            current_chain.push(block);
-        }
-
-        current_chain
-    }
-
-    fn take_tree(&mut self, root_block_hash: &BlockHash) -> Option<BlockTree> {
-        for i in 0..self.trees.len() {
-            if &self.trees[i].root().header.prev_blockhash == root_block_hash {
-                return Some(self.trees.remove(i));
+            match deepest {
+                Some((successor, _)) if !contested => {
+                    current_chain.push(&self.blocks[successor].block);
+                    block_hash = successor;
+                }
+                _ => break,
             }
         }
-        None
-    }
 
-    pub fn get_blocks(&self) -> Vec<&Block> {
-        self.trees
-            .iter()
-            .map(|t| t.blockchains())
-            .flatten()
-            .flatten()
-            .collect()
+        current_chain
     }
 
-    pub fn to_proto(&self) -> proto::BlockForest {
-        proto::BlockForest {
-            delta: self.delta,
-            trees: self.trees.iter().map(|t| t.to_proto()).collect(),
+    // Returns the depth of the chains starting at the given block, including the block
+    // itself, in terms of the fork choice.
+    fn weight(&self, block_hash: &BlockHash) -> Uint256 {
+        let node = &self.blocks[block_hash];
+        match self.fork_choice {
+            ForkChoice::Length => Uint256::from_u64(node.depth + 1).unwrap(),
+            ForkChoice::Chainwork => node.chainwork,
         }
     }
 
-    pub fn from_proto(block_forest_proto: proto::BlockForest) -> Self {
-        Self {
-            delta: block_forest_proto.delta,
-            trees: block_forest_proto
-                .trees
-                .into_iter()
-                .map(BlockTree::from_proto)
-                .collect(),
+    // Returns the weight of the given block on its own.
+    fn block_weight(&self, block_hash: &BlockHash) -> Uint256 {
+        match self.fork_choice {
+            ForkChoice::Length => Uint256::from_u64(1).unwrap(),
+            ForkChoice::Chainwork => self.blocks[block_hash].block.header.work(),
         }
     }
-}
-
-// Maintains a tree of connected blocks.
-#[cfg_attr(test, derive(Debug, PartialEq))]
-struct BlockTree {
-    root: Block,
-    children: Vec<BlockTree>,
-}
-
-type BlockChain<'a> = Vec<&'a Block>;
-
-// An error thrown when trying to add a block that is not part of the tree.
-// See `BlockTree.insert` for more information.
-#[derive(Debug)]
-struct BlockNotPartOfTreeError(pub Block);
 
-impl BlockTree {
-    // Create a new `BlockTree` with the given block as its root.
-    fn new(root: Block) -> Self {
-        Self {
-            root,
-            children: vec![],
-        }
+    /// Returns the block with the given hash, if it exists in the forest.
+    pub fn get_block(&self, block_hash: &BlockHash) -> Option<&Block> {
+        self.blocks.get(block_hash).map(|node| &node.block)
     }
 
-    // Extends the tree with the given block.
-    //
-    // Blocks can extend the tree in the following cases:
-    //   * The block is already present in the tree (no-op).
-    //   * The block is a successor of a block already in the tree.
-    fn extend(&mut self, block: Block) -> Result<(), BlockNotPartOfTreeError> {
-        if self.contains(&block) {
-            // The block is already present in the tree. Nothing to do.
-            return Ok(());
-        }
-
-        // Check if the block is a successor to any of the blocks in the tree.
-        match self.find_mut(&block.header.prev_blockhash) {
-            Some(block_tree) => {
-                assert_eq!(block_tree.root.block_hash(), block.header.prev_blockhash);
-                // Add the block as a successor.
-                block_tree.children.push(BlockTree::new(block));
-                Ok(())
-            }
-            None => Err(BlockNotPartOfTreeError(block)),
-        }
+    pub fn get_blocks(&self) -> Vec<&Block> {
+        self.blocks.values().map(|node| &node.block).collect()
     }
 
-    fn root(&self) -> &Block {
-        &self.root
+    /// Returns the number of blocks in the forest.
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
     }
 
-    fn pop(self) -> (Block, Vec<BlockTree>) {
-        (self.root, self.children)
+    /// Returns the number of trees in the forest, i.e. of blocks whose predecessor isn't
+    /// in the forest.
+    pub fn num_trees(&self) -> usize {
+        self.roots().count()
     }
 
-    // Returns a `BlockTree` where the hash of the root block matches the provided `block_hash`
-    // if it exists, and `None` otherwise.
-    fn find_mut(&mut self, blockhash: &BlockHash) -> Option<&mut BlockTree> {
-        if self.root.block_hash() == *blockhash {
-            return Some(self);
-        }
-
-        for child in self.children.iter_mut() {
-            if let res @ Some(_) = child.find_mut(blockhash) {
-                return res;
+    // Removes the block with the given hash and all of its successors.
+    fn remove_tree(&mut self, root_hash: &BlockHash) {
+        let mut to_remove = vec![*root_hash];
+        while let Some(block_hash) = to_remove.pop() {
+            self.blocks.remove(&block_hash);
+            if let Some(successors) = self.successors.remove(&block_hash) {
+                to_remove.extend(successors);
             }
         }
-
-        None
     }
 
-    // Returns all the blockchains in the tree.
-    fn blockchains(&self) -> Vec<BlockChain> {
-        if self.children.is_empty() {
-            return vec![vec![&self.root]];
-        }
-
-        let mut tips = vec![];
-        for child in self.children.iter() {
-            tips.extend(
-                child
-                    .blockchains()
-                    .into_iter()
-                    .map(|bc| concat(vec![&self.root], bc))
-                    .collect::<Vec<BlockChain>>(),
-            );
-        }
-
-        tips
+    // Returns the hashes of the blocks whose predecessor isn't in the forest.
+    fn roots(&self) -> impl Iterator<Item = &BlockHash> + '_ {
+        self.successors
+            .iter()
+            .filter(move |(prev_blockhash, _)| !self.blocks.contains_key(prev_blockhash))
+            .flat_map(|(_, successors)| successors)
     }
 
-    fn depth(&self) -> u64 {
-        if self.children.is_empty() {
-            return 0;
-        }
-
-        let mut max_child_depth = 0;
-
-        for child in self.children.iter() {
-            max_child_depth = std::cmp::max(1 + child.depth(), max_child_depth);
+    pub fn to_proto(&self) -> proto::BlockForest {
+        proto::BlockForest {
+            delta: self.delta,
+            fork_choice: match self.fork_choice {
+                ForkChoice::Length => 0,
+                ForkChoice::Chainwork => 1,
+            },
+            trees: self
+                .roots()
+                .map(|root_hash| self.tree_to_proto(root_hash))
+                .collect(),
         }
-
-        max_child_depth
     }
 
-    // Returns true if a block exists in the tree, false otherwise.
-    fn contains(&self, block: &Block) -> bool {
-        if self.root.block_hash() == block.block_hash() {
-            return true;
-        }
-
-        for child in self.children.iter() {
-            if child.contains(block) {
-                return true;
-            }
-        }
-
-        false
-    }
-
-    fn to_proto(&self) -> proto::BlockTree {
+    fn tree_to_proto(&self, root_hash: &BlockHash) -> proto::BlockTree {
         proto::BlockTree {
-            root: Some(block::to_proto(&self.root)),
-            children: self.children.iter().map(|t| t.to_proto()).collect(),
+            root: Some(block::to_proto(&self.blocks[root_hash].block)),
+            children: self
+                .successors
+                .get(root_hash)
+                .into_iter()
+                .flatten()
+                .map(|successor| self.tree_to_proto(successor))
+                .collect(),
         }
     }
 
-    fn from_proto(block_tree_proto: proto::BlockTree) -> Self {
-        Self {
-            root: block::from_proto(&block_tree_proto.root.unwrap()),
-            children: block_tree_proto
-                .children
-                .into_iter()
-                .map(BlockTree::from_proto)
-                .collect(),
+    pub fn from_proto(block_forest_proto: proto::BlockForest) -> Self {
+        let mut forest = Self::new(block_forest_proto.delta);
+        forest.set_fork_choice(match block_forest_proto.fork_choice {
+            1 => ForkChoice::Chainwork,
+            // Fork choices that this version doesn't know of fall back to the default.
+            _ => ForkChoice::Length,
+        });
+
+        // Push the blocks of each tree, predecessors first. The blocks are pushed in the
+        // same order as they're serialized, so that the successors are in the same order.
+        let mut trees = block_forest_proto.trees;
+        trees.reverse();
+        while let Some(tree) = trees.pop() {
+            forest.push(block::from_proto(&tree.root.unwrap()).expect("Invalid block"));
+            trees.extend(tree.children.into_iter().rev());
         }
+
+        forest
     }
 }
 
-fn concat<T>(mut a: Vec<T>, b: Vec<T>) -> Vec<T> {
-    a.extend(b);
-    a
-}
+type BlockChain<'a> = Vec<&'a Block>;
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::test_builder::BlockBuilder;
+    use bitcoin::BlockHeader;
 
     #[test]
     fn empty() {
@@ -400,11 +383,11 @@ mod test {
         assert_eq!(forest.pop(&block_0.block_hash()), None);
 
         // There are two trees in the forest.
-        assert_eq!(forest.trees.len(), 2);
+        assert_eq!(forest.roots().count(), 2);
 
         // Add block2, which should result in all the blocks merging into a single tree.
         forest.push(block_2.clone());
-        assert_eq!(forest.trees.len(), 1);
+        assert_eq!(forest.roots().count(), 1);
 
         // Getting the blocks should work as expected.
         assert_eq!(forest.pop(&block_0.block_hash()), Some(block_1.clone()));
@@ -433,7 +416,7 @@ mod test {
         forest.push(block_2);
 
         // There is only one tree in the forest.
-        assert_eq!(forest.trees.len(), 1);
+        assert_eq!(forest.roots().count(), 1);
     }
 
     #[test]
@@ -447,7 +430,7 @@ mod test {
         forest.push(block_0);
 
         // There is only one tree in the forest.
-        assert_eq!(forest.trees.len(), 1);
+        assert_eq!(forest.roots().count(), 1);
     }
 
     #[test]
@@ -461,7 +444,7 @@ mod test {
         forest.push(block_2.clone());
 
         // There is only one tree in the forest.
-        assert_eq!(forest.trees.len(), 1);
+        assert_eq!(forest.roots().count(), 1);
         assert_eq!(forest.pop(&block_0.block_hash()), Some(block_1.clone()));
         assert_eq!(forest.pop(&block_1.block_hash()), Some(block_2.clone()));
         assert_eq!(forest.pop(&block_2.block_hash()), None);
@@ -478,7 +461,7 @@ mod test {
         forest.push(block_1.clone());
 
         // There is only one tree in the forest.
-        assert_eq!(forest.trees.len(), 1);
+        assert_eq!(forest.roots().count(), 1);
         assert_eq!(forest.pop(&block_0.block_hash()), Some(block_1.clone()));
         assert_eq!(forest.pop(&block_1.block_hash()), Some(block_2.clone()));
         assert_eq!(forest.pop(&block_2.block_hash()), None);
@@ -486,28 +469,111 @@ mod test {
 
     #[test]
     fn tree_single_block() {
-        let block_tree = BlockTree::new(BlockBuilder::genesis().build());
+        let block = BlockBuilder::genesis().build();
+        let mut forest = BlockForest::new(1);
+        forest.push(block.clone());
 
-        assert_eq!(block_tree.depth(), 0);
-        assert_eq!(block_tree.blockchains(), vec![vec![&block_tree.root]]);
+        assert_eq!(forest.blocks[&block.block_hash()].depth, 0);
+        assert_eq!(
+            forest.get_current_chain(&block.header.prev_blockhash),
+            vec![&block]
+        );
     }
 
     #[test]
     fn tree_multiple_forks() {
         let genesis_block = BlockBuilder::genesis().build();
+        let genesis_block_hash = genesis_block.block_hash();
         let genesis_block_header = genesis_block.header;
-        let mut block_tree = BlockTree::new(genesis_block);
+        let mut forest = BlockForest::new(1);
+        forest.push(genesis_block);
 
         for i in 1..5 {
             // Create different blocks extending the genesis block.
             // Each one of these should be a separate fork.
-            block_tree
-                .extend(BlockBuilder::with_prev_header(genesis_block_header).build())
-                .unwrap();
-            assert_eq!(block_tree.blockchains().len(), i);
+            forest.push(BlockBuilder::with_prev_header(genesis_block_header).build());
+            assert_eq!(forest.successors[&genesis_block_hash].len(), i);
         }
 
-        assert_eq!(block_tree.depth(), 1);
+        assert_eq!(forest.blocks[&genesis_block_hash].depth, 1);
+    }
+
+    // Creating a forest that looks like this:
+    //
+    // * -> 1 -> 2 -> 3
+    //       \-> a
+    //
+    // And then inserting "b" that extends "a", and "c" that extends "b". The depths of
+    // the predecessors are updated along the way.
+    #[test]
+    fn depths_are_updated() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header).build();
+        let block_a = BlockBuilder::with_prev_header(block_1.header).build();
+        let block_b = BlockBuilder::with_prev_header(block_a.header).build();
+        let block_c = BlockBuilder::with_prev_header(block_b.header).build();
+
+        let mut forest = BlockForest::new(1);
+        for block in [&block_3, &block_1, &block_a, &block_2].iter() {
+            forest.push((*block).clone());
+        }
+
+        let depth = |forest: &BlockForest, block: &Block| forest.blocks[&block.block_hash()].depth;
+        assert_eq!(depth(&forest, &block_1), 2);
+        assert_eq!(depth(&forest, &block_a), 0);
+
+        forest.push(block_b.clone());
+        assert_eq!(depth(&forest, &block_1), 2);
+        assert_eq!(depth(&forest, &block_a), 1);
+
+        forest.push(block_c);
+        assert_eq!(depth(&forest, &block_1), 3);
+        assert_eq!(depth(&forest, &block_a), 2);
+        assert_eq!(depth(&forest, &block_b), 1);
+    }
+
+    #[test]
+    fn to_from_proto() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+        let block_a = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_b = BlockBuilder::with_prev_header(block_1.header).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header).build();
+        let block_4 = BlockBuilder::with_prev_header(block_3.header).build();
+        // A block whose predecessor isn't in the forest, nor is the anchor.
+        let detached_block =
+            BlockBuilder::with_prev_header(BlockBuilder::genesis().build().header).build();
+
+        let mut forest = BlockForest::new(1);
+        for block in [
+            block_1,
+            block_2,
+            block_a,
+            block_b,
+            block_4,
+            block_3,
+            detached_block,
+        ]
+        .iter()
+        {
+            forest.push(block.clone());
+        }
+
+        assert_eq!(BlockForest::from_proto(forest.to_proto()), forest);
+
+        forest.set_fork_choice(ForkChoice::Chainwork);
+        assert_eq!(BlockForest::from_proto(forest.to_proto()), forest);
+
+        // An unknown fork choice falls back to the default.
+        let unknown_fork_choice = proto::BlockForest {
+            fork_choice: 2,
+            ..forest.to_proto()
+        };
+        forest.set_fork_choice(ForkChoice::Length);
+        assert_eq!(BlockForest::from_proto(unknown_fork_choice), forest);
     }
 
     // Creating a forest that looks like this:
@@ -694,4 +760,97 @@ mod test {
             Vec::<&Block>::new()
         );
     }
+
+    // Returns a block extending `prev_header` with a quarter of its predecessor's target,
+    // i.e. with four times as much work. The block doesn't meet its target, which doesn't
+    // matter to the forest.
+    fn harder_block(prev_header: BlockHeader) -> Block {
+        let mut block = BlockBuilder::with_prev_header(prev_header).build();
+        block.header.bits = BlockHeader::compact_target_from_u256(
+            &(prev_header.target() / Uint256::from_u64(4).unwrap()),
+        );
+        block
+    }
+
+    // Creating a forest that looks like this:
+    //
+    // * -> a1 -> a2
+    // * -> b1 -> b2
+    //
+    // Where the "b" blocks have four times as much work as the "a" blocks.
+    #[test]
+    fn chainwork_equal_length_forks() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_a1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_a2 = BlockBuilder::with_prev_header(block_a1.header).build();
+        let block_b1 = harder_block(block_0.header);
+        let block_b2 = BlockBuilder::with_prev_header(block_b1.header).build();
+        assert_eq!(block_b2.header.work(), block_b1.header.work());
+        assert!(block_b1.header.work() > block_a1.header.work());
+
+        let mut forest = BlockForest::new(1);
+        for block in [&block_a1, &block_a2, &block_b1, &block_b2].iter() {
+            forest.push((*block).clone());
+        }
+
+        // By length, the forks are tied.
+        assert_eq!(
+            forest.get_current_chain(&block_0.block_hash()),
+            Vec::<&Block>::new()
+        );
+        assert_eq!(forest.pop(&block_0.block_hash()), None);
+
+        // By chainwork, the "b" fork wins.
+        forest.set_fork_choice(ForkChoice::Chainwork);
+        assert_eq!(
+            forest.get_current_chain(&block_0.block_hash()),
+            vec![&block_b1, &block_b2]
+        );
+        assert_eq!(forest.pop(&block_0.block_hash()), Some(block_b1.clone()));
+        assert_eq!(
+            forest.get_current_chain(&block_b1.block_hash()),
+            vec![&block_b2]
+        );
+        assert_eq!(forest.pop(&block_b1.block_hash()), None);
+    }
+
+    // Creating a forest that looks like this:
+    //
+    // * -> a1 -> a2 -> a3
+    // * -> b1 -> b2
+    //
+    // Where the "b" blocks have four times as much work as the "a" blocks, so the "b" fork
+    // has more work despite being shorter.
+    #[test]
+    fn chainwork_shorter_fork_with_more_work() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_a1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_a2 = BlockBuilder::with_prev_header(block_a1.header).build();
+        let block_a3 = BlockBuilder::with_prev_header(block_a2.header).build();
+        let block_b1 = harder_block(block_0.header);
+        let block_b2 = BlockBuilder::with_prev_header(block_b1.header).build();
+
+        let mut forest = BlockForest::new(1);
+        for block in [&block_b2, &block_a3, &block_b1, &block_a1, &block_a2].iter() {
+            forest.push((*block).clone());
+        }
+
+        assert_eq!(
+            forest.get_current_chain(&block_0.block_hash()),
+            vec![&block_a1, &block_a2, &block_a3]
+        );
+        assert_eq!(forest.pop(&block_0.block_hash()), Some(block_a1.clone()));
+
+        let mut forest = BlockForest::new(1);
+        forest.set_fork_choice(ForkChoice::Chainwork);
+        for block in [&block_b2, &block_a3, &block_b1, &block_a1, &block_a2].iter() {
+            forest.push((*block).clone());
+        }
+
+        assert_eq!(
+            forest.get_current_chain(&block_0.block_hash()),
+            vec![&block_b1, &block_b2]
+        );
+        assert_eq!(forest.pop(&block_0.block_hash()), Some(block_b1));
+    }
 }
@@ -1,33 +1,68 @@
 //! Types used to support the candid API.
 use bitcoin::Network as BitcoinNetwork;
-use ic_cdk::export::candid::{CandidType, Deserialize};
+use btc::blockforest::ForkChoice as BlockForestForkChoice;
+use ic_cdk::export::{
+    candid::{CandidType, Deserialize},
+    Principal,
+};
 
 /// The payload used to initialize the canister.
 #[derive(CandidType, Deserialize)]
 pub struct InitPayload {
     pub delta: u64,
     pub network: Network,
+    /// Whether the transactions of stable blocks are indexed, which is required to look
+    /// up their status. Defaults to `false`, as the index grows with the blockchain.
+    pub index_transactions: Option<bool>,
+    /// The canister that blocks are fetched from and transactions are sent to. If not set,
+    /// the canister relies on the adapter shim instead.
+    pub adapter_canister_id: Option<Principal>,
+    /// How the canister chooses between competing chains. Defaults to `Length`.
+    pub fork_choice: Option<ForkChoice>,
+    /// Whether the input scripts of new blocks are validated. Defaults to `false`, as
+    /// verifying signatures is expensive.
+    pub validate_scripts: Option<bool>,
+    /// The hash of a snapshot to bootstrap the state from, instead of syncing from
+    /// genesis. The snapshot is then uploaded with `upload_snapshot_chunk`.
+    pub snapshot_hash: Option<Vec<u8>>,
 }
 
-/// The supported Bitcoin networks.
-///
-/// Note that this is identical to `Network` that's defined in the Bitcoin
-/// crate, with the only difference being that it derives a `CandidType`.
+pub use ic_btc_types::Network;
+
+/// Converts a `Network` into its equivalent in the Bitcoin crate.
+pub fn to_bitcoin_network(network: Network) -> BitcoinNetwork {
+    match network {
+        Network::Bitcoin => BitcoinNetwork::Bitcoin,
+        Network::Testnet => BitcoinNetwork::Testnet,
+        Network::Signet => BitcoinNetwork::Signet,
+        Network::Regtest => BitcoinNetwork::Regtest,
+    }
+}
+
+/// Converts a network of the Bitcoin crate into a `Network`.
+pub fn from_bitcoin_network(network: BitcoinNetwork) -> Network {
+    match network {
+        BitcoinNetwork::Bitcoin => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Signet => Network::Signet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
+/// The supported ways of choosing between competing chains.
 #[derive(CandidType, Deserialize, Copy, Clone)]
-pub enum Network {
-    Bitcoin,
-    Regtest,
-    Testnet,
-    Signet,
+pub enum ForkChoice {
+    /// The longest chain wins.
+    Length,
+    /// The chain with the most cumulative proof of work wins.
+    Chainwork,
 }
 
-impl From<Network> for BitcoinNetwork {
-    fn from(network: Network) -> Self {
-        match network {
-            Network::Bitcoin => Self::Bitcoin,
-            Network::Testnet => Self::Testnet,
-            Network::Signet => Self::Signet,
-            Network::Regtest => Self::Regtest,
+impl From<ForkChoice> for BlockForestForkChoice {
+    fn from(fork_choice: ForkChoice) -> Self {
+        match fork_choice {
+            ForkChoice::Length => Self::Length,
+            ForkChoice::Chainwork => Self::Chainwork,
         }
     }
 }
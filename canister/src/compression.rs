@@ -0,0 +1,266 @@
+//! The compression of amounts and scripts, and the variable-length integers that Bitcoin
+//! Core uses to store its UTXO set compactly.
+use bitcoin::Script;
+
+// The number of script types that Bitcoin Core compresses to a single byte followed by a
+// hash or a public key. Other scripts are prefixed with their size plus this number.
+//
+// NOTE: Bitcoin Core also compresses P2PK scripts with an uncompressed public key (codes 4
+// and 5), but recovering such a key decompresses a point of the curve, which would be
+// repeated whenever the UTXO is read. These scripts are stored as they are instead.
+const NUM_SPECIAL_SCRIPTS: usize = 6;
+
+const OP_DUP: u8 = 0x76;
+const OP_HASH160: u8 = 0xa9;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_CHECKSIG: u8 = 0xac;
+
+/// Appends an integer encoded as a `VARINT` of Bitcoin Core, which is a base-128 encoding
+/// where all bytes but the last have their most significant bit set.
+pub fn write_varint(out: &mut Vec<u8>, mut n: u128) {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    while n > 0x7f {
+        n = (n >> 7) - 1;
+        bytes.push((n & 0x7f) as u8 | 0x80);
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Reads an integer written with `write_varint` from the front of `bytes`, and advances
+/// `bytes` past it. Returns `None` if the bytes don't start with a valid integer.
+pub fn read_varint(bytes: &mut &[u8]) -> Option<u128> {
+    let mut n: u128 = 0;
+    loop {
+        let (byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+
+        if n > u128::MAX >> 7 {
+            return None;
+        }
+        n = (n << 7) | (byte & 0x7f) as u128;
+
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+        n = n.checked_add(1)?;
+    }
+}
+
+/// Compresses an amount, taking advantage of amounts usually being round numbers.
+///
+/// Amounts are compressed into a `u128`, as compressing large amounts that aren't valid in
+/// Bitcoin would overflow a `u64`.
+pub fn compress_amount(amount: u64) -> u128 {
+    if amount == 0 {
+        return 0;
+    }
+
+    let mut n = amount as u128;
+    let mut exponent = 0;
+    while n % 10 == 0 && exponent < 9 {
+        n /= 10;
+        exponent += 1;
+    }
+
+    if exponent < 9 {
+        let last_digit = n % 10;
+        n /= 10;
+        1 + (n * 9 + last_digit - 1) * 10 + exponent
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// Returns the amount that was compressed with `compress_amount`.
+pub fn decompress_amount(compressed: u128) -> u64 {
+    if compressed == 0 {
+        return 0;
+    }
+
+    let mut x = compressed - 1;
+    let exponent = x % 10;
+    x /= 10;
+
+    let mut n = if exponent < 9 {
+        let last_digit = (x % 9) + 1;
+        x /= 9;
+        x * 10 + last_digit
+    } else {
+        x + 1
+    };
+
+    for _ in 0..exponent {
+        n *= 10;
+    }
+    n as u64
+}
+
+/// Appends a compressed script.
+///
+/// P2PKH and P2SH scripts are compressed into a byte followed by their hash, and P2PK
+/// scripts with a compressed public key into the public key. Other scripts are stored as
+/// they are, prefixed with their size.
+pub fn write_script(out: &mut Vec<u8>, script: &Script) {
+    let bytes = script.as_bytes();
+
+    if script.is_p2pkh() {
+        out.push(0x00);
+        out.extend_from_slice(&bytes[3..23]);
+    } else if script.is_p2sh() {
+        out.push(0x01);
+        out.extend_from_slice(&bytes[2..22]);
+    } else if bytes.len() == 35
+        && bytes[0] == 33
+        && bytes[34] == OP_CHECKSIG
+        && (bytes[1] == 0x02 || bytes[1] == 0x03)
+    {
+        // A compressed public key, whose first byte is the parity of its y coordinate.
+        out.extend_from_slice(&bytes[1..34]);
+    } else {
+        write_varint(out, (bytes.len() + NUM_SPECIAL_SCRIPTS) as u128);
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// Reads a script written with `write_script` from the front of `bytes`, and advances
+/// `bytes` past it. Returns `None` if the bytes don't start with a valid script.
+pub fn read_script(bytes: &mut &[u8]) -> Option<Script> {
+    let code = read_varint(bytes)? as usize;
+    let size = match code {
+        0 | 1 => 20,
+        2 | 3 => 32,
+        4 | 5 => return None,
+        _ => code - NUM_SPECIAL_SCRIPTS,
+    };
+    if bytes.len() < size {
+        return None;
+    }
+    let (data, rest) = bytes.split_at(size);
+    *bytes = rest;
+
+    let script = match code {
+        0 => [
+            &[OP_DUP, OP_HASH160, 20][..],
+            data,
+            &[OP_EQUALVERIFY, OP_CHECKSIG],
+        ]
+        .concat(),
+        1 => [&[OP_HASH160, 20][..], data, &[OP_EQUAL]].concat(),
+        2 | 3 => [&[33, code as u8][..], data, &[OP_CHECKSIG]].concat(),
+        _ => data.to_vec(),
+    };
+    Some(Script::from(script))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1};
+    use bitcoin::{Address, Network, PublicKey as BitcoinPublicKey};
+
+    const COIN: u64 = 100_000_000;
+
+    #[test]
+    fn amounts() {
+        // From `compress_tests.cpp` in Bitcoin Core.
+        for (amount, compressed) in [
+            (0, 0x0),
+            (1, 0x1),
+            (COIN / 100, 0x7),
+            (COIN, 0x9),
+            (50 * COIN, 0x32),
+            (21_000_000 * COIN, 0x1406f40),
+        ]
+        .iter()
+        {
+            assert_eq!(compress_amount(*amount), *compressed);
+            assert_eq!(decompress_amount(*compressed), *amount);
+        }
+
+        for amount in [12345, 999_999_999, 10u64.pow(15), u64::MAX].iter() {
+            assert_eq!(decompress_amount(compress_amount(*amount)), *amount);
+        }
+    }
+
+    #[test]
+    fn varints() {
+        for n in [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            u64::MAX as u128,
+            u128::MAX,
+        ]
+        .iter()
+        {
+            let mut bytes = vec![];
+            write_varint(&mut bytes, *n);
+            bytes.push(0xff);
+
+            let mut reader = &bytes[..];
+            assert_eq!(read_varint(&mut reader), Some(*n));
+            assert_eq!(reader, &[0xff]);
+        }
+
+        // Examples from `serialize.h` in Bitcoin Core.
+        let mut bytes = vec![];
+        write_varint(&mut bytes, 16511);
+        assert_eq!(bytes, vec![0xff, 0x7f]);
+
+        // Truncated integers are rejected.
+        assert_eq!(read_varint(&mut &[0x80][..]), None);
+    }
+
+    // Returns the size of a compressed script, after checking that it decompresses to the
+    // original script.
+    fn compressed_size(script: &Script) -> usize {
+        let mut bytes = vec![];
+        write_script(&mut bytes, script);
+        let mut reader = &bytes[..];
+        assert_eq!(read_script(&mut reader).as_ref(), Some(script));
+        assert!(reader.is_empty());
+        bytes.len()
+    }
+
+    #[test]
+    fn scripts() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let public_key = BitcoinPublicKey::new(secp.generate_keypair(&mut rng).1);
+        let mut uncompressed_public_key = public_key;
+        uncompressed_public_key.compressed = false;
+
+        assert_eq!(
+            compressed_size(&Address::p2pkh(&public_key, Network::Bitcoin).script_pubkey()),
+            21
+        );
+        assert_eq!(
+            compressed_size(&Address::p2sh(&Script::new(), Network::Bitcoin).script_pubkey()),
+            21
+        );
+        assert_eq!(compressed_size(&Script::new_p2pk(&public_key)), 33);
+
+        // Other scripts are stored as they are, including P2PK scripts with an uncompressed
+        // public key.
+        assert_eq!(
+            compressed_size(&Script::new_p2pk(&uncompressed_public_key)),
+            68
+        );
+        let p2wpkh = Address::p2wpkh(&public_key, Network::Bitcoin)
+            .unwrap()
+            .script_pubkey();
+        assert_eq!(compressed_size(&p2wpkh), 23);
+        assert_eq!(compressed_size(&Script::new()), 1);
+        assert_eq!(compressed_size(&Script::new_op_return(&[0; 80])), 84);
+
+        // Bitcoin Core's codes of uncompressed public keys are rejected.
+        assert_eq!(
+            read_script(&mut &[&[0x04][..], &[0; 32][..]].concat()[..]),
+            None
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Scheduling of the requests that fetch blocks from the adapter.
+//!
+//! At most one request is in flight at a time. While the adapter keeps returning blocks,
+//! a new request is sent as soon as the previous one completes. Once the chain is idle,
+//! i.e. no blocks are returned, the delay between requests grows exponentially.
+//!
+//! A request that doesn't complete within `REQUEST_TIMEOUT` is considered lost, e.g.
+//! because processing its response trapped, and a new request may be sent.
+
+// Timestamps are in nanoseconds, as returned by `ic_cdk::api::time`.
+type Timestamp = u64;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// The delay after the first request that doesn't return any blocks.
+pub const MIN_IDLE_DELAY: u64 = NANOS_PER_SECOND;
+
+/// The maximum delay between requests when the chain is idle.
+pub const MAX_IDLE_DELAY: u64 = 60 * NANOS_PER_SECOND;
+
+/// The time after which a request that is still in flight is considered lost.
+pub const REQUEST_TIMEOUT: u64 = 5 * 60 * NANOS_PER_SECOND;
+
+#[derive(Debug, Default)]
+pub struct FetchScheduler {
+    // The time at which the request that's in flight was sent, if any.
+    in_flight_since: Option<Timestamp>,
+    next_fetch_at: Timestamp,
+    idle_delay: u64,
+}
+
+impl FetchScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if a request should be sent now, in which case the request is
+    /// considered to be in flight until `finish` is called or it times out.
+    pub fn try_start(&mut self, now: Timestamp) -> bool {
+        if let Some(started_at) = self.in_flight_since {
+            if now < started_at + REQUEST_TIMEOUT {
+                return false;
+            }
+        }
+
+        if now < self.next_fetch_at {
+            return false;
+        }
+
+        self.in_flight_since = Some(now);
+        true
+    }
+
+    /// Records the completion of the request that's in flight.
+    ///
+    /// `made_progress` is false if the request failed or didn't return any blocks, in
+    /// which case the next request is delayed.
+    pub fn finish(&mut self, now: Timestamp, made_progress: bool) {
+        self.in_flight_since = None;
+
+        self.idle_delay = if made_progress {
+            0
+        } else {
+            (self.idle_delay * 2)
+                .max(MIN_IDLE_DELAY)
+                .min(MAX_IDLE_DELAY)
+        };
+        self.next_fetch_at = now + self.idle_delay;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn one_request_in_flight() {
+        let mut scheduler = FetchScheduler::new();
+        assert!(scheduler.try_start(0));
+        assert!(!scheduler.try_start(0));
+        assert!(!scheduler.try_start(MAX_IDLE_DELAY));
+
+        scheduler.finish(1, true);
+        assert!(scheduler.try_start(1));
+    }
+
+    #[test]
+    fn lost_request_times_out() {
+        let mut scheduler = FetchScheduler::new();
+        assert!(scheduler.try_start(0));
+
+        // The request never finishes, so another one is sent once it times out.
+        assert!(!scheduler.try_start(REQUEST_TIMEOUT - 1));
+        assert!(scheduler.try_start(REQUEST_TIMEOUT));
+        assert!(!scheduler.try_start(REQUEST_TIMEOUT + 1));
+
+        scheduler.finish(REQUEST_TIMEOUT + 1, true);
+        assert!(scheduler.try_start(REQUEST_TIMEOUT + 1));
+    }
+
+    #[test]
+    fn backs_off_when_idle() {
+        let mut scheduler = FetchScheduler::new();
+        let mut now = 0;
+
+        for expected_delay in [
+            MIN_IDLE_DELAY,
+            2 * MIN_IDLE_DELAY,
+            4 * MIN_IDLE_DELAY,
+            8 * MIN_IDLE_DELAY,
+        ]
+        .iter()
+        {
+            assert!(scheduler.try_start(now));
+            scheduler.finish(now, false);
+            assert!(!scheduler.try_start(now + expected_delay - 1));
+            now += expected_delay;
+        }
+
+        // The delay is capped.
+        for _ in 0..10 {
+            assert!(scheduler.try_start(now));
+            scheduler.finish(now, false);
+            now += MAX_IDLE_DELAY;
+        }
+        assert_eq!(scheduler.idle_delay, MAX_IDLE_DELAY);
+
+        // Once blocks are received, requests are sent right away again.
+        assert!(scheduler.try_start(now));
+        scheduler.finish(now, true);
+        assert!(scheduler.try_start(now));
+    }
+}
@@ -1,8 +1,18 @@
+pub mod address;
 pub mod block;
-mod blockforest;
+pub mod blockforest;
+mod compression;
+pub mod fetch_scheduler;
+mod muhash;
+pub mod outgoing_transactions;
+pub mod script_validation;
+mod serialization;
+pub mod snapshot;
 pub mod store;
 pub mod test_builder;
+mod utxo_delta;
 mod utxoset;
+pub mod validation;
 
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/btc.rs"));
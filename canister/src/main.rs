@@ -1,26 +1,605 @@
 use bitcoin::{
-    blockdata::constants::genesis_block, util::psbt::serialize::Deserialize, Address, Network,
-    Transaction,
+    blockdata::constants::genesis_block,
+    hashes::{sha256, Hash},
+    util::psbt::serialize::Deserialize,
+    Address, Network, Script, Transaction, Txid,
 };
 use btc::{
+    address,
+    fetch_scheduler::FetchScheduler,
+    outgoing_transactions::{OutgoingTransactionState, OutgoingTransactions},
     proto::{GetSuccessorsRequest, GetSuccessorsResponse},
-    store::State,
+    serialization::{StreamReader, StreamWriter},
+    snapshot::{SnapshotError, SnapshotLoader},
+    store::{State, StateReader, StateWriter},
 };
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use ic_btc_types::{
-    GetBalanceError, GetBalanceRequest, GetUtxosError, GetUtxosRequest, GetUtxosResponse, OutPoint,
-    SendTransactionError, SendTransactionRequest, Utxo,
+    BalanceBreakdown, BatchError, BlockHeader, GetBalanceBreakdownRequest, GetBalanceError,
+    GetBalanceRequest, GetCurrentChainResponse, GetTransactionStatusError,
+    GetTransactionStatusRequest, GetUtxosByScriptRequest, GetUtxosError, GetUtxosRequest,
+    GetUtxosResponse, InvalidBlock, Metrics, OutPoint, PrepareUpgradeError, SendTransactionError,
+    SendTransactionRequest, SentTransaction, SentTransactionState, TransactionStatus,
+    UploadSnapshotError, Utxo, UtxoSetHash,
+};
+use ic_cdk::api::{
+    call::{call, RejectionCode},
+    print,
 };
-use ic_cdk::api::print;
 use ic_cdk::export::candid::candid_method;
-use ic_cdk_macros::{query, update};
+use ic_cdk::export::Principal;
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
 use prost::Message;
-use std::{cell::RefCell, collections::VecDeque, str::FromStr};
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    io::{self, Read, Write},
+};
+
+mod candid_types;
+use candid_types::InitPayload;
+
+// The maximum number of UTXOs returned in a single `get_utxos` response.
+const MAX_UTXOS_PER_PAGE: u32 = 1_000;
+
+// The maximum number of requests in a single `get_balances` or `get_utxos_multi` call.
+const MAX_REQUESTS_PER_BATCH: u32 = 1_000;
+
+// The number of rejected blocks that are kept for `get_invalid_blocks`.
+const MAX_INVALID_BLOCKS: usize = 100;
+
+// The number of UTXOs or indexed transactions in a batch of the state that is written to
+// stable memory for an upgrade, and the number of batches that are written or read in a single
+// message. Tests use small batches, so that small states take several messages.
+#[cfg(not(test))]
+const UPGRADE_BATCH_SIZE: usize = btc::serialization::UTXO_BATCH_SIZE;
+#[cfg(not(test))]
+const UPGRADE_BATCHES_PER_MESSAGE: usize = 10;
+#[cfg(test)]
+const UPGRADE_BATCH_SIZE: usize = 2;
+#[cfg(test)]
+const UPGRADE_BATCHES_PER_MESSAGE: usize = 2;
 
 thread_local! {
-    // Initialize the canister to expect blocks from the Regtest network.
+    // The state of the canister. It's initialized with a placeholder value that is
+    // overwritten in `init`.
     static STATE: RefCell<State> = RefCell::new(State::new(1, Network::Regtest, genesis_block(Network::Regtest)));
-    // A queue of transactions awaiting to be sent.
-    static OUTGOING_TRANSACTIONS: RefCell<VecDeque<Vec<u8>>> = RefCell::new(VecDeque::new());
+    // The transactions submitted with `send_transaction`.
+    static OUTGOING_TRANSACTIONS: RefCell<OutgoingTransactions> = RefCell::new(OutgoingTransactions::new());
+    // The canister that blocks are fetched from and transactions are sent to, if any.
+    static ADAPTER_CANISTER_ID: RefCell<Option<Principal>> = RefCell::new(None);
+    // Schedules the requests that fetch blocks from the adapter canister.
+    static FETCH_SCHEDULER: RefCell<FetchScheduler> = RefCell::new(FetchScheduler::new());
+    // The most recently rejected blocks, oldest first.
+    static INVALID_BLOCKS: RefCell<VecDeque<InvalidBlock>> = RefCell::new(VecDeque::new());
+    // The number of instructions used to process the last `GetSuccessorsResponse`, if
+    // instructions can be counted and a response was processed.
+    static LAST_SYNC_INSTRUCTIONS: RefCell<Option<u64>> = RefCell::new(None);
+    // The snapshot that is being uploaded, if the canister was initialized with one.
+    static SNAPSHOT_UPLOAD: RefCell<Option<SnapshotUpload>> = RefCell::new(None);
+    // The principal that installed or last upgraded the canister, which is the only one
+    // allowed to prepare an upgrade. It's overwritten in `init` and `post_upgrade`.
+    static UPGRADER: RefCell<Principal> = RefCell::new(Principal::anonymous());
+    // The state that is being written to stable memory ahead of an upgrade, if
+    // `prepare_upgrade` was called.
+    static UPGRADE_CHECKPOINT: RefCell<Option<UpgradeCheckpoint>> = RefCell::new(None);
+    // The state that is being read from stable memory after an upgrade, until it's read
+    // completely.
+    static STATE_LOAD: RefCell<Option<StateLoad>> = RefCell::new(None);
+}
+
+// A snapshot that is being uploaded with `upload_snapshot_chunk`.
+struct SnapshotUpload {
+    // The principal that installed the canister, which is the only one allowed to upload.
+    uploader: Principal,
+    // The payload the canister was initialized with, which configures the state.
+    payload: InitPayload,
+    loader: SnapshotLoader,
+}
+
+impl SnapshotUpload {
+    fn new(uploader: Principal, payload: InitPayload) -> Self {
+        let snapshot_hash = payload
+            .snapshot_hash
+            .as_ref()
+            .expect("A snapshot upload requires a snapshot hash");
+        let loader = SnapshotLoader::new(
+            payload.delta,
+            candid_types::to_bitcoin_network(payload.network),
+            payload.index_transactions.unwrap_or(false),
+            sha256::Hash::from_slice(snapshot_hash).expect("The snapshot hash must be 32 bytes"),
+        );
+
+        Self {
+            uploader,
+            payload,
+            loader,
+        }
+    }
+}
+
+// Returns the current time in nanoseconds.
+#[cfg(not(test))]
+fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+// The system API isn't available in tests, so time only passes when a test sets it.
+#[cfg(test)]
+thread_local! {
+    static TEST_TIME: std::cell::Cell<u64> = std::cell::Cell::new(0);
+}
+
+#[cfg(test)]
+fn now() -> u64 {
+    TEST_TIME.with(|time| time.get())
+}
+
+// NOTE: Replicas that don't provide `ic0.performance_counter`, e.g. the one of dfx 0.8.4,
+// refuse to install a canister that imports it, so it's only imported with the
+// `performance_counter` feature.
+#[cfg(all(not(test), target_arch = "wasm32", feature = "performance_counter"))]
+#[link(wasm_import_module = "ic0")]
+extern "C" {
+    fn performance_counter(counter_type: u32) -> u64;
+}
+
+// Returns the number of instructions executed so far in the current message.
+#[cfg(all(not(test), target_arch = "wasm32", feature = "performance_counter"))]
+fn instruction_counter() -> Option<u64> {
+    // SAFETY: Counter type 0 is the instruction counter, which is always available.
+    Some(unsafe { performance_counter(0) })
+}
+
+// Instructions can only be counted in a canister built with the `performance_counter`
+// feature.
+#[cfg(all(
+    not(test),
+    not(all(target_arch = "wasm32", feature = "performance_counter"))
+))]
+fn instruction_counter() -> Option<u64> {
+    None
+}
+
+// In tests, every reading of the counter counts as one more instruction.
+#[cfg(test)]
+thread_local! {
+    static TEST_INSTRUCTIONS: std::cell::Cell<u64> = std::cell::Cell::new(0);
+}
+
+#[cfg(test)]
+fn instruction_counter() -> Option<u64> {
+    TEST_INSTRUCTIONS.with(|instructions| {
+        instructions.set(instructions.get() + 1);
+        Some(instructions.get())
+    })
+}
+
+// Returns the size of the heap in bytes.
+#[cfg(target_arch = "wasm32")]
+fn heap_size() -> u64 {
+    const WASM_PAGE_SIZE: u64 = 65_536;
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+// The heap of the canister is only known in a canister.
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_size() -> u64 {
+    0
+}
+
+// Returns the size of stable memory in bytes.
+#[cfg(not(test))]
+fn stable_memory_size() -> u64 {
+    const WASM_PAGE_SIZE: u64 = 65_536;
+    ic_cdk::api::stable::stable_size() as u64 * WASM_PAGE_SIZE
+}
+
+// Writes bytes to stable memory at the given offset, growing it as needed.
+#[cfg(not(test))]
+fn stable_memory_write(offset: u64, bytes: &[u8]) -> io::Result<()> {
+    const WASM_PAGE_SIZE: u64 = 65_536;
+    let end = offset + bytes.len() as u64;
+    let size = stable_memory_size();
+    if end > size {
+        let new_pages = (end - size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+        ic_cdk::api::stable::stable_grow(new_pages as u32)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))?;
+    }
+    ic_cdk::api::stable::stable_write(offset as u32, bytes);
+    Ok(())
+}
+
+// Reads bytes from stable memory at the given offset.
+#[cfg(not(test))]
+fn stable_memory_read(offset: u64, buf: &mut [u8]) {
+    ic_cdk::api::stable::stable_read(offset as u32, buf);
+}
+
+// The system API isn't available in tests, so stable memory is a vector that survives
+// simulated upgrades.
+#[cfg(test)]
+thread_local! {
+    static TEST_STABLE_MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![]);
+}
+
+#[cfg(test)]
+fn stable_memory_size() -> u64 {
+    TEST_STABLE_MEMORY.with(|memory| memory.borrow().len() as u64)
+}
+
+#[cfg(test)]
+fn stable_memory_write(offset: u64, bytes: &[u8]) -> io::Result<()> {
+    TEST_STABLE_MEMORY.with(|memory| {
+        let mut memory = memory.borrow_mut();
+        let (start, end) = (offset as usize, offset as usize + bytes.len());
+        if memory.len() < end {
+            memory.resize(end, 0);
+        }
+        memory[start..end].copy_from_slice(bytes);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+fn stable_memory_read(offset: u64, buf: &mut [u8]) {
+    TEST_STABLE_MEMORY.with(|memory| {
+        let start = offset as usize;
+        buf.copy_from_slice(&memory.borrow()[start..start + buf.len()]);
+    });
+}
+
+// Reads and writes stable memory from its start on, like a file.
+#[derive(Default)]
+struct StableMemory {
+    offset: u64,
+}
+
+impl Read for StableMemory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading past the end of stable memory traps, so the end is reported instead.
+        let len = stable_memory_size()
+            .saturating_sub(self.offset)
+            .min(buf.len() as u64) as usize;
+        stable_memory_read(self.offset, &mut buf[..len]);
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for StableMemory {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        stable_memory_write(self.offset, buf)?;
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Returns the principal that called the canister.
+#[cfg(not(test))]
+fn caller() -> Principal {
+    ic_cdk::api::caller()
+}
+
+// The system API isn't available in tests, so all calls are anonymous.
+#[cfg(test)]
+fn caller() -> Principal {
+    Principal::anonymous()
+}
+
+#[init]
+#[candid_method(init)]
+fn init(payload: InitPayload) {
+    let network = candid_types::to_bitcoin_network(payload.network);
+
+    let mut state = if payload.index_transactions.unwrap_or(false) {
+        State::new_with_tx_index(payload.delta, network, genesis_block(network))
+    } else {
+        State::new(payload.delta, network, genesis_block(network))
+    };
+    configure_state(&mut state, &payload);
+
+    STATE.with(|s| {
+        s.replace(state);
+    });
+    ADAPTER_CANISTER_ID.with(|id| id.replace(payload.adapter_canister_id));
+    UPGRADER.with(|upgrader| upgrader.replace(caller()));
+
+    // The state is replaced once the snapshot is uploaded. Until then, no blocks are inserted.
+    if payload.snapshot_hash.is_some() {
+        SNAPSHOT_UPLOAD.with(|upload| upload.replace(Some(SnapshotUpload::new(caller(), payload))));
+    }
+}
+
+// Applies the settings of the init payload that aren't needed to create the state.
+fn configure_state(state: &mut State, payload: &InitPayload) {
+    if let Some(fork_choice) = payload.fork_choice {
+        state.set_fork_choice(fork_choice.into());
+    }
+    state.set_script_validation(payload.validate_scripts.unwrap_or(false));
+}
+
+// The state, followed by the outgoing transactions and the adapter canister ID, is written to
+// stable memory as a single stream (see `btc::serialization`). A large state takes more
+// instructions to write or read than a single message may use, so it's written ahead of an
+// upgrade over several messages, after `prepare_upgrade` is called, and read after the upgrade
+// over several heartbeats. Blocks aren't inserted in the meantime, so that the state doesn't
+// change while it's written, and isn't changed before it's read completely.
+//
+// NOTE: Without `prepare_upgrade`, `pre_upgrade` writes the whole state. An upgrade fails,
+// leaving the canister as it was, if `pre_upgrade` or the part of the reading done in
+// `post_upgrade` exceeds the instruction limit of a message.
+#[pre_upgrade]
+fn pre_upgrade() {
+    // A snapshot that is being uploaded isn't written to stable memory, so the upgrade is
+    // refused rather than losing the chunks uploaded so far.
+    SNAPSHOT_UPLOAD.with(|upload| {
+        assert!(
+            upload.borrow().is_none(),
+            "The canister can't be upgraded while a snapshot is being uploaded"
+        )
+    });
+
+    write_upgrade_data().expect("Writing to stable memory must succeed");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    UPGRADER.with(|upgrader| upgrader.replace(caller()));
+    start_state_load().expect("Reading from stable memory must succeed");
+}
+
+// Writes what's left of the state after the batches written ahead of the upgrade, followed by
+// the outgoing transactions and the adapter canister ID.
+fn write_upgrade_data() -> io::Result<()> {
+    // A state that isn't read completely after the last upgrade is still in stable memory,
+    // along with everything else.
+    if STATE_LOAD.with(|load| load.borrow().is_some()) {
+        return Ok(());
+    }
+
+    let checkpoint = match UPGRADE_CHECKPOINT.with(|c| c.borrow_mut().take()) {
+        Some(checkpoint) => checkpoint,
+        None => UpgradeCheckpoint::start()?,
+    };
+    checkpoint.finish()
+}
+
+// Starts reading what was written with `write_upgrade_data`. Small states are read right away,
+// and larger ones over the following heartbeats.
+fn start_state_load() -> io::Result<()> {
+    STATE_LOAD.with(|load| load.replace(Some(StateLoad::start()?)));
+    continue_state_load()
+}
+
+// Reads the next batches of the state that is being read from stable memory, if any, and
+// restores everything once the state is read completely.
+fn continue_state_load() -> io::Result<()> {
+    let done = STATE_LOAD.with(|load| match load.borrow_mut().as_mut() {
+        Some(load) => load.read_batches(UPGRADE_BATCHES_PER_MESSAGE),
+        None => Ok(false),
+    })?;
+    if done {
+        let load = STATE_LOAD.with(|load| load.borrow_mut().take().unwrap());
+        load.finish()?;
+    }
+    Ok(())
+}
+
+// A state that is written to stable memory over several messages ahead of an upgrade.
+struct UpgradeCheckpoint {
+    memory: StableMemory,
+    // The checksum of the stream written so far.
+    engine: sha256::HashEngine,
+    writer: StateWriter,
+    done: bool,
+}
+
+impl UpgradeCheckpoint {
+    // Starts writing the state at the start of stable memory.
+    fn start() -> io::Result<Self> {
+        let mut memory = StableMemory::default();
+        let mut stream = StreamWriter::new(&mut memory)?;
+        let writer =
+            STATE.with(|s| StateWriter::new(&s.borrow(), &mut stream, UPGRADE_BATCH_SIZE))?;
+        let engine = stream.suspend();
+        Ok(Self {
+            memory,
+            engine,
+            writer,
+            done: false,
+        })
+    }
+
+    // Writes up to `max_batches` more batches of the state. Returns true once the state is
+    // written completely.
+    fn write_batches(&mut self, max_batches: usize) -> io::Result<bool> {
+        if !self.done {
+            let writer = &mut self.writer;
+            let mut stream = StreamWriter::resume(&mut self.memory, self.engine.clone());
+            self.done = STATE.with(|s| writer.write_next(&s.borrow(), &mut stream, max_batches))?;
+            self.engine = stream.suspend();
+        }
+        Ok(self.done)
+    }
+
+    // Writes the rest of the state, followed by the outgoing transactions and the adapter
+    // canister ID, and ends the stream.
+    fn finish(mut self) -> io::Result<()> {
+        self.write_batches(usize::MAX)?;
+
+        let mut stream = StreamWriter::resume(&mut self.memory, self.engine);
+        stream.write_message(&btc::proto::UpgradeData {
+            outgoing_transactions: Some(OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().to_proto())),
+            adapter_canister_id: ADAPTER_CANISTER_ID.with(|id| {
+                id.borrow()
+                    .map(|id| id.as_slice().to_vec())
+                    .unwrap_or_default()
+            }),
+        })?;
+        stream.finish()?;
+        Ok(())
+    }
+}
+
+// A state that is read from stable memory over several messages after an upgrade.
+struct StateLoad {
+    memory: StableMemory,
+    // The checksum of the stream read so far.
+    engine: sha256::HashEngine,
+    reader: StateReader,
+}
+
+impl StateLoad {
+    // Starts reading the state at the start of stable memory.
+    fn start() -> io::Result<Self> {
+        let mut memory = StableMemory::default();
+        let mut stream = StreamReader::new(&mut memory)?;
+        let reader = StateReader::new(&mut stream)?;
+        let engine = stream.suspend();
+        Ok(Self {
+            memory,
+            engine,
+            reader,
+        })
+    }
+
+    // Reads up to `max_batches` more batches of the state. Returns true once the state is
+    // read completely.
+    fn read_batches(&mut self, max_batches: usize) -> io::Result<bool> {
+        let mut stream = StreamReader::resume(&mut self.memory, self.engine.clone());
+        let done = self.reader.read_next(&mut stream, max_batches)?;
+        self.engine = stream.suspend();
+        Ok(done)
+    }
+
+    // Reads the outgoing transactions and the adapter canister ID that follow the state, and
+    // restores them along with the state. Nothing is restored unless the checksum of the
+    // stream matches.
+    fn finish(mut self) -> io::Result<()> {
+        let mut stream = StreamReader::resume(&mut self.memory, self.engine);
+        let data: btc::proto::UpgradeData = stream.read_message()?;
+        stream.finish()?;
+        let state = self.reader.finish()?;
+
+        STATE.with(|s| s.replace(state));
+        OUTGOING_TRANSACTIONS.with(|t| {
+            t.replace(OutgoingTransactions::from_proto(
+                data.outgoing_transactions.unwrap_or_default(),
+            ))
+        });
+        let adapter_canister_id = if data.adapter_canister_id.is_empty() {
+            None
+        } else {
+            Some(Principal::from_slice(&data.adapter_canister_id))
+        };
+        ADAPTER_CANISTER_ID.with(|id| id.replace(adapter_canister_id));
+
+        Ok(())
+    }
+}
+
+// Starts writing the state to stable memory ahead of an upgrade, so that `pre_upgrade` only
+// needs to write what's left of it. The state is written over the following heartbeats, and a
+// few batches on every call. Returns true once the state is written completely.
+//
+// Blocks aren't inserted until the canister is upgraded or `cancel_upgrade` is called.
+#[update]
+#[candid_method(update)]
+fn prepare_upgrade() -> Result<bool, PrepareUpgradeError> {
+    check_upgrader()?;
+    if SNAPSHOT_UPLOAD.with(|upload| upload.borrow().is_some())
+        || STATE_LOAD.with(|load| load.borrow().is_some())
+    {
+        return Err(PrepareUpgradeError::Busy);
+    }
+
+    UPGRADE_CHECKPOINT.with(|c| {
+        let mut checkpoint = c.borrow_mut();
+        if checkpoint.is_none() {
+            checkpoint.replace(
+                UpgradeCheckpoint::start().expect("Writing to stable memory must succeed"),
+            );
+        }
+        Ok(checkpoint
+            .as_mut()
+            .unwrap()
+            .write_batches(UPGRADE_BATCHES_PER_MESSAGE)
+            .expect("Writing to stable memory must succeed"))
+    })
+}
+
+// Stops writing the state ahead of an upgrade, after which blocks are inserted again.
+#[update]
+#[candid_method(update)]
+fn cancel_upgrade() -> Result<(), PrepareUpgradeError> {
+    check_upgrader()?;
+    UPGRADE_CHECKPOINT.with(|c| c.replace(None));
+    Ok(())
+}
+
+fn check_upgrader() -> Result<(), PrepareUpgradeError> {
+    if UPGRADER.with(|upgrader| *upgrader.borrow()) != caller() {
+        return Err(PrepareUpgradeError::Unauthorized);
+    }
+    Ok(())
+}
+
+// Returns true if blocks mustn't be inserted, as the state is about to be replaced by a
+// snapshot, or it's being written to or read from stable memory.
+fn is_block_insertion_paused() -> bool {
+    SNAPSHOT_UPLOAD.with(|upload| upload.borrow().is_some())
+        || UPGRADE_CHECKPOINT.with(|c| c.borrow().is_some())
+        || STATE_LOAD.with(|load| load.borrow().is_some())
+}
+
+// The reasons an address passed to an endpoint is rejected.
+enum AddressError {
+    Malformed,
+    NetworkMismatch { expected: Network, got: Network },
+}
+
+impl From<AddressError> for GetBalanceError {
+    fn from(err: AddressError) -> Self {
+        match err {
+            AddressError::Malformed => Self::MalformedAddress,
+            AddressError::NetworkMismatch { expected, got } => Self::AddressNetworkMismatch {
+                expected: candid_types::from_bitcoin_network(expected),
+                got: candid_types::from_bitcoin_network(got),
+            },
+        }
+    }
+}
+
+impl From<AddressError> for GetUtxosError {
+    fn from(err: AddressError) -> Self {
+        match err {
+            AddressError::Malformed => Self::MalformedAddress,
+            AddressError::NetworkMismatch { expected, got } => Self::AddressNetworkMismatch {
+                expected: candid_types::from_bitcoin_network(expected),
+                got: candid_types::from_bitcoin_network(got),
+            },
+        }
+    }
+}
+
+// Parses the given address, and checks that it's an address on the canister's network.
+fn parse_address(address: &str) -> Result<Address, AddressError> {
+    let address = address::parse(address).map_err(|_| AddressError::Malformed)?;
+
+    let network = STATE.with(|s| s.borrow().network());
+    if !address::is_valid_for_network(&address, network) {
+        return Err(AddressError::NetworkMismatch {
+            expected: network,
+            got: address.network,
+        });
+    }
+
+    Ok(address)
 }
 
 // Retrieves the balance of the given Bitcoin address.
@@ -30,72 +609,633 @@ thread_local! {
 #[update]
 #[candid_method(update)]
 fn get_balance(request: GetBalanceRequest) -> Result<u64, GetBalanceError> {
-    if Address::from_str(&request.address).is_err() {
-        return Err(GetBalanceError::MalformedAddress);
+    parse_address(&request.address)?;
+
+    let min_confirmations = request.min_confirmations.unwrap_or(0);
+
+    Ok(STATE.with(|s| s.borrow().get_balance(&request.address, min_confirmations)))
+}
+
+#[update]
+#[candid_method(update)]
+fn get_utxos(request: GetUtxosRequest) -> Result<GetUtxosResponse, GetUtxosError> {
+    let address = parse_address(&request.address)?;
+
+    get_utxos_page(
+        &address.script_pubkey(),
+        request.min_confirmations,
+        request.page,
+        request.page_size,
+    )
+}
+
+// Retrieves the UTXOs of the given script_pubkey. Unlike `get_utxos`, this also works
+// for outputs that don't have an address, such as P2PK and bare multisig outputs.
+#[update]
+#[candid_method(update)]
+fn get_utxos_by_script(
+    request: GetUtxosByScriptRequest,
+) -> Result<GetUtxosResponse, GetUtxosError> {
+    get_utxos_page(
+        &Script::from(request.script_pubkey),
+        request.min_confirmations,
+        request.page,
+        request.page_size,
+    )
+}
+
+// Retrieves the balance of the given Bitcoin address, broken down by confirmations.
+//
+// All the parts of the breakdown are computed from the same state, so they're consistent
+// with each other.
+//
+// NOTE: Like `get_balance`, this endpoint is exposed as an update call for security reasons.
+#[update]
+#[candid_method(update)]
+fn get_balance_breakdown(
+    request: GetBalanceBreakdownRequest,
+) -> Result<BalanceBreakdown, GetBalanceError> {
+    let address = parse_address(&request.address)?;
+
+    STATE.with(|s| {
+        let state = s.borrow();
+        let stable_height = state.stable_height();
+        let main_chain_height = state.main_chain_height();
+
+        let mut breakdown = BalanceBreakdown {
+            stable: 0,
+            unstable: 0,
+            unstable_by_confirmations: vec![0; (main_chain_height - stable_height) as usize],
+            main_chain_height,
+        };
+
+        // NOTE: As with `get_balance`, the sums can't overflow.
+        for (_, txout, height) in state.get_utxos_by_script(&address.script_pubkey(), 0) {
+            if height <= stable_height {
+                breakdown.stable += txout.value;
+            } else {
+                breakdown.unstable += txout.value;
+                breakdown.unstable_by_confirmations[(main_chain_height - height) as usize] +=
+                    txout.value;
+            }
+        }
+
+        Ok(breakdown)
+    })
+}
+
+// Retrieves the balances of the given Bitcoin addresses, with one result per address.
+//
+// NOTE: Like `get_balance`, this endpoint is exposed as an update call for security reasons.
+#[update]
+#[candid_method(update)]
+fn get_balances(
+    requests: Vec<GetBalanceRequest>,
+) -> Result<Vec<Result<u64, GetBalanceError>>, BatchError> {
+    check_batch_size(requests.len())?;
+
+    let scripts: Vec<Result<(Script, u32), GetBalanceError>> = requests
+        .iter()
+        .map(|request| {
+            let address = parse_address(&request.address)?;
+            Ok((
+                address.script_pubkey(),
+                request.min_confirmations.unwrap_or(0),
+            ))
+        })
+        .collect();
+
+    let valid_scripts: Vec<(Script, u32)> = scripts.iter().flatten().cloned().collect();
+    let (_, utxos) = STATE.with(|s| s.borrow().get_utxos_by_scripts(&valid_scripts));
+    let mut utxos = utxos.into_iter();
+
+    Ok(scripts
+        .into_iter()
+        .map(|script| {
+            script.map(|_| {
+                utxos
+                    .next()
+                    .expect("There must be UTXOs for every valid address")
+                    .iter()
+                    .map(|(_, txout, _)| txout.value)
+                    .sum()
+            })
+        })
+        .collect())
+}
+
+// Retrieves the UTXOs of the given Bitcoin addresses, with one result per address.
+#[update]
+#[candid_method(update)]
+fn get_utxos_multi(
+    requests: Vec<GetUtxosRequest>,
+) -> Result<Vec<Result<GetUtxosResponse, GetUtxosError>>, BatchError> {
+    check_batch_size(requests.len())?;
+
+    let pages: Vec<Result<(Script, u32, PageParams), GetUtxosError>> = requests
+        .into_iter()
+        .map(|request| {
+            let address = parse_address(&request.address)?;
+            let page_params = parse_page_params(request.page, request.page_size)?;
+            Ok((
+                address.script_pubkey(),
+                request.min_confirmations.unwrap_or(0),
+                page_params,
+            ))
+        })
+        .collect();
+
+    let valid_scripts: Vec<(Script, u32)> = pages
+        .iter()
+        .flatten()
+        .map(|(script, min_confirmations, _)| (script.clone(), *min_confirmations))
+        .collect();
+    let (main_chain_height, utxos) =
+        STATE.with(|s| s.borrow().get_utxos_by_scripts(&valid_scripts));
+    let mut utxos = utxos.into_iter();
+
+    Ok(pages
+        .into_iter()
+        .map(|page| {
+            let (_, _, page_params) = page?;
+            let script_utxos = utxos
+                .next()
+                .expect("There must be UTXOs for every valid request");
+            Ok(utxos_page(script_utxos, main_chain_height, page_params))
+        })
+        .collect())
+}
+
+// Rejects batches with more than `MAX_REQUESTS_PER_BATCH` requests, which bounds the
+// instructions of a single call.
+fn check_batch_size(num_requests: usize) -> Result<(), BatchError> {
+    if num_requests > MAX_REQUESTS_PER_BATCH as usize {
+        return Err(BatchError::TooManyRequests {
+            max: MAX_REQUESTS_PER_BATCH,
+        });
     }
+    Ok(())
+}
+
+// Returns a page of the UTXOs of the given script.
+fn get_utxos_page(
+    script: &Script,
+    min_confirmations: Option<u32>,
+    page: Option<Vec<u8>>,
+    page_size: Option<u32>,
+) -> Result<GetUtxosResponse, GetUtxosError> {
+    let min_confirmations = min_confirmations.unwrap_or(0);
+    let page_params = parse_page_params(page, page_size)?;
+
+    STATE.with(|s| {
+        let state = s.borrow();
+        Ok(utxos_page(
+            state.get_utxos_by_script(script, min_confirmations),
+            state.main_chain_height(),
+            page_params,
+        ))
+    })
+}
+
+// The key of the UTXO that a page starts after, if any, and the size of the page.
+type PageParams = (Option<UtxoKey>, usize);
+
+fn parse_page_params(
+    page: Option<Vec<u8>>,
+    page_size: Option<u32>,
+) -> Result<PageParams, GetUtxosError> {
+    let page_size = page_size
+        .unwrap_or(MAX_UTXOS_PER_PAGE)
+        .clamp(1, MAX_UTXOS_PER_PAGE) as usize;
+    let start_after = match page {
+        Some(page) => Some(decode_page_token(&page).ok_or(GetUtxosError::MalformedPage)?),
+        None => None,
+    };
+
+    Ok((start_after, page_size))
+}
+
+// Returns the requested page of the given UTXOs.
+fn utxos_page(
+    utxos: HashSet<(bitcoin::OutPoint, bitcoin::TxOut, u32)>,
+    main_chain_height: u32,
+    (start_after, page_size): PageParams,
+) -> GetUtxosResponse {
+    let mut utxos: Vec<Utxo> = utxos
+        .into_iter()
+        .map(|(outpoint, txout, height)| Utxo {
+            outpoint: OutPoint {
+                txid: outpoint.txid.to_vec(),
+                vout: outpoint.vout,
+            },
+            value: txout.value,
+            height,
+            confirmations: main_chain_height - height + 1,
+        })
+        .collect();
+
+    // Sort the UTXOs so that the pages are consistent across calls, even as new
+    // blocks arrive.
+    utxos.sort_unstable_by_key(utxo_key);
+    let total_count = utxos.len() as u32;
+
+    let mut utxos: Vec<Utxo> = utxos
+        .into_iter()
+        .filter(|utxo| match &start_after {
+            Some(key) => utxo_key(utxo) > *key,
+            None => true,
+        })
+        .collect();
+
+    let next_page = if utxos.len() > page_size {
+        utxos.truncate(page_size);
+        utxos.last().map(|utxo| encode_page_token(&utxo_key(utxo)))
+    } else {
+        None
+    };
+
+    GetUtxosResponse {
+        utxos,
+        total_count,
+        next_page,
+    }
+}
+
+// The key by which UTXOs are ordered when paginating: (height, txid, vout).
+type UtxoKey = (u32, Vec<u8>, u32);
+
+fn utxo_key(utxo: &Utxo) -> UtxoKey {
+    (utxo.height, utxo.outpoint.txid.clone(), utxo.outpoint.vout)
+}
+
+// Encodes the key of the last UTXO of a page into an opaque page token.
+fn encode_page_token((height, txid, vout): &UtxoKey) -> Vec<u8> {
+    let mut token = vec![];
+    token.write_u32::<BigEndian>(*height).unwrap();
+    token.write_u32::<BigEndian>(*vout).unwrap();
+    token.extend_from_slice(txid);
+    token
+}
+
+// Decodes a page token returned by `encode_page_token`.
+fn decode_page_token(mut token: &[u8]) -> Option<UtxoKey> {
+    let height = token.read_u32::<BigEndian>().ok()?;
+    let vout = token.read_u32::<BigEndian>().ok()?;
+    if token.len() != 32 {
+        return None;
+    }
+    Some((height, token.to_vec(), vout))
+}
+
+#[update]
+#[candid_method(update)]
+fn send_transaction(request: SendTransactionRequest) -> Result<(), SendTransactionError> {
+    let tx = Transaction::deserialize(&request.transaction)
+        .map_err(|_| SendTransactionError::MalformedTransaction)?;
+
+    if OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().is_queued(&tx.txid(), now())) {
+        return Err(SendTransactionError::AlreadyQueued);
+    }
+
+    STATE.with(|s| check_transaction(&s.borrow(), &tx))?;
+
+    // The transaction is cached for up to 24 hours and occasionally resent to the network
+    // until it's observed in a block.
+    OUTGOING_TRANSACTIONS.with(|txs| {
+        txs.borrow_mut()
+            .insert(tx.txid(), request.transaction, now());
+    });
+
+    Ok(())
+}
+
+// Checks that a transaction only spends outputs that are unspent on the current main chain,
+// and that it doesn't spend more than the value of these outputs.
+//
+// NOTE: Outputs of transactions that are queued, but not in a block yet, can't be spent.
+fn check_transaction(state: &State, tx: &Transaction) -> Result<(), SendTransactionError> {
+    let mut inputs = HashSet::new();
+    let mut input_value: u64 = 0;
+    for input in &tx.input {
+        // A transaction that spends the same outpoint twice is invalid.
+        if !inputs.insert(input.previous_output) {
+            return Err(SendTransactionError::MalformedTransaction);
+        }
+
+        let txout = state.get_utxo(&input.previous_output).ok_or_else(|| {
+            SendTransactionError::UnknownInput(OutPoint {
+                txid: input.previous_output.txid.to_vec(),
+                vout: input.previous_output.vout,
+            })
+        })?;
+
+        // NOTE: This can't overflow, as the total supply of bitcoin is well below the
+        // max value of a `u64`.
+        input_value += txout.value;
+    }
+
+    // Unlike the inputs, the values of the outputs are arbitrary, so the sum could overflow.
+    let output_value = tx
+        .output
+        .iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or(SendTransactionError::MalformedTransaction)?;
 
-    let min_confirmations = request.min_confirmations.unwrap_or(0);
+    if output_value > input_value {
+        return Err(SendTransactionError::InsufficientInputValue {
+            input_value,
+            output_value,
+        });
+    }
 
-    Ok(STATE.with(|s| s.borrow().get_balance(&request.address, min_confirmations)))
+    Ok(())
 }
 
+// Retrieves the status of the transaction with the given txid.
+//
+// NOTE: Transactions of stable blocks are only found if the canister was initialized
+//       with `index_transactions` set.
 #[update]
 #[candid_method(update)]
-fn get_utxos(request: GetUtxosRequest) -> Result<GetUtxosResponse, GetUtxosError> {
-    if Address::from_str(&request.address).is_err() {
-        return Err(GetUtxosError::MalformedAddress);
-    }
+fn get_transaction_status(
+    request: GetTransactionStatusRequest,
+) -> Result<TransactionStatus, GetTransactionStatusError> {
+    let txid =
+        Txid::from_slice(&request.txid).map_err(|_| GetTransactionStatusError::MalformedTxid)?;
 
-    let min_confirmations = request.min_confirmations.unwrap_or(0);
+    let location = STATE.with(|s| {
+        let state = s.borrow();
+        state
+            .get_transaction_location(&txid)
+            .map(|(height, block_hash)| (height, block_hash, state.main_chain_height()))
+    });
 
-    STATE.with(|s| {
-        let main_chain_height = s.borrow().main_chain_height();
+    // The main chain height counts the genesis block, so the tip of the main chain is at
+    // height `main_chain_height - 1`.
+    if let Some((height, block_hash, main_chain_height)) = location {
+        return Ok(TransactionStatus::Confirmed {
+            block_hash: block_hash.to_vec(),
+            height,
+            confirmations: main_chain_height - height,
+        });
+    }
+
+    if OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().is_queued(&txid, now())) {
+        Ok(TransactionStatus::Pending)
+    } else {
+        Ok(TransactionStatus::Unknown)
+    }
+}
 
-        let utxos: Vec<Utxo> = s
-            .borrow()
-            .get_utxos(&request.address, min_confirmations)
+// Retrieves the transactions submitted with `send_transaction` during the last 48 hours,
+// along with their state.
+#[query]
+#[candid_method(query)]
+fn get_sent_transactions() -> Vec<SentTransaction> {
+    OUTGOING_TRANSACTIONS.with(|txs| {
+        txs.borrow()
+            .get_states(now())
             .into_iter()
-            .map(|(outpoint, txout, height)| Utxo {
-                outpoint: OutPoint {
-                    txid: outpoint.txid.to_vec(),
-                    vout: outpoint.vout,
+            .map(|(txid, state)| SentTransaction {
+                txid: txid.to_vec(),
+                state: match state {
+                    OutgoingTransactionState::Pending => SentTransactionState::Pending,
+                    OutgoingTransactionState::Broadcast { count } => {
+                        SentTransactionState::Broadcast { count }
+                    }
+                    OutgoingTransactionState::Mined => SentTransactionState::Mined,
+                    OutgoingTransactionState::Expired => SentTransactionState::Expired,
                 },
-                value: txout.value,
-                height,
-                confirmations: main_chain_height - height + 1,
             })
-            .collect();
+            .collect()
+    })
+}
 
-        Ok(GetUtxosResponse {
-            total_count: utxos.len() as u32,
-            utxos,
-        })
+// Retrieves the most recently rejected blocks, oldest first, along with the reason they
+// were rejected. Rejected blocks aren't kept across upgrades.
+#[query]
+#[candid_method(query)]
+fn get_invalid_blocks() -> Vec<InvalidBlock> {
+    INVALID_BLOCKS.with(|invalid_blocks| invalid_blocks.borrow().iter().cloned().collect())
+}
+
+// Retrieves the fee rates of the transactions in the most recent blocks of the main chain
+// at the percentiles 0 to 100, in satoshi per vbyte.
+#[query]
+#[candid_method(query)]
+fn get_current_fee_percentiles() -> Vec<u64> {
+    STATE.with(|state| state.borrow().get_current_fee_percentiles())
+}
+
+// Retrieves statistics about the sync progress and the memory usage of the canister.
+#[query]
+#[candid_method(query)]
+fn get_metrics() -> Metrics {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Metrics {
+            stable_height: state.stable_height(),
+            main_chain_height: state.main_chain_height(),
+            unstable_blocks: state.num_unstable_blocks() as u64,
+            unstable_trees: state.num_unstable_trees() as u64,
+            utxos: state.num_utxos() as u64,
+            indexed_scripts: state.num_indexed_scripts() as u64,
+            outgoing_transactions: OUTGOING_TRANSACTIONS
+                .with(|txs| txs.borrow().num_queued(now()) as u64),
+            heap_size: heap_size(),
+            last_sync_instructions: LAST_SYNC_INSTRUCTIONS.with(|i| *i.borrow()),
+        }
     })
 }
 
+// Retrieves the hash of the stable UTXOs, which is comparable to the MuHash of the UTXO set
+// that Bitcoin Core computes.
+#[query]
+#[candid_method(query)]
+fn get_utxo_set_hash() -> UtxoSetHash {
+    STATE.with(|state| {
+        let state = state.borrow();
+        UtxoSetHash {
+            hash: state.utxo_set_hash().to_vec(),
+            stable_height: state.stable_height(),
+        }
+    })
+}
+
+// Adds the next chunk of the snapshot that the canister was initialized with.
+// On error, the upload starts over.
 #[update]
 #[candid_method(update)]
-fn send_transaction(request: SendTransactionRequest) -> Result<(), SendTransactionError> {
-    if Transaction::deserialize(&request.transaction).is_err() {
-        return Err(SendTransactionError::MalformedTransaction);
+fn upload_snapshot_chunk(chunk: Vec<u8>) -> Result<(), UploadSnapshotError> {
+    check_snapshot_uploader()?;
+
+    SNAPSHOT_UPLOAD.with(|upload| {
+        let mut upload = upload.borrow_mut();
+        let result = upload.as_mut().unwrap().loader.push_chunk(&chunk);
+        if let Err(err) = result {
+            let SnapshotUpload {
+                uploader, payload, ..
+            } = upload.take().unwrap();
+            upload.replace(SnapshotUpload::new(uploader, payload));
+            return Err(snapshot_error_to_candid(err));
+        }
+        Ok(())
+    })
+}
+
+// Replaces the state with the one of the uploaded snapshot, after which blocks are
+// inserted again. On error, the upload starts over.
+#[update]
+#[candid_method(update)]
+fn finish_snapshot_upload() -> Result<(), UploadSnapshotError> {
+    check_snapshot_uploader()?;
+
+    let SnapshotUpload {
+        uploader,
+        payload,
+        loader,
+    } = SNAPSHOT_UPLOAD.with(|upload| upload.borrow_mut().take().unwrap());
+    match loader.finish() {
+        Ok(mut state) => {
+            configure_state(&mut state, &payload);
+            STATE.with(|s| s.replace(state));
+            Ok(())
+        }
+        Err(err) => {
+            SNAPSHOT_UPLOAD
+                .with(|upload| upload.replace(Some(SnapshotUpload::new(uploader, payload))));
+            Err(snapshot_error_to_candid(err))
+        }
     }
+}
+
+// Checks that a snapshot is being uploaded, and that the caller is allowed to upload it.
+fn check_snapshot_uploader() -> Result<(), UploadSnapshotError> {
+    SNAPSHOT_UPLOAD.with(|upload| match &*upload.borrow() {
+        None => Err(UploadSnapshotError::NotExpected),
+        Some(upload) if upload.uploader != caller() => Err(UploadSnapshotError::Unauthorized),
+        Some(_) => Ok(()),
+    })
+}
 
-    // NOTE: In the final release, transactions will be cached for up to 24 hours and
-    // occasionally resent to the network until the transaction is observed in a block.
+fn snapshot_error_to_candid(err: SnapshotError) -> UploadSnapshotError {
+    match err {
+        SnapshotError::Malformed => UploadSnapshotError::Malformed,
+        SnapshotError::NetworkMismatch => UploadSnapshotError::NetworkMismatch,
+        SnapshotError::Incomplete => UploadSnapshotError::Incomplete,
+        SnapshotError::HashMismatch => UploadSnapshotError::HashMismatch,
+        // The snapshot has the expected hash, but isn't consistent.
+        SnapshotError::UtxoSetHashMismatch => UploadSnapshotError::Malformed,
+    }
+}
 
-    OUTGOING_TRANSACTIONS.with(|txs| {
-        txs.borrow_mut().push_back(request.transaction);
+// Retrieves the chain that the canister currently considers to be the main chain.
+#[query]
+#[candid_method(query)]
+fn get_current_chain() -> GetCurrentChainResponse {
+    STATE.with(|s| {
+        let state = s.borrow();
+        GetCurrentChainResponse {
+            anchor_hash: state.anchor_hash().to_vec(),
+            stable_height: state.stable_height(),
+            main_chain_height: state.main_chain_height(),
+            headers: state
+                .get_current_chain()
+                .iter()
+                .map(|block| BlockHeader {
+                    hash: block.block_hash().to_vec(),
+                    prev_hash: block.header.prev_blockhash.to_vec(),
+                    time: block.header.time,
+                    bits: block.header.bits,
+                })
+                .collect(),
+        }
+    })
+}
+
+// Fetches blocks from the adapter canister and forwards outgoing transactions to it, if an
+// adapter canister is configured. Around an upgrade, the state is also written to or read from
+// stable memory (see `pre_upgrade`).
+//
+// The adapter canister is expected to expose the following methods, which take and return
+// protobuf messages as defined in `proto.proto`:
+//
+//   get_successors: (blob) -> (blob);  // `GetSuccessorsRequest` -> `GetSuccessorsResponse`
+//   send_transaction: (blob) -> ();    // `SendTransactionRequest`
+#[heartbeat]
+fn heartbeat() {
+    // Until the state is read completely after an upgrade, neither the adapter canister nor the
+    // outgoing transactions are restored.
+    if STATE_LOAD.with(|load| load.borrow().is_some()) {
+        continue_state_load().expect("Reading from stable memory must succeed");
+        return;
+    }
+
+    UPGRADE_CHECKPOINT.with(|c| {
+        if let Some(checkpoint) = c.borrow_mut().as_mut() {
+            checkpoint
+                .write_batches(UPGRADE_BATCHES_PER_MESSAGE)
+                .expect("Writing to stable memory must succeed");
+        }
     });
 
-    Ok(())
+    let adapter_canister_id = match ADAPTER_CANISTER_ID.with(|id| *id.borrow()) {
+        Some(adapter_canister_id) => adapter_canister_id,
+        None => return,
+    };
+
+    if let Some(raw_tx) = OUTGOING_TRANSACTIONS.with(|txs| txs.borrow_mut().next_due(now())) {
+        ic_cdk::block_on(send_transaction_to_adapter(adapter_canister_id, raw_tx));
+    }
+
+    // Blocks aren't fetched until the snapshot is loaded, if there is one, or while the state
+    // is written to stable memory.
+    if is_block_insertion_paused() {
+        return;
+    }
+
+    if FETCH_SCHEDULER.with(|s| s.borrow_mut().try_start(now())) {
+        ic_cdk::block_on(fetch_blocks(adapter_canister_id));
+    }
 }
 
-// Below are helper methods used by the adapter shim. They will not be included in the main
-// release.
+async fn fetch_blocks(adapter_canister_id: Principal) {
+    let request = build_get_successors_request().encode_to_vec();
+    finish_fetch(call(adapter_canister_id, "get_successors", (request,)).await);
+}
 
-// Retrieves a `GetSuccessorsRequest` to send to the adapter.
-#[query]
-fn get_successors_request() -> Vec<u8> {
+// Processes the result of a `get_successors` call and schedules the next one.
+//
+// NOTE: If processing the response traps, the scheduler isn't updated, as the changes
+// of the callback are rolled back. The request is then retried once it times out.
+fn finish_fetch(result: Result<(Vec<u8>,), (RejectionCode, String)>) {
+    let made_progress = match result {
+        Ok((response,)) => process_get_successors_response(&response) > 0,
+        Err((code, message)) => {
+            print(&format!("Fetching blocks failed: {:?} {}", code, message));
+            false
+        }
+    };
+
+    FETCH_SCHEDULER.with(|s| s.borrow_mut().finish(now(), made_progress));
+}
+
+async fn send_transaction_to_adapter(adapter_canister_id: Principal, raw_tx: Vec<u8>) {
+    let request = btc::proto::SendTransactionRequest { raw_tx }.encode_to_vec();
+    let result: Result<(), (RejectionCode, String)> =
+        call(adapter_canister_id, "send_transaction", (request,)).await;
+
+    // Failed transactions are resent along with the others.
+    if let Err((code, message)) = result {
+        print(&format!(
+            "Sending transaction failed: {:?} {}",
+            code, message
+        ));
+    }
+}
+
+// Returns the `GetSuccessorsRequest` for the blocks following the current chain.
+fn build_get_successors_request() -> GetSuccessorsRequest {
     let block_hashes = STATE.with(|state| {
         let state = state.borrow();
         let mut block_hashes: Vec<Vec<u8>> = state
@@ -108,39 +1248,113 @@ fn get_successors_request() -> Vec<u8> {
         block_hashes
     });
 
-    print(format!("block hashes: {:?}", block_hashes));
-    GetSuccessorsRequest { block_hashes }.encode_to_vec()
+    GetSuccessorsRequest { block_hashes }
+}
+
+// Inserts the blocks of a (binary) `GetSuccessorsResponse` received from the adapter,
+// and records the number of instructions it took for `get_metrics`.
+// Returns the number of blocks that were inserted.
+fn process_get_successors_response(response_vec: &[u8]) -> usize {
+    // Blocks can't be inserted until the snapshot is loaded, or while the state is written to
+    // or read from stable memory.
+    if is_block_insertion_paused() {
+        return 0;
+    }
+
+    let start = instruction_counter();
+    let num_inserted_blocks = insert_successors(response_vec);
+    let instructions = start
+        .zip(instruction_counter())
+        .map(|(start, end)| end - start);
+    LAST_SYNC_INSTRUCTIONS.with(|i| i.replace(instructions));
+    num_inserted_blocks
+}
+
+// Inserts the blocks of a (binary) `GetSuccessorsResponse`.
+// Returns the number of blocks that were inserted.
+fn insert_successors(response_vec: &[u8]) -> usize {
+    let response = match GetSuccessorsResponse::decode(response_vec) {
+        Ok(response) => response,
+        Err(err) => {
+            print(&format!("Malformed GetSuccessorsResponse: {:?}", err));
+            return 0;
+        }
+    };
+
+    let mut num_inserted_blocks = 0;
+    for block_proto in response.blocks {
+        // Malformed blocks are skipped, as they don't even have a hash to report.
+        let block = match btc::block::from_proto(&block_proto) {
+            Some(block) => block,
+            None => {
+                print("Skipping malformed block");
+                continue;
+            }
+        };
+        let block_hash = block.block_hash();
+        print(&format!("Processing block with hash: {}", block_hash));
+
+        // Invalid blocks are skipped. Subsequent blocks that build on them will be
+        // rejected as well, since their predecessor is unknown.
+        STATE.with(|state| match state.borrow_mut().insert_block(block) {
+            Ok(new_stable_block) => {
+                // Outgoing transactions are no longer resent once they're in a stable block,
+                // as unstable blocks may still be discarded along with their fork.
+                if let Some(new_stable_block) = new_stable_block {
+                    let txids: Vec<Txid> =
+                        new_stable_block.txdata.iter().map(|tx| tx.txid()).collect();
+                    OUTGOING_TRANSACTIONS.with(|txs| txs.borrow_mut().mark_mined(&txids));
+                }
+                num_inserted_blocks += 1;
+            }
+            Err(err) => {
+                print(&format!("Rejected block {}: {:?}", block_hash, err));
+                INVALID_BLOCKS.with(|invalid_blocks| {
+                    let mut invalid_blocks = invalid_blocks.borrow_mut();
+                    invalid_blocks.push_back(InvalidBlock {
+                        block_hash: block_hash.to_vec(),
+                        reason: format!("{:?}", err),
+                    });
+                    if invalid_blocks.len() > MAX_INVALID_BLOCKS {
+                        invalid_blocks.pop_front();
+                    }
+                });
+            }
+        });
+    }
+
+    num_inserted_blocks
+}
+
+// Below are helper methods used by the adapter shim, which relays requests between the
+// canister and an adapter that isn't a canister. They will not be included in the main
+// release.
+
+// Retrieves a `GetSuccessorsRequest` to send to the adapter.
+#[query]
+fn get_successors_request() -> Vec<u8> {
+    let request = build_get_successors_request();
+    print(format!("block hashes: {:?}", request.block_hashes));
+    request.encode_to_vec()
 }
 
 #[query]
 fn has_outgoing_transaction() -> bool {
-    OUTGOING_TRANSACTIONS.with(|txs| !txs.borrow_mut().is_empty())
+    OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().has_due(now()))
 }
 
-// Retrieve a raw tx to send to the network
+// Retrieve a raw tx to send to the network. The transaction is returned again once it's
+// due to be resent.
 #[update]
 fn get_outgoing_transaction() -> Option<Vec<u8>> {
-    OUTGOING_TRANSACTIONS.with(|txs| txs.borrow_mut().pop_front())
+    OUTGOING_TRANSACTIONS.with(|txs| txs.borrow_mut().next_due(now()))
 }
 
 // Process a (binary) `GetSuccessorsResponse` received from the adapter.
 // Returns the height of the chain after the response is processed.
 #[update]
 fn get_successors_response(response_vec: Vec<u8>) -> u32 {
-    let response = GetSuccessorsResponse::decode(&*response_vec).unwrap();
-
-    for block_proto in response.blocks {
-        let block = btc::block::from_proto(&block_proto);
-        print(&format!(
-            "Processing block with hash: {}",
-            block.block_hash()
-        ));
-
-        STATE.with(|state| {
-            state.borrow_mut().insert_block(block);
-        });
-    }
-
+    process_get_successors_response(&response_vec);
     STATE.with(|state| state.borrow().main_chain_height())
 }
 
@@ -152,7 +1366,9 @@ mod test {
     use bitcoin::secp256k1::rand::rngs::OsRng;
     use bitcoin::secp256k1::Secp256k1;
     use bitcoin::{Address, PublicKey};
-    use btc::test_builder::{BlockBuilder, TransactionBuilder};
+    use btc::fetch_scheduler::{MIN_IDLE_DELAY, REQUEST_TIMEOUT};
+    use btc::store::InsertBlockError;
+    use btc::test_builder::{addresses_of_all_types, BlockBuilder, TransactionBuilder};
 
     #[test]
     fn check_candid_interface_compatibility() {
@@ -187,6 +1403,37 @@ mod test {
             .expect("bitcoin canister interface is not compatible with the candid.did file");
     }
 
+    #[test]
+    fn init_sets_network_and_genesis_block() {
+        for network in [
+            candid_types::Network::Bitcoin,
+            candid_types::Network::Regtest,
+            candid_types::Network::Testnet,
+            candid_types::Network::Signet,
+        ]
+        .iter()
+        {
+            init(InitPayload {
+                delta: 6,
+                network: *network,
+                index_transactions: None,
+                adapter_canister_id: None,
+                fork_choice: None,
+                validate_scripts: None,
+                snapshot_hash: None,
+            });
+
+            STATE.with(|s| {
+                let state = s.borrow();
+                assert_eq!(
+                    state.anchor_hash(),
+                    genesis_block(candid_types::to_bitcoin_network(*network)).block_hash()
+                );
+                assert_eq!(state.stable_height(), 1);
+            });
+        }
+    }
+
     #[test]
     fn get_utxos_from_existing_utxo_set() {
         for network in [
@@ -218,7 +1465,9 @@ mod test {
             assert_eq!(
                 get_utxos(GetUtxosRequest {
                     address: address.to_string(),
-                    min_confirmations: None
+                    min_confirmations: None,
+                    page: None,
+                    page_size: None,
                 }),
                 Ok(GetUtxosResponse {
                     utxos: vec![Utxo {
@@ -230,7 +1479,8 @@ mod test {
                         height: 1,
                         confirmations: 1
                     }],
-                    total_count: 1
+                    total_count: 1,
+                    next_page: None,
                 })
             );
         }
@@ -247,12 +1497,75 @@ mod test {
         );
     }
 
+    #[test]
+    fn address_network_mismatch() {
+        STATE.with(|s| {
+            s.replace(State::new(
+                1,
+                Network::Regtest,
+                genesis_block(Network::Regtest),
+            ))
+        });
+
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2wpkh(
+                &PublicKey::new(secp.generate_keypair(&mut rng).1),
+                Network::Bitcoin,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: address.to_string(),
+                min_confirmations: None
+            }),
+            Err(GetBalanceError::AddressNetworkMismatch {
+                expected: candid_types::Network::Regtest,
+                got: candid_types::Network::Bitcoin,
+            })
+        );
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                min_confirmations: None,
+                page: None,
+                page_size: None,
+            }),
+            Err(GetUtxosError::AddressNetworkMismatch {
+                expected: candid_types::Network::Regtest,
+                got: candid_types::Network::Bitcoin,
+            })
+        );
+
+        // Regtest shares the base58 prefixes of testnet.
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(
+                &PublicKey::new(secp.generate_keypair(&mut rng).1),
+                Network::Testnet,
+            )
+        };
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: address.to_string(),
+                min_confirmations: None
+            }),
+            Ok(0)
+        );
+    }
+
     #[test]
     fn get_utxos_malformed_address() {
         assert_eq!(
             get_utxos(GetUtxosRequest {
                 address: String::from("not an address"),
-                min_confirmations: None
+                min_confirmations: None,
+                page: None,
+                page_size: None,
             }),
             Err(GetUtxosError::MalformedAddress)
         );
@@ -299,7 +1612,7 @@ mod test {
             // Set the state.
             STATE.with(|s| {
                 s.replace(State::new(2, *network, block_0));
-                s.borrow_mut().insert_block(block_1);
+                s.borrow_mut().insert_block(block_1).unwrap();
             });
 
             // With up to one confirmation, expect address 2 to have a balance 1000, and
@@ -339,24 +1652,287 @@ mod test {
                 Ok(1000)
             );
 
-            // With >= 2 confirmations, both addresses should have an empty UTXO set.
-            for i in 3..10 {
-                assert_eq!(
-                    get_balance(GetBalanceRequest {
-                        address: address_2.to_string(),
-                        min_confirmations: Some(i)
-                    }),
-                    Ok(0)
-                );
-                assert_eq!(
-                    get_balance(GetBalanceRequest {
-                        address: address_1.to_string(),
-                        min_confirmations: Some(i)
-                    }),
-                    Ok(0)
-                );
-            }
-        }
+            // With >= 2 confirmations, both addresses should have an empty UTXO set.
+            for i in 3..10 {
+                assert_eq!(
+                    get_balance(GetBalanceRequest {
+                        address: address_2.to_string(),
+                        min_confirmations: Some(i)
+                    }),
+                    Ok(0)
+                );
+                assert_eq!(
+                    get_balance(GetBalanceRequest {
+                        address: address_1.to_string(),
+                        min_confirmations: Some(i)
+                    }),
+                    Ok(0)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn get_utxos_of_all_address_types() {
+        let network = Network::Regtest;
+        let addresses = addresses_of_all_types(network);
+
+        // Give 1000 satoshis to every address in the genesis block, which is stable, and
+        // another 500 satoshis in an unstable block.
+        let mut block_0 = BlockBuilder::genesis();
+        for address in &addresses {
+            block_0 = block_0.with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(address, 1000)
+                    .build(),
+            );
+        }
+        let block_0 = block_0.build();
+
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header);
+        let mut unstable_txids = vec![];
+        for address in &addresses {
+            let tx = TransactionBuilder::coinbase()
+                .with_output(address, 500)
+                .build();
+            unstable_txids.push(tx.txid());
+            block_1 = block_1.with_transaction(tx);
+        }
+        let block_1 = block_1.build();
+
+        STATE.with(|s| {
+            s.replace(State::new(2, network, block_0));
+            s.borrow_mut().insert_block(block_1).unwrap();
+        });
+
+        for (address, unstable_txid) in addresses.iter().zip(unstable_txids) {
+            assert_eq!(
+                get_balance(GetBalanceRequest {
+                    address: address.to_string(),
+                    min_confirmations: None
+                }),
+                Ok(1500),
+                "{}",
+                address
+            );
+
+            let response = get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                min_confirmations: Some(1),
+                page: None,
+                page_size: None,
+            })
+            .unwrap();
+            assert_eq!(response.total_count, 2, "{}", address);
+            assert_eq!(response.utxos[1].outpoint.txid, unstable_txid.to_vec());
+            assert_eq!(response.utxos[1].value, 500);
+        }
+
+        // Bech32 addresses can be looked up in uppercase too.
+        for address in &addresses[3..] {
+            assert_eq!(
+                get_balance(GetBalanceRequest {
+                    address: address.to_string().to_uppercase(),
+                    min_confirmations: None
+                }),
+                Ok(1500),
+                "{}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn get_balance_breakdown_test() {
+        let network = Network::Regtest;
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(&PublicKey::new(secp.generate_keypair(&mut rng).1), network)
+        };
+
+        // Give 1000 satoshis to the address in the genesis block, which is stable, and
+        // another 500 and 200 satoshis in two unstable blocks.
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 500)
+                    .build(),
+            )
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header)
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 200)
+                    .build(),
+            )
+            .build();
+
+        STATE.with(|s| {
+            s.replace(State::new(2, network, block_0));
+            s.borrow_mut().insert_block(block_1).unwrap();
+            s.borrow_mut().insert_block(block_2).unwrap();
+        });
+
+        assert_eq!(
+            get_balance_breakdown(GetBalanceBreakdownRequest {
+                address: address.to_string()
+            }),
+            Ok(BalanceBreakdown {
+                stable: 1000,
+                unstable: 700,
+                unstable_by_confirmations: vec![200, 500],
+                main_chain_height: 3,
+            })
+        );
+
+        assert_eq!(
+            get_balance_breakdown(GetBalanceBreakdownRequest {
+                address: String::from("not an address")
+            }),
+            Err(GetBalanceError::MalformedAddress)
+        );
+    }
+
+    #[test]
+    fn get_balances_and_utxos_multi() {
+        let network = Network::Regtest;
+        let address_1 = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(&PublicKey::new(secp.generate_keypair(&mut rng).1), network)
+        };
+        let address_2 = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(&PublicKey::new(secp.generate_keypair(&mut rng).1), network)
+        };
+
+        // Create a genesis block where 1000 satoshis are given to the address_1, followed
+        // by a block where address_1 gives 1000 satoshis to address_2.
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let tx = TransactionBuilder::with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address_2, 1000)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(tx)
+            .build();
+
+        STATE.with(|s| {
+            s.replace(State::new(2, network, block_0));
+            s.borrow_mut().insert_block(block_1).unwrap();
+        });
+
+        // Every request gets its own result, including the malformed ones.
+        assert_eq!(
+            get_balances(vec![
+                GetBalanceRequest {
+                    address: address_2.to_string(),
+                    min_confirmations: None
+                },
+                GetBalanceRequest {
+                    address: String::from("not an address"),
+                    min_confirmations: None
+                },
+                GetBalanceRequest {
+                    address: address_1.to_string(),
+                    min_confirmations: Some(2)
+                },
+                GetBalanceRequest {
+                    address: address_1.to_string(),
+                    min_confirmations: None
+                },
+            ]),
+            Ok(vec![
+                Ok(1000),
+                Err(GetBalanceError::MalformedAddress),
+                Ok(1000),
+                Ok(0)
+            ])
+        );
+
+        let request = |address: String, page: Option<Vec<u8>>| GetUtxosRequest {
+            address,
+            min_confirmations: None,
+            page,
+            page_size: None,
+        };
+        assert_eq!(
+            get_utxos_multi(vec![
+                request(address_2.to_string(), None),
+                request(String::from("not an address"), None),
+                request(address_1.to_string(), Some(vec![1, 2, 3])),
+                request(address_1.to_string(), None),
+            ]),
+            Ok(vec![
+                get_utxos(request(address_2.to_string(), None)),
+                Err(GetUtxosError::MalformedAddress),
+                Err(GetUtxosError::MalformedPage),
+                Ok(GetUtxosResponse {
+                    utxos: vec![],
+                    total_count: 0,
+                    next_page: None
+                }),
+            ])
+        );
+    }
+
+    #[test]
+    fn batch_size_limit() {
+        let balance_requests = |num_requests: u32| {
+            (0..num_requests)
+                .map(|_| GetBalanceRequest {
+                    address: String::from("not an address"),
+                    min_confirmations: None,
+                })
+                .collect()
+        };
+        let utxos_requests = |num_requests: u32| {
+            (0..num_requests)
+                .map(|_| GetUtxosRequest {
+                    address: String::from("not an address"),
+                    min_confirmations: None,
+                    page: None,
+                    page_size: None,
+                })
+                .collect()
+        };
+
+        // A batch of the maximum size is processed.
+        assert_eq!(
+            get_balances(balance_requests(MAX_REQUESTS_PER_BATCH))
+                .unwrap()
+                .len(),
+            MAX_REQUESTS_PER_BATCH as usize
+        );
+        assert_eq!(
+            get_utxos_multi(utxos_requests(MAX_REQUESTS_PER_BATCH))
+                .unwrap()
+                .len(),
+            MAX_REQUESTS_PER_BATCH as usize
+        );
+
+        // A larger batch is rejected as a whole.
+        assert_eq!(
+            get_balances(balance_requests(MAX_REQUESTS_PER_BATCH + 1)).err(),
+            Some(BatchError::TooManyRequests { max: 1_000 })
+        );
+        assert_eq!(
+            get_utxos_multi(utxos_requests(MAX_REQUESTS_PER_BATCH + 1)).err(),
+            Some(BatchError::TooManyRequests { max: 1_000 })
+        );
     }
 
     #[test]
@@ -400,7 +1976,7 @@ mod test {
             // Set the state.
             STATE.with(|s| {
                 s.replace(State::new(2, *network, block_0));
-                s.borrow_mut().insert_block(block_1);
+                s.borrow_mut().insert_block(block_1).unwrap();
             });
 
             // With up to one confirmation, expect address 2 to have one UTXO, and
@@ -409,7 +1985,9 @@ mod test {
                 assert_eq!(
                     get_utxos(GetUtxosRequest {
                         address: address_2.to_string(),
-                        min_confirmations: *min_confirmations
+                        min_confirmations: *min_confirmations,
+                        page: None,
+                        page_size: None,
                     }),
                     Ok(GetUtxosResponse {
                         utxos: vec![Utxo {
@@ -421,18 +1999,22 @@ mod test {
                             height: 2,
                             confirmations: 1,
                         }],
-                        total_count: 1
+                        total_count: 1,
+                        next_page: None,
                     })
                 );
 
                 assert_eq!(
                     get_utxos(GetUtxosRequest {
                         address: address_1.to_string(),
-                        min_confirmations: *min_confirmations
+                        min_confirmations: *min_confirmations,
+                        page: None,
+                        page_size: None,
                     }),
                     Ok(GetUtxosResponse {
                         utxos: vec![],
-                        total_count: 0
+                        total_count: 0,
+                        next_page: None,
                     })
                 );
             }
@@ -442,17 +2024,22 @@ mod test {
             assert_eq!(
                 get_utxos(GetUtxosRequest {
                     address: address_2.to_string(),
-                    min_confirmations: Some(2)
+                    min_confirmations: Some(2),
+                    page: None,
+                    page_size: None,
                 }),
                 Ok(GetUtxosResponse {
                     utxos: vec![],
-                    total_count: 0
+                    total_count: 0,
+                    next_page: None,
                 })
             );
             assert_eq!(
                 get_utxos(GetUtxosRequest {
                     address: address_1.to_string(),
-                    min_confirmations: Some(2)
+                    min_confirmations: Some(2),
+                    page: None,
+                    page_size: None,
                 }),
                 Ok(GetUtxosResponse {
                     utxos: vec![Utxo {
@@ -464,7 +2051,8 @@ mod test {
                         height: 1,
                         confirmations: 2,
                     }],
-                    total_count: 1
+                    total_count: 1,
+                    next_page: None,
                 })
             );
 
@@ -473,27 +2061,361 @@ mod test {
                 assert_eq!(
                     get_utxos(GetUtxosRequest {
                         address: address_2.to_string(),
-                        min_confirmations: Some(i)
+                        min_confirmations: Some(i),
+                        page: None,
+                        page_size: None,
                     }),
                     Ok(GetUtxosResponse {
                         utxos: vec![],
-                        total_count: 0
+                        total_count: 0,
+                        next_page: None,
                     })
                 );
                 assert_eq!(
                     get_utxos(GetUtxosRequest {
                         address: address_1.to_string(),
-                        min_confirmations: Some(i)
+                        min_confirmations: Some(i),
+                        page: None,
+                        page_size: None,
                     }),
                     Ok(GetUtxosResponse {
                         utxos: vec![],
-                        total_count: 0
+                        total_count: 0,
+                        next_page: None,
                     })
                 );
             }
         }
     }
 
+    #[test]
+    fn get_utxos_pagination() {
+        let network = Network::Regtest;
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(&PublicKey::new(secp.generate_keypair(&mut rng).1), network)
+        };
+
+        // Create a genesis block with 5 outputs to the address, followed by a block
+        // with another 5 outputs to the address.
+        let mut block_0 = BlockBuilder::genesis();
+        for _ in 0..5 {
+            block_0 = block_0.with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            );
+        }
+        let block_0 = block_0.build();
+
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header);
+        for _ in 0..5 {
+            block_1 = block_1.with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            );
+        }
+        let block_1 = block_1.build();
+
+        STATE.with(|s| {
+            s.replace(State::new(2, network, block_0));
+            s.borrow_mut().insert_block(block_1).unwrap();
+        });
+
+        let all_utxos = get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            min_confirmations: None,
+            page: None,
+            page_size: None,
+        })
+        .unwrap();
+        assert_eq!(all_utxos.total_count, 10);
+        assert_eq!(all_utxos.next_page, None);
+
+        // Walking through the pages returns all the UTXOs, in the same order.
+        let mut utxos = vec![];
+        let mut page = None;
+        loop {
+            let response = get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                min_confirmations: None,
+                page,
+                page_size: Some(3),
+            })
+            .unwrap();
+
+            assert!(response.utxos.len() <= 3);
+            assert_eq!(response.total_count, 10);
+            utxos.extend(response.utxos);
+
+            match response.next_page {
+                Some(next_page) => page = Some(next_page),
+                None => break,
+            }
+        }
+
+        assert_eq!(utxos, all_utxos.utxos);
+
+        // The UTXOs are ordered by height.
+        assert!(utxos.windows(2).all(|w| w[0].height <= w[1].height));
+    }
+
+    #[test]
+    fn get_utxos_malformed_page() {
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(
+                &PublicKey::new(secp.generate_keypair(&mut rng).1),
+                Network::Regtest,
+            )
+        };
+
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                min_confirmations: None,
+                page: Some(vec![1, 2, 3]),
+                page_size: None,
+            }),
+            Err(GetUtxosError::MalformedPage)
+        );
+    }
+
+    #[test]
+    fn get_current_chain_test() {
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+
+        let mut state = State::new(3, Network::Regtest, genesis_block.clone());
+        state.insert_block(block_1.clone()).unwrap();
+        state.insert_block(block_2.clone()).unwrap();
+        STATE.with(|s| s.replace(state));
+
+        assert_eq!(
+            get_current_chain(),
+            GetCurrentChainResponse {
+                anchor_hash: genesis_block.block_hash().to_vec(),
+                stable_height: 1,
+                main_chain_height: 3,
+                headers: vec![
+                    BlockHeader {
+                        hash: block_1.block_hash().to_vec(),
+                        prev_hash: genesis_block.block_hash().to_vec(),
+                        time: block_1.header.time,
+                        bits: block_1.header.bits,
+                    },
+                    BlockHeader {
+                        hash: block_2.block_hash().to_vec(),
+                        prev_hash: block_1.block_hash().to_vec(),
+                        time: block_2.header.time,
+                        bits: block_2.header.bits,
+                    }
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn get_transaction_status_test() {
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+
+        let mut state = State::new_with_tx_index(1, Network::Regtest, genesis_block.clone());
+        state.insert_block(block_1.clone()).unwrap();
+        state.insert_block(block_2.clone()).unwrap();
+        STATE.with(|s| s.replace(state));
+
+        // A stable transaction.
+        assert_eq!(
+            get_transaction_status(GetTransactionStatusRequest {
+                txid: block_1.txdata[0].txid().to_vec()
+            }),
+            Ok(TransactionStatus::Confirmed {
+                block_hash: block_1.block_hash().to_vec(),
+                height: 1,
+                confirmations: 2
+            })
+        );
+
+        // An unstable transaction.
+        assert_eq!(
+            get_transaction_status(GetTransactionStatusRequest {
+                txid: block_2.txdata[0].txid().to_vec()
+            }),
+            Ok(TransactionStatus::Confirmed {
+                block_hash: block_2.block_hash().to_vec(),
+                height: 2,
+                confirmations: 1
+            })
+        );
+
+        // A transaction that was sent but isn't in a block yet.
+        let tx =
+            TransactionBuilder::with_input(bitcoin::OutPoint::new(block_1.txdata[0].txid(), 0))
+                .build();
+        send_transaction(SendTransactionRequest {
+            transaction: bitcoin::consensus::serialize(&tx),
+        })
+        .unwrap();
+        assert_eq!(
+            get_transaction_status(GetTransactionStatusRequest {
+                txid: tx.txid().to_vec()
+            }),
+            Ok(TransactionStatus::Pending)
+        );
+
+        // An unknown transaction.
+        assert_eq!(
+            get_transaction_status(GetTransactionStatusRequest {
+                txid: TransactionBuilder::coinbase().build().txid().to_vec()
+            }),
+            Ok(TransactionStatus::Unknown)
+        );
+    }
+
+    #[test]
+    fn sent_transactions_are_mined_once_stable() {
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(
+                &PublicKey::new(secp.generate_keypair(&mut rng).1),
+                Network::Regtest,
+            )
+        };
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        STATE.with(|s| s.replace(State::new(1, Network::Regtest, block_0.clone())));
+
+        let tx = TransactionBuilder::with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address, 900)
+            .build();
+        send_transaction(SendTransactionRequest {
+            transaction: bitcoin::consensus::serialize(&tx),
+        })
+        .unwrap();
+        let sent_tx = |state| {
+            vec![SentTransaction {
+                txid: tx.txid().to_vec(),
+                state,
+            }]
+        };
+
+        // The transaction is in an unstable block, which may still be discarded.
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(tx.clone())
+            .build();
+        let response = GetSuccessorsResponse {
+            blocks: vec![btc::block::to_proto(&block_1)],
+        };
+        process_get_successors_response(&response.encode_to_vec());
+        assert_eq!(
+            get_sent_transactions(),
+            sent_tx(SentTransactionState::Pending)
+        );
+
+        // The block becomes stable.
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+        let response = GetSuccessorsResponse {
+            blocks: vec![btc::block::to_proto(&block_2)],
+        };
+        process_get_successors_response(&response.encode_to_vec());
+        assert_eq!(
+            get_sent_transactions(),
+            sent_tx(SentTransactionState::Mined)
+        );
+    }
+
+    #[test]
+    fn get_transaction_status_malformed_txid() {
+        assert_eq!(
+            get_transaction_status(GetTransactionStatusRequest {
+                txid: vec![1, 2, 3]
+            }),
+            Err(GetTransactionStatusError::MalformedTxid)
+        );
+    }
+
+    #[test]
+    fn send_transaction_checks_inputs() {
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(
+                &PublicKey::new(secp.generate_keypair(&mut rng).1),
+                Network::Regtest,
+            )
+        };
+
+        // A stable coinbase transaction and an unstable transaction spending it.
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let genesis_block = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let tx = TransactionBuilder::with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address, 900)
+            .build();
+        let block = BlockBuilder::with_prev_header(genesis_block.header)
+            .with_transaction(tx.clone())
+            .build();
+
+        let mut state = State::new(2, Network::Regtest, genesis_block);
+        state.insert_block(block).unwrap();
+        STATE.with(|s| s.replace(state));
+
+        let send = |tx: &Transaction| {
+            send_transaction(SendTransactionRequest {
+                transaction: bitcoin::consensus::serialize(tx),
+            })
+        };
+
+        // The output of the coinbase transaction is spent by the unstable block.
+        assert_eq!(
+            send(
+                &TransactionBuilder::with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0))
+                    .with_output(&address, 1000)
+                    .build()
+            ),
+            Err(SendTransactionError::UnknownInput(OutPoint {
+                txid: coinbase_tx.txid().to_vec(),
+                vout: 0
+            }))
+        );
+
+        // The output of the unstable transaction can be spent, but not for more than its value.
+        assert_eq!(
+            send(
+                &TransactionBuilder::with_input(bitcoin::OutPoint::new(tx.txid(), 0))
+                    .with_output(&address, 901)
+                    .build()
+            ),
+            Err(SendTransactionError::InsufficientInputValue {
+                input_value: 900,
+                output_value: 901
+            })
+        );
+
+        let new_tx = TransactionBuilder::with_input(bitcoin::OutPoint::new(tx.txid(), 0))
+            .with_output(&address, 800)
+            .build();
+        assert_eq!(send(&new_tx), Ok(()));
+
+        // Sending the same transaction again fails.
+        assert_eq!(send(&new_tx), Err(SendTransactionError::AlreadyQueued));
+    }
+
     #[test]
     fn malformed_transaction() {
         assert_eq!(
@@ -503,4 +2425,395 @@ mod test {
             Err(SendTransactionError::MalformedTransaction)
         );
     }
+
+    #[test]
+    fn invalid_blocks_are_reported() {
+        let network = Network::Regtest;
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(&PublicKey::new(secp.generate_keypair(&mut rng).1), network)
+        };
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+
+        // A block that spends the output of the genesis block without a signature, a block
+        // that can't be decoded, and a valid block.
+        let invalid_tx =
+            TransactionBuilder::with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0)).build();
+        let invalid_block = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(invalid_tx.clone())
+            .build();
+        let valid_block = BlockBuilder::with_prev_header(block_0.header).build();
+
+        STATE.with(|s| {
+            let mut state = State::new(2, network, block_0);
+            state.set_script_validation(true);
+            s.replace(state);
+        });
+
+        let malformed_block = btc::proto::Block {
+            header: None,
+            ..btc::block::to_proto(&valid_block)
+        };
+
+        let response = GetSuccessorsResponse {
+            blocks: vec![
+                btc::block::to_proto(&invalid_block),
+                malformed_block,
+                btc::block::to_proto(&valid_block),
+            ],
+        };
+        assert_eq!(
+            process_get_successors_response(&response.encode_to_vec()),
+            1
+        );
+
+        assert_eq!(
+            get_invalid_blocks(),
+            vec![InvalidBlock {
+                block_hash: invalid_block.block_hash().to_vec(),
+                reason: format!(
+                    "{:?}",
+                    InsertBlockError::InvalidInputScript(invalid_tx.input[0].previous_output)
+                ),
+            }]
+        );
+    }
+
+    #[test]
+    fn fetching_blocks_recovers_from_failures() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        STATE.with(|s| s.replace(State::new(2, Network::Regtest, block_0)));
+
+        let try_start = || FETCH_SCHEDULER.with(|s| s.borrow_mut().try_start(now()));
+        let set_time = |time| TEST_TIME.with(|t| t.set(time));
+
+        // A failed call delays the next one.
+        assert!(try_start());
+        finish_fetch(Err((
+            RejectionCode::SysTransient,
+            String::from("unreachable"),
+        )));
+        assert!(!try_start());
+        set_time(MIN_IDLE_DELAY);
+        assert!(try_start());
+
+        // Processing the response traps, so the request is never finished. Another one is
+        // sent once it times out.
+        assert!(!try_start());
+        set_time(MIN_IDLE_DELAY + REQUEST_TIMEOUT);
+        assert!(try_start());
+
+        // Blocks are inserted, and the next request is sent right away.
+        let response = GetSuccessorsResponse {
+            blocks: vec![btc::block::to_proto(&block_1)],
+        };
+        finish_fetch(Ok((response.encode_to_vec(),)));
+        assert_eq!(STATE.with(|s| s.borrow().main_chain_height()), 2);
+        assert!(try_start());
+    }
+
+    #[test]
+    fn get_metrics_test() {
+        let network = Network::Regtest;
+        let address = {
+            let secp = Secp256k1::new();
+            let mut rng = OsRng::new().unwrap();
+            Address::p2pkh(&PublicKey::new(secp.generate_keypair(&mut rng).1), network)
+        };
+
+        // A stable genesis block with two UTXOs of the same address, followed by two
+        // competing blocks.
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 2000)
+                    .build(),
+            )
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_1_prime = BlockBuilder::with_prev_header(block_0.header).build();
+
+        STATE.with(|s| {
+            s.replace(State::new(2, network, block_0));
+        });
+        let response = GetSuccessorsResponse {
+            blocks: vec![
+                btc::block::to_proto(&block_1),
+                btc::block::to_proto(&block_1_prime),
+            ],
+        };
+        process_get_successors_response(&response.encode_to_vec());
+
+        assert_eq!(
+            get_metrics(),
+            Metrics {
+                stable_height: 1,
+                main_chain_height: 2,
+                unstable_blocks: 2,
+                unstable_trees: 2,
+                utxos: 2,
+                indexed_scripts: 1,
+                outgoing_transactions: 0,
+                // The heap can't be measured outside of a canister.
+                heap_size: 0,
+                // The instructions between the two readings of the counter.
+                last_sync_instructions: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn bootstrap_from_snapshot() {
+        // A state with a few stable blocks to take a snapshot of.
+        let mut block = BlockBuilder::genesis().build();
+        let mut state = State::new(2, Network::Regtest, block.clone());
+        let mut blocks = vec![];
+        for _ in 0..10 {
+            block = BlockBuilder::with_prev_header(block.header).build();
+            state.insert_block(block.clone()).unwrap();
+            blocks.push(block.clone());
+        }
+
+        let mut snapshot = vec![];
+        let snapshot_hash = btc::snapshot::write(&state, &mut snapshot).unwrap();
+
+        init(InitPayload {
+            delta: 2,
+            network: candid_types::Network::Regtest,
+            index_transactions: None,
+            adapter_canister_id: None,
+            fork_choice: None,
+            validate_scripts: None,
+            snapshot_hash: Some(snapshot_hash.to_vec()),
+        });
+
+        // Blocks aren't inserted until the snapshot is loaded.
+        let response = GetSuccessorsResponse {
+            blocks: vec![btc::block::to_proto(&blocks[0])],
+        };
+        assert_eq!(
+            process_get_successors_response(&response.encode_to_vec()),
+            0
+        );
+
+        // Finishing early fails, and the upload starts over.
+        assert_eq!(upload_snapshot_chunk(snapshot[..10].to_vec()), Ok(()));
+        assert_eq!(
+            finish_snapshot_upload(),
+            Err(UploadSnapshotError::Incomplete)
+        );
+
+        for chunk in snapshot.chunks(100) {
+            assert_eq!(upload_snapshot_chunk(chunk.to_vec()), Ok(()));
+        }
+        assert_eq!(finish_snapshot_upload(), Ok(()));
+
+        STATE.with(|s| {
+            let s = s.borrow();
+            assert_eq!(s.anchor_hash(), state.anchor_hash());
+            assert_eq!(s.stable_height(), state.stable_height());
+        });
+        assert_eq!(
+            get_utxo_set_hash(),
+            UtxoSetHash {
+                hash: state.utxo_set_hash().to_vec(),
+                stable_height: state.stable_height(),
+            }
+        );
+        assert_eq!(
+            upload_snapshot_chunk(vec![]),
+            Err(UploadSnapshotError::NotExpected)
+        );
+
+        // The unstable blocks follow the snapshot.
+        let response = GetSuccessorsResponse {
+            blocks: state
+                .get_current_chain()
+                .into_iter()
+                .map(btc::block::to_proto)
+                .collect(),
+        };
+        assert_eq!(
+            process_get_successors_response(&response.encode_to_vec()),
+            state.get_current_chain().len()
+        );
+    }
+
+    // Returns the state as a protobuf struct with its UTXOs sorted, so that states with the
+    // same UTXOs are equal.
+    fn sorted_state_proto() -> btc::proto::State {
+        let mut state_proto = STATE.with(|s| s.borrow().to_proto());
+        state_proto
+            .utxos
+            .as_mut()
+            .unwrap()
+            .utxos
+            .sort_by_key(|utxo| utxo.encode_to_vec());
+        state_proto
+    }
+
+    // Upgrades the canister, which starts over with empty data apart from stable memory. The
+    // state may not be read completely yet.
+    fn simulate_upgrade() {
+        pre_upgrade();
+
+        STATE.with(|s| {
+            s.replace(State::new(
+                1,
+                Network::Bitcoin,
+                genesis_block(Network::Bitcoin),
+            ))
+        });
+        OUTGOING_TRANSACTIONS.with(|txs| txs.replace(OutgoingTransactions::new()));
+        ADAPTER_CANISTER_ID.with(|id| id.replace(None));
+        UPGRADE_CHECKPOINT.with(|c| c.replace(None));
+
+        post_upgrade();
+    }
+
+    #[test]
+    fn upgrade_preserves_data() {
+        let mut block = BlockBuilder::genesis().build();
+        let mut state = State::new(2, Network::Regtest, block.clone());
+        for _ in 0..5 {
+            block = BlockBuilder::with_prev_header(block.header).build();
+            state.insert_block(block.clone()).unwrap();
+        }
+        STATE.with(|s| s.replace(state));
+
+        let tx = TransactionBuilder::coinbase().build();
+        OUTGOING_TRANSACTIONS.with(|txs| {
+            txs.borrow_mut()
+                .insert(tx.txid(), bitcoin::consensus::serialize(&tx), now())
+        });
+        ADAPTER_CANISTER_ID.with(|id| id.replace(Some(Principal::anonymous())));
+
+        let state_proto = sorted_state_proto();
+        let txs_proto = OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().to_proto());
+
+        simulate_upgrade();
+        while STATE_LOAD.with(|load| load.borrow().is_some()) {
+            heartbeat();
+        }
+
+        assert_eq!(sorted_state_proto(), state_proto);
+        assert_eq!(
+            OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().to_proto()),
+            txs_proto
+        );
+        assert_eq!(
+            ADAPTER_CANISTER_ID.with(|id| *id.borrow()),
+            Some(Principal::anonymous())
+        );
+
+        // Truncated data is rejected.
+        TEST_STABLE_MEMORY.with(|memory| memory.borrow_mut().pop());
+        let mut result = start_state_load();
+        while result.is_ok() && STATE_LOAD.with(|load| load.borrow().is_some()) {
+            result = continue_state_load();
+        }
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn upgrade_in_steps() {
+        // A state with many more UTXOs and indexed transactions than fit in the batches that
+        // are written or read in a single message.
+        let mut block = BlockBuilder::genesis().build();
+        let mut state = State::new_with_tx_index(2, Network::Regtest, block.clone());
+        for _ in 0..20 {
+            block = BlockBuilder::with_prev_header(block.header).build();
+            state.insert_block(block.clone()).unwrap();
+        }
+        STATE.with(|s| s.replace(state));
+        let state_proto = sorted_state_proto();
+
+        let response = GetSuccessorsResponse {
+            blocks: vec![btc::block::to_proto(
+                &BlockBuilder::with_prev_header(block.header).build(),
+            )],
+        }
+        .encode_to_vec();
+
+        // Only the principal that installed or last upgraded the canister may prepare an
+        // upgrade.
+        UPGRADER.with(|upgrader| upgrader.replace(Principal::management_canister()));
+        assert_eq!(prepare_upgrade(), Err(PrepareUpgradeError::Unauthorized));
+        assert_eq!(cancel_upgrade(), Err(PrepareUpgradeError::Unauthorized));
+        UPGRADER.with(|upgrader| upgrader.replace(caller()));
+
+        // No blocks are inserted while the state is written, until the upgrade is canceled.
+        assert_eq!(prepare_upgrade(), Ok(false));
+        assert_eq!(process_get_successors_response(&response), 0);
+        assert_eq!(cancel_upgrade(), Ok(()));
+        assert!(!is_block_insertion_paused());
+
+        // The state is written over several heartbeats.
+        assert_eq!(prepare_upgrade(), Ok(false));
+        let mut num_heartbeats = 0;
+        while !UPGRADE_CHECKPOINT.with(|c| c.borrow().as_ref().unwrap().done) {
+            heartbeat();
+            num_heartbeats += 1;
+        }
+        assert!(num_heartbeats > 1);
+        assert_eq!(prepare_upgrade(), Ok(true));
+
+        // Transactions that are sent in the meantime are written in `pre_upgrade`.
+        let tx = TransactionBuilder::coinbase().build();
+        OUTGOING_TRANSACTIONS.with(|txs| {
+            txs.borrow_mut()
+                .insert(tx.txid(), bitcoin::consensus::serialize(&tx), now())
+        });
+        let txs_proto = OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().to_proto());
+
+        // The state is read over several heartbeats, during which no blocks are inserted and
+        // no other upgrade can be prepared.
+        simulate_upgrade();
+        assert_eq!(process_get_successors_response(&response), 0);
+        assert_eq!(prepare_upgrade(), Err(PrepareUpgradeError::Busy));
+        let mut num_heartbeats = 0;
+        while STATE_LOAD.with(|load| load.borrow().is_some()) {
+            heartbeat();
+            num_heartbeats += 1;
+        }
+        assert!(num_heartbeats > 1);
+
+        assert_eq!(sorted_state_proto(), state_proto);
+        assert_eq!(
+            OUTGOING_TRANSACTIONS.with(|txs| txs.borrow().to_proto()),
+            txs_proto
+        );
+
+        // Blocks are inserted again.
+        assert_eq!(process_get_successors_response(&response), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The canister can't be upgraded while a snapshot is being uploaded")]
+    fn upgrade_during_snapshot_upload() {
+        init(InitPayload {
+            delta: 2,
+            network: candid_types::Network::Regtest,
+            index_transactions: None,
+            adapter_canister_id: None,
+            fork_choice: None,
+            validate_scripts: None,
+            snapshot_hash: Some(vec![0; 32]),
+        });
+
+        pre_upgrade();
+    }
 }
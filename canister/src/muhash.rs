@@ -0,0 +1,312 @@
+//! MuHash3072, the rolling set hash that Bitcoin Core uses to hash its UTXO set
+//! (`gettxoutsetinfo muhash`).
+//!
+//! Every element is mapped to a number modulo the prime 2^3072 - 1103717, and a set is
+//! hashed as the product of the numbers of its elements, so elements can be inserted and
+//! removed in any order. Removed elements are multiplied into a separate denominator, so
+//! that the expensive modular inverse is only computed when the hash is finalized.
+use bitcoin::hashes::{sha256, Hash};
+
+// The number of 64-bit limbs of a 3072-bit number.
+const LIMBS: usize = 48;
+
+// The number of bytes of a 3072-bit number.
+const BYTES: usize = LIMBS * 8;
+
+// The modulus is 2^3072 - MODULUS_DIFF.
+const MODULUS_DIFF: u64 = 1103717;
+
+/// A rolling hash of a set of byte strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MuHash {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MuHash {
+    /// Returns the hash of the empty set.
+    pub fn new() -> Self {
+        Self {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+
+    /// Adds an element to the set.
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&Num3072::from_data(data));
+    }
+
+    /// Removes an element from the set.
+    ///
+    /// NOTE: The caller is responsible for only removing elements that are in the set.
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&Num3072::from_data(data));
+    }
+
+    /// Returns the 32-byte hash of the set, in the byte order that Bitcoin Core uses
+    /// internally. Core displays the hash with its bytes reversed.
+    pub fn finalize(&self) -> sha256::Hash {
+        let value = self.numerator.mul(&self.denominator.inverse());
+        sha256::Hash::hash(&value.to_bytes())
+    }
+
+    /// Returns true if both hashes are of the same set. This is cheaper than comparing the
+    /// finalized hashes, as no inverse needs to be computed.
+    pub fn is_same_set(&self, other: &Self) -> bool {
+        // a / b = c / d if and only if a * d = c * b.
+        self.numerator.mul(&other.denominator) == other.numerator.mul(&self.denominator)
+    }
+
+    /// Returns the state of the hash, which can be restored with `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.numerator.to_bytes();
+        bytes.extend(self.denominator.to_bytes());
+        bytes
+    }
+
+    /// Restores the state of a hash returned by `to_bytes`, or returns `None` if the
+    /// bytes aren't a valid state.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 2 * BYTES {
+            return None;
+        }
+
+        Some(Self {
+            numerator: Num3072::from_bytes(&bytes[..BYTES]),
+            denominator: Num3072::from_bytes(&bytes[BYTES..]),
+        })
+    }
+}
+
+// A 3072-bit number, as little-endian limbs, that is always less than the modulus.
+#[derive(Clone, Debug, PartialEq)]
+struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+    fn one() -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Self(limbs)
+    }
+
+    // Maps data to a number using the ChaCha20 keystream of its SHA-256 hash, as Core does.
+    fn from_data(data: &[u8]) -> Self {
+        let key = sha256::Hash::hash(data);
+        let mut keystream = [0; BYTES];
+        for (counter, block) in keystream.chunks_mut(64).enumerate() {
+            block.copy_from_slice(&chacha20_block(&key[..], counter as u64));
+        }
+        Self::from_bytes(&keystream)
+    }
+
+    // Decodes a little-endian number, reducing it if it's not less than the modulus.
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(8)) {
+            let mut limb_bytes = [0; 8];
+            limb_bytes.copy_from_slice(chunk);
+            *limb = u64::from_le_bytes(limb_bytes);
+        }
+        reduce_once(&mut limbs);
+        Self(limbs)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|limb| limb.to_le_bytes()).collect()
+    }
+
+    // Returns the product of two numbers modulo the modulus.
+    fn mul(&self, other: &Self) -> Self {
+        let mut product = [0u64; 2 * LIMBS];
+        for i in 0..LIMBS {
+            let mut carry = 0u128;
+            for j in 0..LIMBS {
+                let t = self.0[i] as u128 * other.0[j] as u128 + product[i + j] as u128 + carry;
+                product[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            product[i + LIMBS] = carry as u64;
+        }
+
+        // As 2^3072 ≡ MODULUS_DIFF, the high half of the product is folded into the low
+        // half by multiplying it with MODULUS_DIFF.
+        let mut limbs = [0; LIMBS];
+        let mut carry = 0u128;
+        for i in 0..LIMBS {
+            let t = product[i] as u128 + product[i + LIMBS] as u128 * MODULUS_DIFF as u128 + carry;
+            limbs[i] = t as u64;
+            carry = t >> 64;
+        }
+
+        // The same applies to the bits that overflow 2^3072.
+        while carry != 0 {
+            let mut overflow = carry * MODULUS_DIFF as u128;
+            for limb in limbs.iter_mut() {
+                let t = *limb as u128 + overflow;
+                *limb = t as u64;
+                overflow = t >> 64;
+            }
+            carry = overflow;
+        }
+
+        reduce_once(&mut limbs);
+        Self(limbs)
+    }
+
+    // Returns the modular inverse, which is self^(modulus - 2) as the modulus is prime.
+    fn inverse(&self) -> Self {
+        // The exponent is processed 4 bits at a time, using the powers self^0..self^15.
+        let mut powers = vec![Self::one(), self.clone()];
+        for i in 2..16 {
+            powers.push(powers[i - 1].mul(self));
+        }
+
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] = u64::MAX - MODULUS_DIFF - 1;
+
+        let mut result = Self::one();
+        for limb in exponent.iter().rev() {
+            for shift in (0..16).rev() {
+                for _ in 0..4 {
+                    result = result.mul(&result);
+                }
+                let window = (limb >> (shift * 4)) & 0xf;
+                if window != 0 {
+                    result = result.mul(&powers[window as usize]);
+                }
+            }
+        }
+        result
+    }
+}
+
+// Subtracts the modulus from a number that is less than twice the modulus, if needed.
+fn reduce_once(limbs: &mut [u64; LIMBS]) {
+    // A number is at least the modulus iff adding MODULUS_DIFF to it overflows 2^3072, in
+    // which case the wrapped-around sum is the number minus the modulus.
+    let mut sum = *limbs;
+    let mut carry = MODULUS_DIFF as u128;
+    for limb in sum.iter_mut() {
+        let t = *limb as u128 + carry;
+        *limb = t as u64;
+        carry = t >> 64;
+    }
+
+    if carry != 0 {
+        *limbs = sum;
+    }
+}
+
+// Returns a block of the ChaCha20 keystream with the given key, a zero nonce, and the given
+// block counter.
+fn chacha20_block(key: &[u8], counter: u64) -> [u8; 64] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (word, chunk) in input[4..12].iter_mut().zip(key.chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0; 64];
+    for (i, chunk) in block.chunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    block
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The 32-byte element that Core's tests derive from an integer.
+    fn element(i: u8) -> [u8; 32] {
+        let mut data = [0; 32];
+        data[0] = i;
+        data
+    }
+
+    // Returns the hash in the byte order that Core displays it in.
+    fn to_hex(hash: sha256::Hash) -> String {
+        hash.into_inner()
+            .iter()
+            .rev()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn bitcoin_core_test_vector() {
+        // From `muhash_tests` in Bitcoin Core's `crypto_tests.cpp`.
+        let mut muhash = MuHash::new();
+        muhash.insert(&element(0));
+        muhash.insert(&element(1));
+        muhash.remove(&element(2));
+        assert_eq!(
+            to_hex(muhash.finalize()),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+    }
+
+    #[test]
+    fn order_independence() {
+        let mut muhash_1 = MuHash::new();
+        let mut muhash_2 = MuHash::new();
+        for i in 0..10 {
+            muhash_1.insert(&element(i));
+            muhash_2.insert(&element(9 - i));
+        }
+        assert_eq!(muhash_1.finalize(), muhash_2.finalize());
+
+        // Removing all the elements results in the hash of the empty set.
+        for i in 0..10 {
+            muhash_1.remove(&element(i));
+        }
+        assert_eq!(muhash_1.finalize(), MuHash::new().finalize());
+        assert_ne!(muhash_1.finalize(), muhash_2.finalize());
+
+        // Hashes of the same set are recognized as such, even if their states differ.
+        assert!(muhash_1.is_same_set(&MuHash::new()));
+        assert_ne!(muhash_1, MuHash::new());
+        assert!(!muhash_1.is_same_set(&muhash_2));
+    }
+
+    #[test]
+    fn to_and_from_bytes() {
+        let mut muhash = MuHash::new();
+        muhash.insert(&element(0));
+        muhash.remove(&element(1));
+        assert_eq!(MuHash::from_bytes(&muhash.to_bytes()), Some(muhash));
+        assert_eq!(MuHash::from_bytes(&[0; 10]), None);
+    }
+}
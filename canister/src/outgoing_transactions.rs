@@ -0,0 +1,330 @@
+//! A cache of the transactions submitted with `send_transaction`.
+//!
+//! Transactions are resent to the network periodically until they're observed in a stable
+//! block or until they expire.
+use crate::proto;
+use bitcoin::{hashes::Hash, Txid};
+use std::collections::BTreeMap;
+
+// Timestamps are in nanoseconds, as returned by `ic_cdk::api::time`.
+type Timestamp = u64;
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+/// The interval after which a transaction that was already sent is resent.
+pub const REBROADCAST_INTERVAL: u64 = 10 * NANOS_PER_MINUTE;
+
+/// The maximum amount of time a transaction is resent for.
+pub const MAX_AGE: u64 = 24 * 60 * NANOS_PER_MINUTE;
+
+/// The state of an outgoing transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutgoingTransactionState {
+    /// The transaction hasn't been sent yet.
+    Pending,
+    /// The transaction has been sent `count` times.
+    Broadcast { count: u32 },
+    /// The transaction has been observed in a stable block.
+    Mined,
+    /// The transaction has been dropped before it was observed in a stable block.
+    Expired,
+}
+
+#[derive(Debug, PartialEq)]
+struct OutgoingTransaction {
+    // The raw transaction. Cleared once the transaction is mined or expired.
+    raw_tx: Vec<u8>,
+    submitted_at: Timestamp,
+    last_broadcast_at: Option<Timestamp>,
+    broadcast_count: u32,
+    mined: bool,
+}
+
+impl OutgoingTransaction {
+    fn state(&self, now: Timestamp) -> OutgoingTransactionState {
+        if self.mined {
+            OutgoingTransactionState::Mined
+        } else if self.is_expired(now) {
+            OutgoingTransactionState::Expired
+        } else if self.broadcast_count == 0 {
+            OutgoingTransactionState::Pending
+        } else {
+            OutgoingTransactionState::Broadcast {
+                count: self.broadcast_count,
+            }
+        }
+    }
+
+    fn is_expired(&self, now: Timestamp) -> bool {
+        now.saturating_sub(self.submitted_at) > MAX_AGE
+    }
+
+    fn is_forgotten(&self, now: Timestamp) -> bool {
+        now.saturating_sub(self.submitted_at) > 2 * MAX_AGE
+    }
+
+    fn is_due(&self, now: Timestamp) -> bool {
+        if self.mined || self.is_expired(now) {
+            return false;
+        }
+
+        match self.last_broadcast_at {
+            None => true,
+            Some(last_broadcast_at) => {
+                now.saturating_sub(last_broadcast_at) >= REBROADCAST_INTERVAL
+            }
+        }
+    }
+}
+
+/// The transactions submitted with `send_transaction`, keyed by their txid.
+///
+/// Mined and expired transactions are kept (without their raw bytes) for another `MAX_AGE`,
+/// so that their state can still be queried.
+#[derive(Debug, Default, PartialEq)]
+pub struct OutgoingTransactions {
+    txs: BTreeMap<Txid, OutgoingTransaction>,
+}
+
+impl OutgoingTransactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a transaction to be sent. Returns `false`, without modifying the transaction,
+    /// if a transaction with the same txid is already queued, i.e. it's neither mined
+    /// nor expired.
+    pub fn insert(&mut self, txid: Txid, raw_tx: Vec<u8>, now: Timestamp) -> bool {
+        if self.is_queued(&txid, now) {
+            return false;
+        }
+
+        self.txs.insert(
+            txid,
+            OutgoingTransaction {
+                raw_tx,
+                submitted_at: now,
+                last_broadcast_at: None,
+                broadcast_count: 0,
+                mined: false,
+            },
+        );
+        true
+    }
+
+    /// Returns true if the transaction with the given txid is still being sent.
+    pub fn is_queued(&self, txid: &Txid, now: Timestamp) -> bool {
+        matches!(
+            self.get_state(txid, now),
+            Some(OutgoingTransactionState::Pending)
+                | Some(OutgoingTransactionState::Broadcast { .. })
+        )
+    }
+
+    /// Returns the number of transactions that are still being sent.
+    pub fn num_queued(&self, now: Timestamp) -> usize {
+        self.txs
+            .values()
+            .filter(|tx| !tx.mined && !tx.is_expired(now))
+            .count()
+    }
+
+    /// Returns true if there is a transaction that should be sent.
+    pub fn has_due(&self, now: Timestamp) -> bool {
+        self.txs.values().any(|tx| tx.is_due(now))
+    }
+
+    /// Returns the next transaction that should be sent, and records that it was sent.
+    ///
+    /// Transactions that have been sent the fewest number of times are returned first.
+    pub fn next_due(&mut self, now: Timestamp) -> Option<Vec<u8>> {
+        self.prune(now);
+
+        let tx = self
+            .txs
+            .values_mut()
+            .filter(|tx| tx.is_due(now))
+            .min_by_key(|tx| (tx.broadcast_count, tx.submitted_at))?;
+
+        tx.broadcast_count += 1;
+        tx.last_broadcast_at = Some(now);
+        Some(tx.raw_tx.clone())
+    }
+
+    /// Marks the transactions with the given txids as mined, so they're no longer sent.
+    pub fn mark_mined(&mut self, txids: &[Txid]) {
+        for txid in txids {
+            if let Some(outgoing_tx) = self.txs.get_mut(txid) {
+                outgoing_tx.mined = true;
+                outgoing_tx.raw_tx.clear();
+            }
+        }
+    }
+
+    /// Returns the state of the transaction with the given txid, if it's known.
+    pub fn get_state(&self, txid: &Txid, now: Timestamp) -> Option<OutgoingTransactionState> {
+        self.txs
+            .get(txid)
+            .filter(|tx| !tx.is_forgotten(now))
+            .map(|tx| tx.state(now))
+    }
+
+    /// Returns the txids of all the known transactions along with their state.
+    pub fn get_states(&self, now: Timestamp) -> Vec<(Txid, OutgoingTransactionState)> {
+        self.txs
+            .iter()
+            .filter(|(_, tx)| !tx.is_forgotten(now))
+            .map(|(txid, tx)| (*txid, tx.state(now)))
+            .collect()
+    }
+
+    // Drops the raw bytes of expired transactions and forgets transactions that were
+    // submitted more than twice `MAX_AGE` ago.
+    fn prune(&mut self, now: Timestamp) {
+        self.txs.retain(|_, tx| !tx.is_forgotten(now));
+
+        for tx in self.txs.values_mut() {
+            if tx.is_expired(now) {
+                tx.raw_tx.clear();
+            }
+        }
+    }
+
+    pub fn to_proto(&self) -> proto::OutgoingTransactions {
+        proto::OutgoingTransactions {
+            txs: self
+                .txs
+                .iter()
+                .map(|(txid, tx)| proto::OutgoingTransaction {
+                    txid: txid.to_vec(),
+                    raw_tx: tx.raw_tx.clone(),
+                    submitted_at: tx.submitted_at,
+                    // Only set if the transaction has been sent.
+                    last_broadcast_at: tx.last_broadcast_at.unwrap_or(0),
+                    broadcast_count: tx.broadcast_count,
+                    mined: tx.mined,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_proto(txs_proto: proto::OutgoingTransactions) -> Self {
+        Self {
+            txs: txs_proto
+                .txs
+                .into_iter()
+                .map(|tx| {
+                    (
+                        Txid::from_hash(Hash::from_slice(&tx.txid).unwrap()),
+                        OutgoingTransaction {
+                            raw_tx: tx.raw_tx,
+                            submitted_at: tx.submitted_at,
+                            last_broadcast_at: if tx.broadcast_count > 0 {
+                                Some(tx.last_broadcast_at)
+                            } else {
+                                None
+                            },
+                            broadcast_count: tx.broadcast_count,
+                            mined: tx.mined,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_builder::TransactionBuilder;
+
+    #[test]
+    fn transactions_are_resent_until_mined() {
+        let tx = TransactionBuilder::coinbase().build();
+        let mut txs = OutgoingTransactions::new();
+        assert!(txs.insert(tx.txid(), vec![1, 2, 3], 0));
+
+        assert_eq!(
+            txs.get_state(&tx.txid(), 0),
+            Some(OutgoingTransactionState::Pending)
+        );
+        assert_eq!(txs.next_due(0), Some(vec![1, 2, 3]));
+
+        // The transaction isn't resent before the rebroadcast interval has passed.
+        assert!(!txs.has_due(REBROADCAST_INTERVAL - 1));
+        assert_eq!(txs.next_due(REBROADCAST_INTERVAL - 1), None);
+
+        assert!(txs.has_due(REBROADCAST_INTERVAL));
+        assert_eq!(txs.next_due(REBROADCAST_INTERVAL), Some(vec![1, 2, 3]));
+        assert_eq!(
+            txs.get_state(&tx.txid(), REBROADCAST_INTERVAL),
+            Some(OutgoingTransactionState::Broadcast { count: 2 })
+        );
+
+        // Once the transaction is in a block, it's no longer sent.
+        txs.mark_mined(&[tx.txid()]);
+        assert!(!txs.has_due(2 * REBROADCAST_INTERVAL));
+        assert_eq!(
+            txs.get_state(&tx.txid(), 2 * REBROADCAST_INTERVAL),
+            Some(OutgoingTransactionState::Mined)
+        );
+    }
+
+    #[test]
+    fn transactions_expire() {
+        let tx = TransactionBuilder::coinbase().build();
+        let mut txs = OutgoingTransactions::new();
+        txs.insert(tx.txid(), vec![1, 2, 3], 0);
+
+        assert!(txs.has_due(MAX_AGE));
+        assert!(!txs.has_due(MAX_AGE + 1));
+        assert_eq!(txs.num_queued(MAX_AGE), 1);
+        assert_eq!(txs.num_queued(MAX_AGE + 1), 0);
+        assert_eq!(txs.next_due(MAX_AGE + 1), None);
+        assert_eq!(
+            txs.get_state(&tx.txid(), MAX_AGE + 1),
+            Some(OutgoingTransactionState::Expired)
+        );
+
+        // Eventually, the transaction is forgotten.
+        txs.next_due(2 * MAX_AGE + 1);
+        assert_eq!(txs.get_state(&tx.txid(), 2 * MAX_AGE + 1), None);
+    }
+
+    #[test]
+    fn duplicate_transactions_are_ignored() {
+        let tx = TransactionBuilder::coinbase().build();
+        let mut txs = OutgoingTransactions::new();
+        assert!(txs.insert(tx.txid(), vec![1, 2, 3], 0));
+        txs.next_due(0);
+
+        assert!(!txs.insert(tx.txid(), vec![1, 2, 3], 1));
+        assert_eq!(
+            txs.get_state(&tx.txid(), 1),
+            Some(OutgoingTransactionState::Broadcast { count: 1 })
+        );
+
+        // Once the transaction expired, it can be sent again.
+        assert!(txs.insert(tx.txid(), vec![1, 2, 3], MAX_AGE + 1));
+        assert_eq!(
+            txs.get_state(&tx.txid(), MAX_AGE + 1),
+            Some(OutgoingTransactionState::Pending)
+        );
+    }
+
+    #[test]
+    fn to_from_proto() {
+        let mut txs = OutgoingTransactions::new();
+        for i in 0..3 {
+            txs.insert(
+                TransactionBuilder::coinbase().build().txid(),
+                vec![i],
+                i as u64,
+            );
+        }
+        txs.next_due(5);
+
+        assert_eq!(OutgoingTransactions::from_proto(txs.to_proto()), txs);
+    }
+}
@@ -0,0 +1,459 @@
+//! Validation of the scripts of transaction inputs.
+//!
+//! Only the most common types of outputs are supported: P2PKH, P2WPKH and P2SH, where
+//! P2SH outputs are fully validated if they wrap a P2WPKH output, and otherwise only the
+//! hash of the redeem script is checked. Inputs that spend other types of outputs are
+//! accepted as they are.
+//!
+//! As rejecting a valid block would stall the chain, inputs that can't be validated with
+//! certainty are accepted as well, e.g. if their script_sig contains other operations than
+//! pushes, or if their signature or public key can't be parsed.
+//!
+//! Witnesses are only validated from the SegWit activation height of the network on.
+//! Before that, P2WPKH outputs, including those wrapped in P2SH, were anyone-can-spend.
+use bitcoin::{
+    blockdata::opcodes::all::{OP_PUSHNUM_1, OP_PUSHNUM_16, OP_PUSHNUM_NEG1},
+    blockdata::script::Instruction,
+    hashes::{hash160, Hash},
+    secp256k1::{Message, Secp256k1, Signature, VerifyOnly},
+    util::bip143::SigHashCache,
+    Network, PublicKey, Script, SigHash, SigHashType, Transaction, TxOut,
+};
+use lazy_static::lazy_static;
+
+type Height = u32;
+
+lazy_static! {
+    // Creating a context is expensive, so a single one is shared by all validations.
+    static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
+}
+
+// The data pushed by OP_1 to OP_16.
+static SMALL_INTEGERS: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+// The data pushed by OP_1NEGATE.
+static NEGATIVE_ONE: [u8; 1] = [0x81];
+
+/// Returns the height of the first block in which SegWit is enforced on the given network.
+pub fn segwit_height(network: Network) -> Height {
+    match network {
+        Network::Bitcoin => 481_824,
+        Network::Testnet => 834_624,
+        Network::Signet | Network::Regtest => 0,
+    }
+}
+
+/// Returns true if the script of the input at `input_index` satisfies the script of the
+/// output it spends, where `height` is the height of the block containing the transaction.
+///
+/// `sighash_cache` must be created from the same transaction, so that it can be shared
+/// across the inputs of the transaction.
+pub fn verify_input(
+    network: Network,
+    height: Height,
+    tx: &Transaction,
+    input_index: usize,
+    prevout: &TxOut,
+    sighash_cache: &mut SigHashCache<&Transaction>,
+) -> bool {
+    let input = &tx.input[input_index];
+    let script_pubkey = &prevout.script_pubkey;
+    let segwit_active = height >= segwit_height(network);
+
+    if script_pubkey.is_p2pkh() {
+        // The script_sig ends with <signature> <public key>. Other operations than pushes
+        // aren't supported.
+        let pushes = match push_only(&input.script_sig) {
+            Some(pushes) => pushes,
+            None => return true,
+        };
+        let (signature, public_key) = match pushes.as_slice() {
+            [.., signature, public_key] => (signature, public_key),
+            _ => return false,
+        };
+        if hash160::Hash::hash(public_key)[..] != script_pubkey[3..23] {
+            return false;
+        }
+
+        verify_signature(signature, public_key, |sighash_type| {
+            tx.signature_hash(input_index, script_pubkey, sighash_type)
+        })
+    } else if script_pubkey.is_v0_p2wpkh() {
+        if !segwit_active {
+            // The output is anyone-can-spend.
+            return true;
+        }
+
+        input.script_sig.is_empty()
+            && verify_p2wpkh(
+                tx,
+                input_index,
+                &script_pubkey[2..22],
+                prevout.value,
+                sighash_cache,
+            )
+    } else if script_pubkey.is_p2sh() {
+        // The script_sig must only push data, the last of which is the redeem script.
+        let redeem_script = match push_only(&input.script_sig) {
+            Some(pushes) => match pushes.last() {
+                Some(redeem_script) => Script::from(redeem_script.to_vec()),
+                None => return false,
+            },
+            None => return false,
+        };
+        if hash160::Hash::hash(redeem_script.as_bytes())[..] != script_pubkey[2..22] {
+            return false;
+        }
+
+        if redeem_script.is_v0_p2wpkh() && segwit_active {
+            verify_p2wpkh(
+                tx,
+                input_index,
+                &redeem_script[2..22],
+                prevout.value,
+                sighash_cache,
+            )
+        } else {
+            // Other redeem scripts aren't supported, and before SegWit a P2WPKH redeem
+            // script only pushes the public key hash, which makes it succeed.
+            true
+        }
+    } else {
+        // Other types of outputs aren't supported.
+        true
+    }
+}
+
+// Verifies the witness of an input that spends a P2WPKH output with the given public key hash.
+fn verify_p2wpkh(
+    tx: &Transaction,
+    input_index: usize,
+    pubkey_hash: &[u8],
+    value: u64,
+    sighash_cache: &mut SigHashCache<&Transaction>,
+) -> bool {
+    // The witness is <signature> <public key>.
+    let witness = &tx.input[input_index].witness;
+    if witness.len() != 2 || hash160::Hash::hash(&witness[1])[..] != *pubkey_hash {
+        return false;
+    }
+
+    // The script code of P2WPKH is the P2PKH script of the public key hash.
+    let script_code = Script::new_p2pkh(&bitcoin::PubkeyHash::from_slice(pubkey_hash).unwrap());
+    verify_signature(&witness[0], &witness[1], |sighash_type| {
+        sighash_cache.signature_hash(
+            input_index,
+            &script_code,
+            value,
+            SigHashType::from_u32_consensus(sighash_type),
+        )
+    })
+}
+
+// Verifies a DER-encoded signature, followed by its sighash type, against a public key.
+//
+// Signatures are parsed leniently, as signatures from before BIP 66 aren't always strictly
+// DER-encoded. Signatures and public keys that can't be parsed at all are accepted.
+fn verify_signature<F>(signature: &[u8], public_key: &[u8], sighash: F) -> bool
+where
+    F: FnOnce(u32) -> SigHash,
+{
+    let (sighash_type, der_signature) = match signature.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    let public_key = match PublicKey::from_slice(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return true,
+    };
+
+    let mut signature = match Signature::from_der_lax(der_signature) {
+        Ok(signature) => signature,
+        Err(_) => return true,
+    };
+    // High-S signatures are valid by consensus, but rejected by `verify`.
+    signature.normalize_s();
+
+    let message = Message::from_slice(&sighash(*sighash_type as u32)[..])
+        .expect("A sighash is a valid message");
+    SECP.verify(&message, &signature, &public_key.key).is_ok()
+}
+
+// Returns the data pushed by the script, or `None` if the script contains other operations.
+// OP_0 to OP_16 and OP_1NEGATE are pushes as well.
+fn push_only(script: &Script) -> Option<Vec<&[u8]>> {
+    script
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(data)) => Some(data),
+            Ok(Instruction::Op(opcode)) => {
+                let code = opcode.into_u8();
+                if code == OP_PUSHNUM_NEG1.into_u8() {
+                    Some(&NEGATIVE_ONE[..])
+                } else if (OP_PUSHNUM_1.into_u8()..=OP_PUSHNUM_16.into_u8()).contains(&code) {
+                    let n = (code - OP_PUSHNUM_1.into_u8()) as usize;
+                    Some(&SMALL_INTEGERS[n..=n])
+                } else {
+                    None
+                }
+            }
+            Err(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_builder::TransactionBuilder;
+    use bitcoin::{
+        blockdata::script::Builder, secp256k1::rand::rngs::OsRng, secp256k1::SecretKey, Address,
+        Network, OutPoint,
+    };
+
+    const SIGHASH_ALL: u32 = 1;
+
+    struct Key {
+        secret_key: SecretKey,
+        public_key: PublicKey,
+    }
+
+    fn key() -> Key {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let (secret_key, public_key) = secp.generate_keypair(&mut rng);
+        Key {
+            secret_key,
+            public_key: PublicKey::new(public_key),
+        }
+    }
+
+    // Signs the sighash, returning the DER-encoded signature followed by the sighash type.
+    fn sign(key: &Key, sighash: SigHash) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let message = Message::from_slice(&sighash[..]).unwrap();
+        let mut signature = secp
+            .sign(&message, &key.secret_key)
+            .serialize_der()
+            .to_vec();
+        signature.push(SIGHASH_ALL as u8);
+        signature
+    }
+
+    // Returns a transaction that spends the given output, and the output itself.
+    fn spend(prev_address: &Address) -> (Transaction, TxOut) {
+        let prev_tx = TransactionBuilder::coinbase()
+            .with_output(prev_address, 1000)
+            .build();
+        let tx = TransactionBuilder::with_input(OutPoint::new(prev_tx.txid(), 0)).build();
+        (tx, prev_tx.output[0].clone())
+    }
+
+    fn verify(tx: &Transaction, prevout: &TxOut) -> bool {
+        verify_at(Network::Regtest, 0, tx, prevout)
+    }
+
+    fn verify_at(network: Network, height: Height, tx: &Transaction, prevout: &TxOut) -> bool {
+        verify_input(network, height, tx, 0, prevout, &mut SigHashCache::new(tx))
+    }
+
+    #[test]
+    fn p2pkh() {
+        let key = key();
+        let (mut tx, prevout) = spend(&Address::p2pkh(&key.public_key, Network::Regtest));
+
+        let signature = sign(
+            &key,
+            tx.signature_hash(0, &prevout.script_pubkey, SIGHASH_ALL),
+        );
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&signature)
+            .push_key(&key.public_key)
+            .into_script();
+        assert!(verify(&tx, &prevout));
+
+        // A signature of a different key is rejected.
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&signature)
+            .push_key(&key().public_key)
+            .into_script();
+        assert!(!verify(&tx, &prevout));
+
+        // A signature of a different transaction is rejected.
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&signature)
+            .push_key(&key.public_key)
+            .into_script();
+        tx.lock_time += 1;
+        assert!(!verify(&tx, &prevout));
+        tx.lock_time -= 1;
+
+        // Only the last two pushes are used.
+        tx.input[0].script_sig = Builder::new()
+            .push_int(1)
+            .push_slice(&signature)
+            .push_key(&key.public_key)
+            .into_script();
+        assert!(verify(&tx, &prevout));
+
+        // A script_sig with other operations than pushes isn't supported.
+        tx.input[0].script_sig = Builder::new()
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_NOP)
+            .push_key(&key().public_key)
+            .into_script();
+        assert!(verify(&tx, &prevout));
+    }
+
+    #[test]
+    fn lax_der_signatures() {
+        let key = key();
+        let (mut tx, prevout) = spend(&Address::p2pkh(&key.public_key, Network::Regtest));
+
+        // A signature whose R is padded with an extra zero, which isn't strictly DER.
+        let mut signature = sign(
+            &key,
+            tx.signature_hash(0, &prevout.script_pubkey, SIGHASH_ALL),
+        );
+        signature.insert(4, 0);
+        signature[1] += 1;
+        signature[3] += 1;
+        let der_signature = &signature[..signature.len() - 1];
+        assert!(Signature::from_der(der_signature).is_err());
+        assert!(Signature::from_der_lax(der_signature).is_ok());
+
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&signature)
+            .push_key(&key.public_key)
+            .into_script();
+        assert!(verify(&tx, &prevout));
+
+        // The signature is still verified.
+        tx.lock_time += 1;
+        assert!(!verify(&tx, &prevout));
+
+        // A signature that can't be parsed at all is accepted.
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&[0x30, 0x00, SIGHASH_ALL as u8])
+            .push_key(&key.public_key)
+            .into_script();
+        assert!(verify(&tx, &prevout));
+    }
+
+    #[test]
+    fn push_only_scripts() {
+        let script = Builder::new()
+            .push_int(0)
+            .push_int(-1)
+            .push_int(1)
+            .push_int(16)
+            .push_slice(&[0xab; 20])
+            .into_script();
+        assert_eq!(
+            push_only(&script),
+            Some(vec![
+                &[][..],
+                &[0x81][..],
+                &[1][..],
+                &[16][..],
+                &[0xab; 20][..]
+            ])
+        );
+
+        let script = Builder::new()
+            .push_int(1)
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_DROP)
+            .into_script();
+        assert_eq!(push_only(&script), None);
+    }
+
+    #[test]
+    fn p2wpkh() {
+        let key = key();
+        let address = Address::p2wpkh(&key.public_key, Network::Regtest).unwrap();
+        let (mut tx, prevout) = spend(&address);
+
+        let script_code = Address::p2pkh(&key.public_key, Network::Regtest).script_pubkey();
+        let signature = sign(
+            &key,
+            SigHashCache::new(&tx).signature_hash(0, &script_code, prevout.value, SigHashType::All),
+        );
+        tx.input[0].witness = vec![signature, key.public_key.to_bytes()];
+        assert!(verify(&tx, &prevout));
+
+        // The signature commits to the value of the spent output.
+        let mut other_prevout = prevout.clone();
+        other_prevout.value += 1;
+        assert!(!verify(&tx, &other_prevout));
+
+        // A missing witness is rejected.
+        tx.input[0].witness = vec![];
+        assert!(!verify(&tx, &prevout));
+    }
+
+    #[test]
+    fn p2sh_p2wpkh() {
+        let key = key();
+        let address = Address::p2shwpkh(&key.public_key, Network::Regtest).unwrap();
+        let (mut tx, prevout) = spend(&address);
+
+        let redeem_script = Address::p2wpkh(&key.public_key, Network::Regtest)
+            .unwrap()
+            .script_pubkey();
+        let script_code = Address::p2pkh(&key.public_key, Network::Regtest).script_pubkey();
+        let signature = sign(
+            &key,
+            SigHashCache::new(&tx).signature_hash(0, &script_code, prevout.value, SigHashType::All),
+        );
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(redeem_script.as_bytes())
+            .into_script();
+        tx.input[0].witness = vec![signature.clone(), key.public_key.to_bytes()];
+        assert!(verify(&tx, &prevout));
+
+        // A redeem script that doesn't match the script hash is rejected.
+        let other_redeem_script = Address::p2wpkh(&key().public_key, Network::Regtest)
+            .unwrap()
+            .script_pubkey();
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(other_redeem_script.as_bytes())
+            .into_script();
+        assert!(!verify(&tx, &prevout));
+    }
+
+    #[test]
+    fn witnesses_before_segwit_activation() {
+        let key = key();
+        let activation = segwit_height(Network::Bitcoin);
+
+        // Spends of P2WPKH outputs with an empty witness are only rejected from the
+        // activation height on.
+        let address = Address::p2wpkh(&key.public_key, Network::Bitcoin).unwrap();
+        let (tx, prevout) = spend(&address);
+        assert!(tx.input[0].witness.is_empty());
+        assert!(verify_at(Network::Bitcoin, activation - 1, &tx, &prevout));
+        assert!(!verify_at(Network::Bitcoin, activation, &tx, &prevout));
+
+        // Likewise for P2WPKH outputs wrapped in P2SH.
+        let address = Address::p2shwpkh(&key.public_key, Network::Bitcoin).unwrap();
+        let (mut tx, prevout) = spend(&address);
+        let redeem_script = Address::p2wpkh(&key.public_key, Network::Bitcoin)
+            .unwrap()
+            .script_pubkey();
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(redeem_script.as_bytes())
+            .into_script();
+        assert!(verify_at(Network::Bitcoin, activation - 1, &tx, &prevout));
+        assert!(!verify_at(Network::Bitcoin, activation, &tx, &prevout));
+
+        // The redeem script must still match the script hash.
+        tx.input[0].script_sig = Builder::new().push_slice(&[0xab; 22]).into_script();
+        assert!(!verify_at(Network::Bitcoin, activation - 1, &tx, &prevout));
+    }
+
+    #[test]
+    fn unsupported_outputs_are_accepted() {
+        let (tx, prevout) = spend(&Address::p2wsh(&Script::new(), Network::Regtest));
+        assert!(verify(&tx, &prevout));
+    }
+}
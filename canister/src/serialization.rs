@@ -0,0 +1,315 @@
+//! The format in which states and snapshots are serialized.
+//!
+//! A stream starts with the version of the format, followed by protobuf messages that are
+//! each prefixed with their length, and ends with the SHA-256 hash of all the preceding
+//! bytes as a checksum. Integers are little-endian.
+use crate::proto;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use prost::Message;
+use std::io::{self, Read, Write};
+
+/// The version of the format. It must be incremented whenever the format changes.
+pub const VERSION: u32 = 1;
+
+/// The number of UTXOs that are encoded together in a `UtxoBatch`.
+pub const UTXO_BATCH_SIZE: usize = 10_000;
+
+/// The length of the checksum that ends a stream.
+pub const CHECKSUM_LEN: usize = 32;
+
+/// Writes a stream, computing its checksum as it goes.
+pub struct StreamWriter<'a, W> {
+    writer: &'a mut W,
+    engine: sha256::HashEngine,
+}
+
+impl<'a, W: Write> StreamWriter<'a, W> {
+    /// Starts a stream by writing the version of the format.
+    pub fn new(writer: &'a mut W) -> io::Result<Self> {
+        let mut stream_writer = Self {
+            writer,
+            engine: sha256::Hash::engine(),
+        };
+        stream_writer.write_u32::<LittleEndian>(VERSION)?;
+        Ok(stream_writer)
+    }
+
+    /// Continues a stream that was suspended with `suspend`. The writer must continue where
+    /// the one the stream was suspended from stopped.
+    pub fn resume(writer: &'a mut W, engine: sha256::HashEngine) -> Self {
+        Self { writer, engine }
+    }
+
+    /// Suspends the stream, so that it can be continued in a later message with `resume`.
+    /// Returns the checksum computed so far.
+    pub fn suspend(self) -> sha256::HashEngine {
+        self.engine
+    }
+
+    /// Writes a message prefixed with its length.
+    pub fn write_message<M: Message>(&mut self, message: &M) -> io::Result<()> {
+        write_message(self, message)
+    }
+
+    /// Writes UTXOs in `UtxoBatch`es of the given size, followed by an empty batch that marks
+    /// their end.
+    pub fn write_utxos<I: Iterator<Item = proto::Utxo>>(
+        &mut self,
+        utxos: I,
+        batch_size: usize,
+    ) -> io::Result<()> {
+        let mut batch = proto::UtxoBatch { utxos: vec![] };
+        for utxo in utxos {
+            batch.utxos.push(utxo);
+            if batch.utxos.len() == batch_size {
+                self.write_message(&batch)?;
+                batch.utxos.clear();
+            }
+        }
+
+        if !batch.utxos.is_empty() {
+            self.write_message(&batch)?;
+        }
+
+        self.write_message(&proto::UtxoBatch { utxos: vec![] })
+    }
+
+    /// Ends the stream with its checksum, which is returned.
+    pub fn finish(self) -> io::Result<sha256::Hash> {
+        let checksum = sha256::Hash::from_engine(self.engine);
+        self.writer.write_all(&checksum[..])?;
+        Ok(checksum)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.engine.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads a stream from a reader, computing its checksum as it goes. No more bytes are read
+/// than were written.
+pub struct StreamReader<'a, R> {
+    reader: &'a mut R,
+    engine: sha256::HashEngine,
+}
+
+impl<'a, R: Read> StreamReader<'a, R> {
+    /// Starts reading a stream by reading the version of the format, failing if it isn't
+    /// the current version.
+    pub fn new(reader: &'a mut R) -> io::Result<Self> {
+        let version = reader.read_u32::<LittleEndian>()?;
+        check_version(version)?;
+        let mut engine = sha256::Hash::engine();
+        engine.input(&version.to_le_bytes());
+        Ok(Self { reader, engine })
+    }
+
+    /// Continues reading a stream that was suspended with `suspend`. The reader must continue
+    /// where the one the stream was suspended from stopped.
+    pub fn resume(reader: &'a mut R, engine: sha256::HashEngine) -> Self {
+        Self { reader, engine }
+    }
+
+    /// Suspends reading the stream, so that it can be continued in a later message with
+    /// `resume`. Returns the checksum computed so far.
+    pub fn suspend(self) -> sha256::HashEngine {
+        self.engine
+    }
+
+    /// Reads a message that was written with `StreamWriter::write_message`.
+    pub fn read_message<M: Message + Default>(&mut self) -> io::Result<M> {
+        read_message(self)
+    }
+
+    /// Reads the checksum that ends the stream, failing if it doesn't match.
+    pub fn finish(self) -> io::Result<()> {
+        let checksum = sha256::Hash::from_engine(self.engine);
+        let mut expected_checksum = [0; CHECKSUM_LEN];
+        self.reader.read_exact(&mut expected_checksum)?;
+        if checksum[..] != expected_checksum[..] {
+            return Err(invalid_data("Checksum mismatch"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.engine.input(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Reads a stream that is received in chunks of any size, so that it never needs to be held
+/// in memory as a whole.
+pub struct ChunkReader {
+    engine: sha256::HashEngine,
+
+    // The bytes received so far that weren't read yet.
+    buffer: Vec<u8>,
+
+    // Whether the version at the start of the stream was read.
+    version_read: bool,
+}
+
+impl ChunkReader {
+    pub fn new() -> Self {
+        Self {
+            engine: sha256::Hash::engine(),
+            buffer: vec![],
+            version_read: false,
+        }
+    }
+
+    /// Adds the next chunk of the stream.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Returns the next message, or `None` if it wasn't received completely yet.
+    pub fn next_message<M: Message + Default>(&mut self) -> io::Result<Option<M>> {
+        if !self.version_read {
+            if self.buffer.len() < 4 {
+                return Ok(None);
+            }
+            check_version((&self.buffer[..4]).read_u32::<LittleEndian>()?)?;
+            self.consume(4);
+            self.version_read = true;
+        }
+
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+        let len = (&self.buffer[..4]).read_u32::<LittleEndian>()? as usize;
+        if self.buffer.len() < 4 + len {
+            return Ok(None);
+        }
+
+        let message = M::decode(&self.buffer[4..4 + len]).map_err(invalid_data)?;
+        self.consume(4 + len);
+        Ok(Some(message))
+    }
+
+    /// Returns the number of bytes that were received but not read yet.
+    pub fn unread_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Reads the checksum that ends the stream, which must follow the last message that was
+    /// read, and returns it if it matches.
+    pub fn finish(self) -> io::Result<sha256::Hash> {
+        if self.buffer.len() < CHECKSUM_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The stream ended before its checksum",
+            ));
+        }
+
+        let checksum = sha256::Hash::from_engine(self.engine);
+        if self.buffer[..] != checksum[..] {
+            return Err(invalid_data("Checksum mismatch"));
+        }
+        Ok(checksum)
+    }
+
+    // Drops bytes that were read from the buffer, adding them to the checksum.
+    fn consume(&mut self, len: usize) {
+        self.engine.input(&self.buffer[..len]);
+        self.buffer.drain(..len);
+    }
+}
+
+impl Default for ChunkReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes a protobuf message prefixed with its length.
+pub fn write_message<W: Write, M: Message>(writer: &mut W, message: &M) -> io::Result<()> {
+    let bytes = message.encode_to_vec();
+    writer.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    writer.write_all(&bytes)
+}
+
+/// Reads a protobuf message that was written with `write_message`.
+pub fn read_message<R: Read, M: Message + Default>(reader: &mut R) -> io::Result<M> {
+    let len = reader.read_u32::<LittleEndian>()?;
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    M::decode(&*bytes).map_err(invalid_data)
+}
+
+/// Returns an error for data that can't be deserialized.
+pub fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn check_version(version: u32) -> io::Result<()> {
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported serialization version: {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        let messages: Vec<proto::OutPoint> = (0..10)
+            .map(|vout| proto::OutPoint {
+                txid: vec![vout as u8; 32],
+                vout,
+            })
+            .collect();
+
+        let mut bytes = vec![];
+        let mut writer = StreamWriter::new(&mut bytes).unwrap();
+        for message in &messages {
+            writer.write_message(message).unwrap();
+        }
+        let checksum = writer.finish().unwrap();
+
+        // The stream can be read regardless of how it's split into chunks.
+        for chunk_size in [1, 7, bytes.len()].iter() {
+            let mut reader = ChunkReader::new();
+            let mut read_messages: Vec<proto::OutPoint> = vec![];
+            for chunk in bytes.chunks(*chunk_size) {
+                reader.push(chunk);
+                while read_messages.len() < messages.len() {
+                    match reader.next_message().unwrap() {
+                        Some(message) => read_messages.push(message),
+                        None => break,
+                    }
+                }
+            }
+
+            assert_eq!(read_messages, messages);
+            assert_eq!(reader.unread_len(), CHECKSUM_LEN);
+            assert_eq!(reader.finish().unwrap(), checksum);
+        }
+
+        // Streams of other versions are rejected.
+        let mut reader = ChunkReader::new();
+        reader.push(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            reader.next_message::<proto::OutPoint>().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
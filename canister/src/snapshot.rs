@@ -0,0 +1,332 @@
+//! Snapshots of the stable part of the state, which allow bootstrapping a `State` from a
+//! trusted checkpoint instead of syncing the blockchain from genesis.
+//!
+//! A snapshot is serialized like a `State` (see `serialization`), except that only the
+//! `StateHeader` and the `UtxoBatch`es are included. A snapshot is identified by its
+//! checksum, i.e. the SHA-256 hash of all of its bytes but the last 32, which are the
+//! checksum itself.
+use crate::{
+    block,
+    muhash::MuHash,
+    proto,
+    serialization::{ChunkReader, StreamWriter, CHECKSUM_LEN, UTXO_BATCH_SIZE},
+    store::State,
+    utxoset::network_from_proto,
+};
+use bitcoin::hashes::sha256;
+use bitcoin::{BlockHeader, Network};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+/// Errors that can occur when loading a snapshot.
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    /// The snapshot couldn't be decoded.
+    Malformed,
+    /// The snapshot is of a different network than the expected one.
+    NetworkMismatch,
+    /// The snapshot ended before all of its contents were received.
+    Incomplete,
+    /// The hash of the snapshot differs from the expected hash.
+    HashMismatch,
+    /// The UTXOs of the snapshot don't match the hash of the UTXOs in its header.
+    UtxoSetHashMismatch,
+}
+
+/// Writes a snapshot of the stable part of the state, and returns the snapshot's hash.
+pub fn write<W: Write>(state: &State, writer: &mut W) -> io::Result<sha256::Hash> {
+    let mut writer = StreamWriter::new(writer)?;
+
+    // The configuration of the state is up to the loader, and the transaction index isn't
+    // part of snapshots.
+    writer.write_message(&proto::StateHeader {
+        index_txs: false,
+        validate_scripts: false,
+        stable_fee_rates: vec![],
+        ..state.header_to_proto()
+    })?;
+    writer.write_utxos(state.iter_utxos_proto(), UTXO_BATCH_SIZE)?;
+    writer.finish()
+}
+
+/// Builds a `State` from a snapshot that is received in chunks, so that the snapshot
+/// never needs to be held in memory as a whole.
+///
+/// The UTXOs are inserted as they're received, but the state is only returned once the
+/// whole snapshot is received and its hash is the expected one.
+pub struct SnapshotLoader {
+    delta: u64,
+    network: Network,
+    index_txs: bool,
+    expected_hash: sha256::Hash,
+
+    // The reader of the chunks received so far.
+    reader: ChunkReader,
+
+    // The state, once the snapshot's header is received.
+    state: Option<State>,
+
+    // The hash of the UTXOs according to the snapshot's header, once it's received. It's
+    // checked against the hash of the UTXOs that are actually received.
+    expected_muhash: Option<MuHash>,
+
+    // Whether the empty batch that ends the UTXOs was received.
+    complete: bool,
+}
+
+impl SnapshotLoader {
+    /// Creates a loader of a snapshot of the given network with the given hash.
+    ///
+    /// The `delta` parameter is that of `State::new`, and `index_txs` specifies whether the
+    /// state indexes the transactions of the blocks that become stable after the snapshot.
+    pub fn new(delta: u64, network: Network, index_txs: bool, expected_hash: sha256::Hash) -> Self {
+        Self {
+            delta,
+            network,
+            index_txs,
+            expected_hash,
+            reader: ChunkReader::new(),
+            state: None,
+            expected_muhash: None,
+            complete: false,
+        }
+    }
+
+    /// Processes the next chunk of the snapshot. Chunks can be of any size.
+    pub fn push_chunk(&mut self, chunk: &[u8]) -> Result<(), SnapshotError> {
+        self.reader.push(chunk);
+
+        // Process all the messages that are complete.
+        while !self.complete {
+            let result = if self.state.is_none() {
+                self.next_message()?
+                    .map(|header| self.process_header(header))
+            } else {
+                self.next_message()?.map(|batch| self.process_batch(batch))
+            };
+
+            match result {
+                Some(result) => result?,
+                None => break,
+            }
+        }
+
+        // Only the checksum may follow the UTXOs.
+        if self.complete && self.reader.unread_len() > CHECKSUM_LEN {
+            return Err(SnapshotError::Malformed);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the state once the whole snapshot has been received.
+    pub fn finish(self) -> Result<State, SnapshotError> {
+        if !self.complete {
+            return Err(SnapshotError::Incomplete);
+        }
+
+        let checksum = self.reader.finish().map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Incomplete,
+            _ => SnapshotError::Malformed,
+        })?;
+        if checksum != self.expected_hash {
+            return Err(SnapshotError::HashMismatch);
+        }
+
+        let state = self.state.expect("A complete snapshot must have a header");
+        let expected_muhash = self
+            .expected_muhash
+            .expect("A complete snapshot must have a header");
+        if !state.utxos_muhash().is_same_set(&expected_muhash) {
+            return Err(SnapshotError::UtxoSetHashMismatch);
+        }
+
+        Ok(state)
+    }
+
+    // Returns the next message of the snapshot, if it was received completely.
+    fn next_message<M: prost::Message + Default>(&mut self) -> Result<Option<M>, SnapshotError> {
+        self.reader
+            .next_message()
+            .map_err(|_| SnapshotError::Malformed)
+    }
+
+    fn process_header(&mut self, header: proto::StateHeader) -> Result<(), SnapshotError> {
+        let network = network_from_proto(header.network).ok_or(SnapshotError::Malformed)?;
+        if network != self.network {
+            return Err(SnapshotError::NetworkMismatch);
+        }
+
+        if header.height == 0 || header.stable_headers.is_empty() {
+            return Err(SnapshotError::Malformed);
+        }
+
+        let stable_headers: VecDeque<BlockHeader> = header
+            .stable_headers
+            .iter()
+            .map(block::header_from_proto)
+            .collect::<Option<_>>()
+            .ok_or(SnapshotError::Malformed)?;
+        if stable_headers.back().unwrap().block_hash()[..] != header.anchor[..] {
+            return Err(SnapshotError::Malformed);
+        }
+
+        let muhash = MuHash::from_bytes(&header.utxos_muhash).ok_or(SnapshotError::Malformed)?;
+
+        self.state = Some(State::from_checkpoint(
+            self.delta,
+            self.network,
+            header.height,
+            stable_headers,
+            self.index_txs,
+        ));
+        self.expected_muhash = Some(muhash);
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch: proto::UtxoBatch) -> Result<(), SnapshotError> {
+        // An empty batch marks the end of the UTXOs.
+        if batch.utxos.is_empty() {
+            self.complete = true;
+        }
+
+        let state = self
+            .state
+            .as_mut()
+            .expect("The header must be processed first");
+        for utxo in batch.utxos {
+            if !is_well_formed(&utxo) {
+                return Err(SnapshotError::Malformed);
+            }
+
+            state
+                .insert_utxo_proto(utxo)
+                .map_err(|_| SnapshotError::Malformed)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Returns true if the UTXO can be inserted into the state without panicking.
+fn is_well_formed(utxo: &proto::Utxo) -> bool {
+    match (&utxo.outpoint, &utxo.txout) {
+        (Some(outpoint), Some(_)) => outpoint.txid.len() == 32,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_builder::{BlockBuilder, TransactionBuilder};
+    use bitcoin::hashes::Hash;
+    use bitcoin::Block;
+
+    // Returns a state with stable and unstable blocks, along with the unstable blocks of
+    // its main chain.
+    fn state_with_blocks() -> (State, Vec<Block>) {
+        let mut block = BlockBuilder::genesis()
+            .with_transaction(TransactionBuilder::coinbase().build())
+            .build();
+        let mut state = State::new(3, Network::Regtest, block.clone());
+
+        for _ in 0..20 {
+            block = BlockBuilder::with_prev_header(block.header)
+                .with_transaction(TransactionBuilder::coinbase().build())
+                .build();
+            state.insert_block(block.clone()).unwrap();
+        }
+
+        let unstable_blocks = state.get_current_chain().into_iter().cloned().collect();
+        (state, unstable_blocks)
+    }
+
+    fn load(bytes: &[u8], chunk_size: usize, hash: sha256::Hash) -> Result<State, SnapshotError> {
+        let mut loader = SnapshotLoader::new(3, Network::Regtest, false, hash);
+        for chunk in bytes.chunks(chunk_size) {
+            loader.push_chunk(chunk)?;
+        }
+        loader.finish()
+    }
+
+    #[test]
+    fn write_and_load() {
+        let (state, unstable_blocks) = state_with_blocks();
+        let mut bytes = vec![];
+        let hash = write(&state, &mut bytes).unwrap();
+
+        // The snapshot ends with its hash.
+        let checksum_start = bytes.len() - CHECKSUM_LEN;
+        assert_eq!(hash, sha256::Hash::hash(&bytes[..checksum_start]));
+        assert_eq!(&hash[..], &bytes[checksum_start..]);
+
+        for chunk_size in [1, 7, bytes.len()].iter() {
+            let mut loaded_state = load(&bytes, *chunk_size, hash).unwrap();
+            assert_eq!(loaded_state.anchor_hash(), state.anchor_hash());
+            assert_eq!(loaded_state.stable_height(), state.stable_height());
+            assert_eq!(loaded_state.num_utxos(), state.num_utxos());
+            assert_eq!(loaded_state.utxo_set_hash(), state.utxo_set_hash());
+
+            // The unstable blocks can be inserted on top of the snapshot.
+            for block in &unstable_blocks {
+                loaded_state.insert_block(block.clone()).unwrap();
+            }
+            assert_eq!(loaded_state.main_chain_height(), state.main_chain_height());
+        }
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let (state, _) = state_with_blocks();
+        let mut bytes = vec![];
+        let hash = write(&state, &mut bytes).unwrap();
+
+        assert_eq!(
+            load(&bytes, 100, sha256::Hash::hash(&[])).err(),
+            Some(SnapshotError::HashMismatch)
+        );
+        assert_eq!(
+            load(&bytes[..bytes.len() - 1], 100, hash).err(),
+            Some(SnapshotError::Incomplete)
+        );
+
+        let mut corrupted_bytes = bytes.clone();
+        *corrupted_bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            load(&corrupted_bytes, 100, hash).err(),
+            Some(SnapshotError::Malformed)
+        );
+
+        // A snapshot whose UTXOs don't match the hash of the UTXOs in its header.
+        let mut tampered_bytes = vec![];
+        let mut writer = StreamWriter::new(&mut tampered_bytes).unwrap();
+        writer
+            .write_message(&proto::StateHeader {
+                utxos_muhash: MuHash::new().to_bytes(),
+                ..state.header_to_proto()
+            })
+            .unwrap();
+        writer
+            .write_utxos(state.iter_utxos_proto(), UTXO_BATCH_SIZE)
+            .unwrap();
+        let tampered_hash = writer.finish().unwrap();
+        assert_eq!(
+            load(&tampered_bytes, 100, tampered_hash).err(),
+            Some(SnapshotError::UtxoSetHashMismatch)
+        );
+
+        let mut loader = SnapshotLoader::new(3, Network::Bitcoin, false, hash);
+        assert_eq!(
+            loader.push_chunk(&bytes),
+            Err(SnapshotError::NetworkMismatch)
+        );
+
+        // Data following the end of the snapshot.
+        let mut loader = SnapshotLoader::new(3, Network::Regtest, false, hash);
+        assert_eq!(
+            loader.push_chunk(&[&bytes[..], &bytes[..]].concat()),
+            Err(SnapshotError::Malformed)
+        );
+    }
+}
@@ -1,8 +1,20 @@
-use crate::{blockforest::BlockForest, proto, utxoset::UtxoSet};
-use bitcoin::hashes::Hash;
-use bitcoin::{Block, BlockHash, Network, OutPoint, TxOut, Txid};
+use crate::{
+    address, block,
+    blockforest::{BlockForest, ForkChoice},
+    muhash::MuHash,
+    proto, script_validation,
+    serialization::{invalid_data, StreamReader, StreamWriter, UTXO_BATCH_SIZE},
+    utxo_delta::UtxoDelta,
+    utxoset::{network_from_proto, InsertTxError, UtxoCursor, UtxoSet},
+    validation::{self, BlockValidationError},
+};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::{Block, BlockHash, BlockHeader, Network, OutPoint, Script, TxOut, Txid};
 use lazy_static::lazy_static;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::ops::Bound::{Excluded, Unbounded};
 use std::str::FromStr;
 
 lazy_static! {
@@ -15,6 +27,45 @@ lazy_static! {
 type Height = u32;
 type Satoshi = u64;
 
+// The number of stable block headers to keep for validating new blocks.
+// This is the length of a difficulty adjustment interval.
+const MAX_STABLE_HEADERS: usize = 2016;
+
+// The number of most recent blocks of the main chain whose transactions are used to compute
+// the fee percentiles.
+const FEE_PERCENTILES_WINDOW: usize = 10;
+
+/// Errors that can occur when inserting a block.
+#[derive(Debug, PartialEq)]
+pub enum InsertBlockError {
+    /// The block's predecessor is neither the anchor nor one of the unstable blocks.
+    PrevBlockNotFound(BlockHash),
+    /// The block didn't pass validation.
+    InvalidBlock(BlockValidationError),
+    /// A transaction in the block spends an outpoint that doesn't exist or was already spent.
+    MissingInput(OutPoint),
+    /// A transaction in the block creates an outpoint that already exists.
+    DuplicateOutpoint(OutPoint),
+    /// A transaction in the block has an input whose script doesn't satisfy the output
+    /// it spends. Only checked if script validation is enabled.
+    InvalidInputScript(OutPoint),
+}
+
+impl From<BlockValidationError> for InsertBlockError {
+    fn from(err: BlockValidationError) -> Self {
+        Self::InvalidBlock(err)
+    }
+}
+
+impl From<InsertTxError> for InsertBlockError {
+    fn from(err: InsertTxError) -> Self {
+        match err {
+            InsertTxError::MissingInput(outpoint) => Self::MissingInput(outpoint),
+            InsertTxError::DuplicateOutpoint(outpoint) => Self::DuplicateOutpoint(outpoint),
+        }
+    }
+}
+
 // A structure used to maintain the entire state.
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct State {
@@ -29,6 +80,22 @@ pub struct State {
 
     // Blocks inserted, but are not considered stable yet.
     unstable_blocks: BlockForest,
+
+    // The changes that each unstable block makes to the UTXOs, keyed by the block's hash.
+    unstable_utxo_deltas: HashMap<BlockHash, UtxoDelta>,
+
+    // The headers of the most recent stable blocks, oldest first.
+    stable_headers: VecDeque<BlockHeader>,
+
+    // The fee rates of the transactions of the most recent stable blocks, oldest first.
+    stable_fee_rates: VecDeque<Vec<u64>>,
+
+    // The height and hash of the block of every stable transaction, if transactions
+    // are indexed.
+    tx_index: Option<BTreeMap<Txid, (Height, BlockHash)>>,
+
+    // Whether the input scripts of new blocks are validated.
+    validate_scripts: bool,
 }
 
 impl State {
@@ -38,21 +105,80 @@ impl State {
     /// it is considered stable. Stable blocks are assumed to be final and are never
     /// removed.
     pub fn new(delta: u64, network: Network, genesis_block: Block) -> Self {
-        let mut state = Self {
-            height: 1,
-            latest_stable_block_hash: genesis_block.block_hash(),
-            utxos: UtxoSet::new(true, network),
-            unstable_blocks: BlockForest::new(delta),
-        };
+        Self::create(delta, network, genesis_block, false)
+    }
+
+    /// Create a new blockchain that also indexes the transactions of stable blocks, so
+    /// that they can be looked up with `get_transaction_location`.
+    pub fn new_with_tx_index(delta: u64, network: Network, genesis_block: Block) -> Self {
+        Self::create(delta, network, genesis_block, true)
+    }
+
+    fn create(delta: u64, network: Network, genesis_block: Block, index_txs: bool) -> Self {
+        let mut state = Self::from_checkpoint(
+            delta,
+            network,
+            1,
+            VecDeque::from(vec![genesis_block.header]),
+            index_txs,
+        );
 
         // Process the txs in the genesis block to include them in the UTXOs.
-        for tx in genesis_block.txdata {
-            state.utxos.insert_tx(&tx, 1);
+        for tx in &genesis_block.txdata {
+            state
+                .utxos
+                .insert_tx(tx, 1)
+                .expect("The genesis block must be valid");
         }
+        state.index_transactions(&genesis_block, 0);
 
         state
     }
 
+    /// Creates a blockchain whose latest stable block is the last of `stable_headers`, at
+    /// the given height, without any UTXOs. The headers must be the most recent stable
+    /// ones, oldest first, as they're needed to validate the following blocks.
+    pub(crate) fn from_checkpoint(
+        delta: u64,
+        network: Network,
+        height: Height,
+        stable_headers: VecDeque<BlockHeader>,
+        index_txs: bool,
+    ) -> Self {
+        Self {
+            height,
+            latest_stable_block_hash: stable_headers
+                .back()
+                .expect("A checkpoint must have at least one header")
+                .block_hash(),
+            utxos: UtxoSet::new(true, network),
+            unstable_blocks: BlockForest::new(delta),
+            unstable_utxo_deltas: HashMap::new(),
+            stable_headers,
+            stable_fee_rates: VecDeque::new(),
+            tx_index: if index_txs {
+                Some(BTreeMap::new())
+            } else {
+                None
+            },
+            validate_scripts: false,
+        }
+    }
+
+    /// Sets how the main chain is chosen among the unstable blocks, and how deep blocks
+    /// need to be to become stable.
+    pub fn set_fork_choice(&mut self, fork_choice: ForkChoice) {
+        self.unstable_blocks.set_fork_choice(fork_choice);
+    }
+
+    /// Sets whether the scripts of the inputs of new blocks are validated against the
+    /// outputs they spend. See `script_validation` for the supported scripts.
+    ///
+    /// Validation is disabled by default, as verifying signatures is expensive.
+    pub fn set_script_validation(&mut self, enabled: bool) {
+        self.validate_scripts = enabled;
+    }
+
     /// Returns the balance of a bitcoin address.
     pub fn get_balance(&self, address: &str, min_confirmations: u32) -> Satoshi {
         // NOTE: It is safe to sum up the balances here without the risk of overflow.
@@ -68,22 +194,79 @@ impl State {
 
     /// Returns the set of UTXOs for a given bitcoin address.
     /// Transactions with confirmations < `min_confirmations` are not considered.
+    ///
+    /// The UTXOs are those of the address's script, so an empty set is returned if the
+    /// address can't be parsed.
     pub fn get_utxos(
         &self,
         address: &str,
         min_confirmations: u32,
     ) -> HashSet<(OutPoint, TxOut, Height)> {
-        let mut address_utxos = self.utxos.get_utxos(address);
+        match address::parse(address) {
+            Ok(address) => self.get_utxos_by_script(&address.script_pubkey(), min_confirmations),
+            Err(_) => HashSet::new(),
+        }
+    }
 
-        // Apply unstable blocks to the UTXO set.
-        for (i, block) in self
-            .unstable_blocks
-            .get_current_chain(&self.latest_stable_block_hash)
+    /// Returns the set of UTXOs for a given script.
+    /// Transactions with confirmations < `min_confirmations` are not considered.
+    pub fn get_utxos_by_script(
+        &self,
+        script: &Script,
+        min_confirmations: u32,
+    ) -> HashSet<(OutPoint, TxOut, Height)> {
+        self.get_utxos_in_chain(script, min_confirmations, &self.current_chain_utxo_deltas())
+    }
+
+    /// Returns the sets of UTXOs for the given scripts, each with its own `min_confirmations`,
+    /// in the same order as the scripts, along with the height of the main chain.
+    ///
+    /// Unlike calling `get_utxos_by_script` for every script, the current chain is only
+    /// computed once.
+    pub fn get_utxos_by_scripts(
+        &self,
+        scripts: &[(Script, u32)],
+    ) -> (Height, Vec<HashSet<(OutPoint, TxOut, Height)>>) {
+        let chain_utxo_deltas = self.current_chain_utxo_deltas();
+        let main_chain_height = self.stable_height() + chain_utxo_deltas.len() as u32;
+        let utxos = scripts
             .iter()
-            .enumerate()
-        {
+            .map(|(script, min_confirmations)| {
+                self.get_utxos_in_chain(script, *min_confirmations, &chain_utxo_deltas)
+            })
+            .collect();
+        (main_chain_height, utxos)
+    }
+
+    // Returns the UTXO deltas of the unstable blocks of the current chain, oldest first.
+    fn current_chain_utxo_deltas(&self) -> Vec<&UtxoDelta> {
+        self.get_current_chain()
+            .iter()
+            .map(|block| &self.unstable_utxo_deltas[&block.block_hash()])
+            .collect()
+    }
+
+    // Returns the UTXOs of a script, given the UTXO deltas of the current chain.
+    fn get_utxos_in_chain(
+        &self,
+        script: &Script,
+        min_confirmations: u32,
+        chain_utxo_deltas: &[&UtxoDelta],
+    ) -> HashSet<(OutPoint, TxOut, Height)> {
+        let mut script_utxos: HashMap<OutPoint, (TxOut, Height)> = self
+            .utxos
+            .get_utxos(script)
+            .into_set()
+            .into_iter()
+            .map(|(outpoint, txout, height)| (outpoint, (txout, height)))
+            .collect();
+
+        let main_chain_height = self.stable_height() + chain_utxo_deltas.len() as u32;
+
+        // Apply the changes that unstable blocks make to the UTXOs of the script.
+        for (i, utxo_delta) in chain_utxo_deltas.iter().enumerate() {
             let block_height = self.stable_height() + (i as u32) + 1;
-            let confirmations = self.main_chain_height() - block_height + 1;
+            let confirmations = main_chain_height - block_height + 1;
 
             if confirmations < min_confirmations {
                 // The block has fewer confirmations than requested.
@@ -91,43 +274,363 @@ impl State {
                 break;
             }
 
-            for tx in &block.txdata {
-                address_utxos.insert_tx(tx, block_height);
-            }
+            utxo_delta.apply(script, block_height, &mut script_utxos);
         }
 
-        address_utxos
-            // Filter out UTXOs added in unstable blocks that are not for the given address.
-            .get_utxos(address)
-            .into_set()
+        script_utxos
             .into_iter()
             // Filter out UTXOs that are below the `min_confirmations` threshold.
-            .filter(|(_, _, height)| self.main_chain_height() - height + 1 >= min_confirmations)
+            .filter(|(_, (_, height))| main_chain_height - height + 1 >= min_confirmations)
+            .map(|(outpoint, (txout, height))| (outpoint, txout, height))
             .collect()
     }
 
+    /// Returns the fee rates at the percentiles 0 to 100 of the transactions in the most
+    /// recent blocks of the main chain, in satoshi per vbyte.
+    ///
+    /// The coinbase transactions are excluded. If there are no transactions, the result
+    /// is empty.
+    pub fn get_current_fee_percentiles(&self) -> Vec<u64> {
+        let unstable_fee_rates = self
+            .current_chain_utxo_deltas()
+            .into_iter()
+            .map(|utxo_delta| utxo_delta.fee_rates());
+
+        let mut fee_rates: Vec<u64> = self
+            .stable_fee_rates
+            .iter()
+            .map(|fee_rates| fee_rates.as_slice())
+            .chain(unstable_fee_rates)
+            .rev()
+            .take(FEE_PERCENTILES_WINDOW)
+            .flatten()
+            .copied()
+            .collect();
+
+        if fee_rates.is_empty() {
+            return vec![];
+        }
+
+        fee_rates.sort_unstable();
+        (0..=100)
+            .map(|percentile| fee_rates[(fee_rates.len() - 1) * percentile / 100])
+            .collect()
+    }
+
+    /// Returns the output of the given outpoint if it's unspent on the current main chain,
+    /// i.e. it's a stable UTXO or created by an unstable block, and isn't spent by an
+    /// unstable block.
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        let chain = self.get_current_chain();
+
+        let is_spent = chain
+            .iter()
+            .flat_map(|block| &block.txdata)
+            .flat_map(|tx| &tx.input)
+            .any(|input| input.previous_output == *outpoint);
+        if is_spent {
+            return None;
+        }
+
+        if let Some(txout) = self.utxos.get(outpoint) {
+            return Some(txout);
+        }
+
+        chain
+            .iter()
+            .flat_map(|block| &block.txdata)
+            .find(|tx| tx.txid() == outpoint.txid)
+            .and_then(|tx| tx.output.get(outpoint.vout as usize))
+            .cloned()
+    }
+
     /// Insert a block into the blockchain.
-    pub fn insert_block(&mut self, block: Block) {
+    ///
+    /// The block is validated before it's inserted, and is rejected if its predecessor
+    /// isn't known, if it doesn't follow the consensus rules of the network, or if its
+    /// transactions aren't consistent with the UTXOs of its ancestors.
+    ///
+    /// Returns the block that became stable as a result, if any.
+    pub fn insert_block(&mut self, block: Block) -> Result<Option<Block>, InsertBlockError> {
+        self.validate_block(&block)?;
+
         // The block is first inserted into the unstable blocks.
+        let utxo_delta = self.utxo_delta(&block);
+        self.unstable_utxo_deltas
+            .insert(block.block_hash(), utxo_delta);
         self.unstable_blocks.push(block);
 
         // Process a stable block, if any.
+        //
+        // NOTE: Every unstable block extends the anchor or another unstable block, and its
+        // transactions were checked against the UTXOs of its ancestors when it was inserted.
+        // A block that becomes stable is therefore applied in full, and a failure here is a
+        // bug rather than an invalid block.
         if let Some(new_stable_block) = self.unstable_blocks.pop(&self.latest_stable_block_hash) {
-            // Verify the block is extending the previous block.
             assert_eq!(
                 new_stable_block.header.prev_blockhash, self.latest_stable_block_hash,
-                "Invalid block hash at height: {}",
-                self.height
+                "A stable block must extend the anchor"
             );
 
+            // The stable blocks before this one are the genesis block and its successors, so
+            // `self.height` is the height of this block, with the genesis block at height 0.
+            for tx in &new_stable_block.txdata {
+                self.utxos
+                    .insert_tx(tx, self.height)
+                    .expect("The transactions of a stable block must have been checked");
+            }
+            self.index_transactions(&new_stable_block, self.height);
+
             self.latest_stable_block_hash = new_stable_block.block_hash();
 
-            for tx in &new_stable_block.txdata {
-                self.utxos.insert_tx(tx, self.height);
+            self.stable_headers.push_back(new_stable_block.header);
+            if self.stable_headers.len() > MAX_STABLE_HEADERS {
+                self.stable_headers.pop_front();
             }
 
             self.height += 1;
+
+            // Keep the fee rates of the stable block for the fee percentiles.
+            let fee_rates = self.unstable_utxo_deltas[&self.latest_stable_block_hash]
+                .fee_rates()
+                .to_vec();
+            self.stable_fee_rates.push_back(fee_rates);
+            if self.stable_fee_rates.len() > FEE_PERCENTILES_WINDOW {
+                self.stable_fee_rates.pop_front();
+            }
+
+            // Drop the deltas of the stable block and of the blocks that were discarded
+            // along with their forks.
+            let unstable_blocks = &self.unstable_blocks;
+            self.unstable_utxo_deltas
+                .retain(|block_hash, _| unstable_blocks.get_block(block_hash).is_some());
+
+            return Ok(Some(new_stable_block));
+        }
+
+        Ok(None)
+    }
+
+    // Computes the changes that an unstable block makes to the UTXOs of its ancestors.
+    // The deltas of the block's unstable ancestors must already be computed.
+    fn utxo_delta(&self, block: &Block) -> UtxoDelta {
+        UtxoDelta::new(block, |outpoint| {
+            self.get_spent_txout(block.header.prev_blockhash, outpoint)
+        })
+    }
+
+    // Returns the output of an outpoint that is either a stable UTXO or created by the
+    // block with the given hash or one of its unstable ancestors, whose deltas must
+    // already be computed.
+    fn get_spent_txout(&self, mut prev_blockhash: BlockHash, outpoint: &OutPoint) -> Option<TxOut> {
+        if let Some(txout) = self.utxos.get(outpoint) {
+            return Some(txout);
+        }
+
+        while let Some(delta) = self.unstable_utxo_deltas.get(&prev_blockhash) {
+            if let Some(txout) = delta.get_added(outpoint) {
+                return Some(txout.clone());
+            }
+            prev_blockhash = self
+                .unstable_blocks
+                .get_block(&prev_blockhash)?
+                .header
+                .prev_blockhash;
+        }
+
+        None
+    }
+
+    // Computes the UTXO deltas of all the unstable blocks, ancestors first.
+    fn compute_unstable_utxo_deltas(&mut self) {
+        let block_hashes: Vec<BlockHash> = self
+            .unstable_blocks
+            .get_blocks()
+            .iter()
+            .map(|block| block.block_hash())
+            .collect();
+
+        for block_hash in block_hashes {
+            // Collect the block and its ancestors whose deltas are missing, newest first.
+            let mut missing = vec![];
+            let mut next_hash = block_hash;
+            while !self.unstable_utxo_deltas.contains_key(&next_hash) {
+                match self.unstable_blocks.get_block(&next_hash) {
+                    Some(block) => {
+                        missing.push(next_hash);
+                        next_hash = block.header.prev_blockhash;
+                    }
+                    None => break,
+                }
+            }
+
+            for block_hash in missing.into_iter().rev() {
+                let block = self.unstable_blocks.get_block(&block_hash).unwrap();
+                let utxo_delta = self.utxo_delta(block);
+                self.unstable_utxo_deltas.insert(block_hash, utxo_delta);
+            }
+        }
+    }
+
+    // Adds the transactions of a stable block to the transaction index, if any.
+    fn index_transactions(&mut self, block: &Block, height: Height) {
+        if let Some(tx_index) = &mut self.tx_index {
+            let block_hash = block.block_hash();
+            for tx in &block.txdata {
+                tx_index.insert(tx.txid(), (height, block_hash));
+            }
+        }
+    }
+
+    /// Returns the height and the hash of the block on the current main chain that
+    /// contains the given transaction. Like in Bitcoin Core, the genesis block is at height 0.
+    ///
+    /// Transactions of stable blocks are only found if the transactions are indexed.
+    pub fn get_transaction_location(&self, txid: &Txid) -> Option<(Height, BlockHash)> {
+        // The first unstable block follows the `stable_height()` stable blocks, so that's
+        // its height.
+        for (i, block) in self.get_current_chain().iter().enumerate() {
+            if block.txdata.iter().any(|tx| tx.txid() == *txid) {
+                return Some((self.stable_height() + i as u32, block.block_hash()));
+            }
+        }
+
+        self.tx_index
+            .as_ref()
+            .and_then(|tx_index| tx_index.get(txid).copied())
+    }
+
+    // Validates a block against its ancestors.
+    fn validate_block(&self, block: &Block) -> Result<(), InsertBlockError> {
+        // Collect the block's unstable ancestors, starting with its parent.
+        let mut unstable_ancestors = vec![];
+        let mut prev_blockhash = block.header.prev_blockhash;
+        while prev_blockhash != self.latest_stable_block_hash {
+            match self.unstable_blocks.get_block(&prev_blockhash) {
+                Some(prev_block) => {
+                    unstable_ancestors.push(prev_block);
+                    prev_blockhash = prev_block.header.prev_blockhash;
+                }
+                None => {
+                    return Err(InsertBlockError::PrevBlockNotFound(
+                        block.header.prev_blockhash,
+                    ))
+                }
+            }
+        }
+
+        // NOTE: `self.height` counts the genesis block, so the height of the anchor is one less.
+        let prev_height = self.height - 1 + unstable_ancestors.len() as u32;
+        let ancestors = unstable_ancestors
+            .iter()
+            .map(|b| &b.header)
+            .chain(self.stable_headers.iter().rev());
+
+        validation::validate_block(self.utxos.network(), block, prev_height, ancestors)?;
+
+        self.check_transactions(block, &unstable_ancestors)?;
+
+        if self.validate_scripts {
+            self.check_scripts(block, prev_height + 1)?;
+        }
+
+        Ok(())
+    }
+
+    // Checks the scripts of the block's inputs against the outputs they spend. The block's
+    // transactions must already be checked, so that all the spent outputs exist.
+    fn check_scripts(&self, block: &Block, height: Height) -> Result<(), InsertBlockError> {
+        // The outputs created by the block's earlier transactions.
+        let mut created: HashMap<OutPoint, &TxOut> = HashMap::new();
+
+        for tx in &block.txdata {
+            if !tx.is_coin_base() {
+                let mut sighash_cache = SigHashCache::new(tx);
+                for (input_index, input) in tx.input.iter().enumerate() {
+                    let prevout = match created.get(&input.previous_output) {
+                        Some(txout) => (*txout).clone(),
+                        None => self
+                            .get_spent_txout(block.header.prev_blockhash, &input.previous_output)
+                            .ok_or(InsertBlockError::MissingInput(input.previous_output))?,
+                    };
+
+                    if !script_validation::verify_input(
+                        self.network(),
+                        height,
+                        tx,
+                        input_index,
+                        &prevout,
+                        &mut sighash_cache,
+                    ) {
+                        return Err(InsertBlockError::InvalidInputScript(input.previous_output));
+                    }
+                }
+            }
+
+            let txid = tx.txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                created.insert(OutPoint::new(txid, vout as u32), txout);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks that the transactions of a block only spend existing outputs, and don't create
+    // outputs that already exist, given the stable UTXOs and the block's unstable ancestors,
+    // which are ordered starting with the block's parent.
+    fn check_transactions(
+        &self,
+        block: &Block,
+        unstable_ancestors: &[&Block],
+    ) -> Result<(), InsertBlockError> {
+        let mut created: HashSet<OutPoint> = HashSet::new();
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+
+        let is_unspent =
+            |outpoint: &OutPoint, created: &HashSet<OutPoint>, spent: &HashSet<OutPoint>| {
+                (self.utxos.contains(outpoint) || created.contains(outpoint))
+                    && !spent.contains(outpoint)
+            };
+
+        // Apply the transactions of the ancestors, oldest first. These have already been checked.
+        for ancestor in unstable_ancestors.iter().rev() {
+            for tx in &ancestor.txdata {
+                if !tx.is_coin_base() {
+                    spent.extend(tx.input.iter().map(|input| input.previous_output));
+                }
+
+                let txid = tx.txid();
+                created.extend((0..tx.output.len()).map(|vout| OutPoint::new(txid, vout as u32)));
+            }
+        }
+
+        for tx in &block.txdata {
+            if !tx.is_coin_base() {
+                for input in &tx.input {
+                    if !is_unspent(&input.previous_output, &created, &spent)
+                        || !spent.insert(input.previous_output)
+                    {
+                        return Err(InsertBlockError::MissingInput(input.previous_output));
+                    }
+                }
+            }
+
+            let txid = tx.txid();
+            for vout in 0..tx.output.len() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                if is_unspent(&outpoint, &created, &spent) && !DUPLICATE_TX_IDS.contains(&txid) {
+                    return Err(InsertBlockError::DuplicateOutpoint(outpoint));
+                }
+                created.insert(outpoint);
+            }
         }
+
+        Ok(())
+    }
+
+    pub fn network(&self) -> Network {
+        self.utxos.network()
     }
 
     pub fn stable_height(&self) -> Height {
@@ -135,10 +638,39 @@ impl State {
     }
 
     pub fn main_chain_height(&self) -> Height {
+        self.get_current_chain().len() as u32 + self.height
+    }
+
+    /// Returns the number of unstable blocks, including those not on the main chain.
+    pub fn num_unstable_blocks(&self) -> usize {
+        self.unstable_blocks.num_blocks()
+    }
+
+    /// Returns the number of trees that the unstable blocks form.
+    pub fn num_unstable_trees(&self) -> usize {
+        self.unstable_blocks.num_trees()
+    }
+
+    /// Returns the number of stable UTXOs.
+    pub fn num_utxos(&self) -> usize {
+        self.utxos.num_utxos()
+    }
+
+    /// Returns the number of scripts in the index of the stable UTXOs.
+    pub fn num_indexed_scripts(&self) -> usize {
+        self.utxos.num_scripts()
+    }
+
+    /// Returns the hash of the stable UTXOs. See `UtxoSet::hash`.
+    pub fn utxo_set_hash(&self) -> sha256::Hash {
+        self.utxos.hash()
+    }
+
+    /// Returns the unstable blocks of the current main chain, starting with the block
+    /// that follows the anchor.
+    pub fn get_current_chain(&self) -> Vec<&Block> {
         self.unstable_blocks
             .get_current_chain(&self.latest_stable_block_hash)
-            .len() as u32
-            + self.height
     }
 
     pub fn get_unstable_blocks(&self) -> Vec<&Block> {
@@ -151,23 +683,413 @@ impl State {
             latest_stable_block_hash: self.latest_stable_block_hash.to_vec(),
             utxos: Some(self.utxos.to_proto()),
             unstable_blocks: Some(self.unstable_blocks.to_proto()),
+            stable_headers: self
+                .stable_headers
+                .iter()
+                .map(block::header_to_proto)
+                .collect(),
+            tx_index: self.tx_index.as_ref().map(|tx_index| proto::TxIndex {
+                entries: tx_index.iter().map(tx_index_entry_to_proto).collect(),
+            }),
+            validate_scripts: self.validate_scripts,
+            stable_fee_rates: self.stable_fee_rates_to_proto(),
         }
     }
 
     pub fn from_proto(proto_state: proto::State) -> Self {
-        Self {
+        let mut state = Self {
             height: proto_state.height,
             latest_stable_block_hash: BlockHash::from_hash(
                 Hash::from_slice(&proto_state.latest_stable_block_hash).unwrap(),
             ),
             utxos: UtxoSet::from_proto(proto_state.utxos.unwrap()),
             unstable_blocks: BlockForest::from_proto(proto_state.unstable_blocks.unwrap()),
+            unstable_utxo_deltas: HashMap::new(),
+            stable_headers: proto_state
+                .stable_headers
+                .iter()
+                .map(|header| block::header_from_proto(header).expect("Invalid block header"))
+                .collect(),
+            tx_index: proto_state.tx_index.map(|tx_index| {
+                tx_index
+                    .entries
+                    .iter()
+                    .map(tx_index_entry_from_proto)
+                    .collect()
+            }),
+            validate_scripts: proto_state.validate_scripts,
+            stable_fee_rates: proto_state
+                .stable_fee_rates
+                .into_iter()
+                .map(|fee_rates| fee_rates.fee_rates)
+                .collect(),
+        };
+        assert!(
+            state.has_anchor_header(),
+            "The stable headers must end with the header of the latest stable block"
+        );
+        state.compute_unstable_utxo_deltas();
+        state
+    }
+
+    /// Serializes the state into the given writer.
+    ///
+    /// Unlike `to_proto`, the state is written as a stream of messages (see `serialization`),
+    /// so it never needs to be held in memory as a single protobuf message. The stream starts
+    /// with a `StateHeader`, followed by the UTXOs in `UtxoBatch`es that end with an empty
+    /// batch, the unstable blocks, and the transaction index in `TxIndex` batches that end with
+    /// an empty batch, if transactions are indexed.
+    pub fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.serialize_in_batches(writer, UTXO_BATCH_SIZE)
+    }
+
+    /// Deserializes a state that was written with `serialize`, reading no more bytes than
+    /// were written.
+    pub fn deserialize<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut reader = StreamReader::new(reader)?;
+        let mut state_reader = StateReader::new(&mut reader)?;
+        state_reader.read_next(&mut reader, usize::MAX)?;
+
+        // The state is only returned if the checksum matches.
+        reader.finish()?;
+
+        state_reader.finish()
+    }
+
+    // Builds a deserialized state from its parts.
+    fn from_header(
+        header: proto::StateHeader,
+        utxos: UtxoSet,
+        unstable_blocks: BlockForest,
+        tx_index: Option<BTreeMap<Txid, (Height, BlockHash)>>,
+    ) -> io::Result<Self> {
+        let mut state = Self {
+            height: header.height,
+            latest_stable_block_hash: BlockHash::from_slice(&header.anchor)
+                .map_err(|_| invalid_data("Invalid anchor"))?,
+            utxos,
+            unstable_blocks,
+            unstable_utxo_deltas: HashMap::new(),
+            stable_headers: header
+                .stable_headers
+                .iter()
+                .map(block::header_from_proto)
+                .collect::<Option<_>>()
+                .ok_or_else(|| invalid_data("Invalid block header"))?,
+            tx_index,
+            validate_scripts: header.validate_scripts,
+            stable_fee_rates: header
+                .stable_fee_rates
+                .into_iter()
+                .map(|fee_rates| fee_rates.fee_rates)
+                .collect(),
+        };
+        if !state.has_anchor_header() {
+            return Err(invalid_data(
+                "The stable headers must end with the header of the latest stable block",
+            ));
+        }
+
+        // Now that all the UTXOs are read, the deltas of the unstable blocks can be computed.
+        state.compute_unstable_utxo_deltas();
+
+        Ok(state)
+    }
+
+    // Returns true if the stable headers end with the header of the latest stable block,
+    // which is needed to validate the blocks that follow it.
+    fn has_anchor_header(&self) -> bool {
+        self.stable_headers.back().map(BlockHeader::block_hash)
+            == Some(self.latest_stable_block_hash)
+    }
+
+    // Returns the header of the stable part of the state, which starts a serialized state
+    // or a snapshot.
+    pub(crate) fn header_to_proto(&self) -> proto::StateHeader {
+        let utxos = self.utxos.to_proto_without_utxos();
+        proto::StateHeader {
+            height: self.height,
+            anchor: self.latest_stable_block_hash.to_vec(),
+            network: utxos.network,
+            strict: utxos.strict,
+            utxos_muhash: utxos.muhash,
+            stable_headers: self
+                .stable_headers
+                .iter()
+                .map(block::header_to_proto)
+                .collect(),
+            index_txs: self.tx_index.is_some(),
+            validate_scripts: self.validate_scripts,
+            stable_fee_rates: self.stable_fee_rates_to_proto(),
         }
     }
 
+    fn stable_fee_rates_to_proto(&self) -> Vec<proto::FeeRates> {
+        self.stable_fee_rates
+            .iter()
+            .map(|fee_rates| proto::FeeRates {
+                fee_rates: fee_rates.clone(),
+            })
+            .collect()
+    }
+
+    fn serialize_in_batches<W: Write>(&self, writer: &mut W, batch_size: usize) -> io::Result<()> {
+        let mut writer = StreamWriter::new(writer)?;
+        StateWriter::new(self, &mut writer, batch_size)?.write_next(
+            self,
+            &mut writer,
+            usize::MAX,
+        )?;
+        writer.finish()?;
+        Ok(())
+    }
+
     pub fn anchor_hash(&self) -> BlockHash {
         self.latest_stable_block_hash
     }
+
+    // The following give snapshots access to the stable part of the state.
+
+    pub(crate) fn iter_utxos_proto(&self) -> impl Iterator<Item = proto::Utxo> + '_ {
+        self.utxos.iter_proto()
+    }
+
+    // Inserts a stable UTXO and adds it to the hash of the UTXOs.
+    pub(crate) fn insert_utxo_proto(&mut self, utxo: proto::Utxo) -> Result<(), InsertTxError> {
+        self.utxos.insert_proto_and_update_hash(utxo)
+    }
+
+    pub(crate) fn utxos_muhash(&self) -> &MuHash {
+        self.utxos.muhash()
+    }
+}
+
+// The parts of a serialized state that follow its header, in the order in which they're
+// written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StatePart {
+    Utxos,
+    UnstableBlocks,
+    TxIndex,
+    Done,
+}
+
+/// Writes a state to a stream a few batches at a time, so that a large state can be written
+/// over several messages. Only one batch is held in memory at a time.
+///
+/// The state must not change until it's written completely.
+pub struct StateWriter {
+    next: StatePart,
+    utxos: UtxoCursor,
+    // The last transaction of the index that was written, if any.
+    last_txid: Option<Txid>,
+    batch_size: usize,
+}
+
+impl StateWriter {
+    /// Starts writing the state by writing its header. UTXOs and entries of the transaction
+    /// index are written in batches of the given size.
+    pub fn new<W: Write>(
+        state: &State,
+        writer: &mut StreamWriter<W>,
+        batch_size: usize,
+    ) -> io::Result<Self> {
+        writer.write_message(&state.header_to_proto())?;
+        Ok(Self {
+            next: StatePart::Utxos,
+            utxos: UtxoCursor::default(),
+            last_txid: None,
+            batch_size,
+        })
+    }
+
+    /// Writes up to `max_batches` more batches, along with the messages between them.
+    /// Returns true once the state is written completely.
+    pub fn write_next<W: Write>(
+        &mut self,
+        state: &State,
+        writer: &mut StreamWriter<W>,
+        max_batches: usize,
+    ) -> io::Result<bool> {
+        let mut num_batches = 0;
+        loop {
+            match self.next {
+                StatePart::Utxos => {
+                    if num_batches == max_batches {
+                        return Ok(false);
+                    }
+
+                    // An empty batch marks the end of the UTXOs.
+                    let utxos = state
+                        .utxos
+                        .next_utxos_proto(&mut self.utxos, self.batch_size);
+                    if utxos.is_empty() {
+                        self.next = StatePart::UnstableBlocks;
+                    }
+                    writer.write_message(&proto::UtxoBatch { utxos })?;
+                    num_batches += 1;
+                }
+                StatePart::UnstableBlocks => {
+                    writer.write_message(&state.unstable_blocks.to_proto())?;
+                    self.next = if state.tx_index.is_some() {
+                        StatePart::TxIndex
+                    } else {
+                        StatePart::Done
+                    };
+                }
+                StatePart::TxIndex => {
+                    if num_batches == max_batches {
+                        return Ok(false);
+                    }
+
+                    // An empty batch marks the end of the transaction index.
+                    let tx_index = state.tx_index.as_ref().unwrap();
+                    let entries: Vec<_> = match &self.last_txid {
+                        Some(last_txid) => tx_index.range((Excluded(*last_txid), Unbounded)),
+                        None => tx_index.range(..),
+                    }
+                    .take(self.batch_size)
+                    .collect();
+                    match entries.last() {
+                        Some((last_txid, _)) => self.last_txid = Some(**last_txid),
+                        None => self.next = StatePart::Done,
+                    }
+                    writer.write_message(&proto::TxIndex {
+                        entries: entries.into_iter().map(tx_index_entry_to_proto).collect(),
+                    })?;
+                    num_batches += 1;
+                }
+                StatePart::Done => return Ok(true),
+            }
+        }
+    }
+}
+
+/// Reads a state that was written with a `StateWriter` a few batches at a time, so that a
+/// large state can be read over several messages.
+pub struct StateReader {
+    next: StatePart,
+    header: proto::StateHeader,
+    utxos: UtxoSet,
+    unstable_blocks: Option<BlockForest>,
+    tx_index: Option<BTreeMap<Txid, (Height, BlockHash)>>,
+}
+
+impl StateReader {
+    /// Starts reading a state by reading its header.
+    pub fn new<R: Read>(reader: &mut StreamReader<R>) -> io::Result<Self> {
+        let header: proto::StateHeader = reader.read_message()?;
+        let network =
+            network_from_proto(header.network).ok_or_else(|| invalid_data("Invalid network"))?;
+        Ok(Self {
+            next: StatePart::Utxos,
+            utxos: UtxoSet::new(header.strict, network),
+            unstable_blocks: None,
+            tx_index: if header.index_txs {
+                Some(BTreeMap::new())
+            } else {
+                None
+            },
+            header,
+        })
+    }
+
+    /// Reads up to `max_batches` more batches, along with the messages between them.
+    /// Returns true once the state is read completely.
+    pub fn read_next<R: Read>(
+        &mut self,
+        reader: &mut StreamReader<R>,
+        max_batches: usize,
+    ) -> io::Result<bool> {
+        let mut num_batches = 0;
+        loop {
+            match self.next {
+                StatePart::Utxos => {
+                    if num_batches == max_batches {
+                        return Ok(false);
+                    }
+
+                    let batch: proto::UtxoBatch = reader.read_message()?;
+                    num_batches += 1;
+                    if batch.utxos.is_empty() {
+                        self.next = StatePart::UnstableBlocks;
+                    }
+                    for utxo in batch.utxos {
+                        self.utxos
+                            .insert_proto(utxo)
+                            .map_err(|err| invalid_data(format!("{:?}", err)))?;
+                    }
+                }
+                StatePart::UnstableBlocks => {
+                    self.unstable_blocks = Some(BlockForest::from_proto(reader.read_message()?));
+                    self.next = if self.tx_index.is_some() {
+                        StatePart::TxIndex
+                    } else {
+                        StatePart::Done
+                    };
+                }
+                StatePart::TxIndex => {
+                    if num_batches == max_batches {
+                        return Ok(false);
+                    }
+
+                    let batch: proto::TxIndex = reader.read_message()?;
+                    num_batches += 1;
+                    if batch.entries.is_empty() {
+                        self.next = StatePart::Done;
+                    }
+                    if let Some(tx_index) = &mut self.tx_index {
+                        tx_index.extend(batch.entries.iter().map(tx_index_entry_from_proto));
+                    }
+                }
+                StatePart::Done => return Ok(true),
+            }
+        }
+    }
+
+    /// Returns the state once `read_next` returned true. The state must only be used once
+    /// the checksum of the stream is verified.
+    pub fn finish(self) -> io::Result<State> {
+        assert_eq!(
+            self.next,
+            StatePart::Done,
+            "The state must be read completely"
+        );
+
+        // States that were written before the hash of the UTXOs was introduced don't have one.
+        let mut utxos = self.utxos;
+        match MuHash::from_bytes(&self.header.utxos_muhash) {
+            Some(muhash) => utxos.set_muhash(muhash),
+            None => utxos.recompute_hash(),
+        }
+
+        State::from_header(
+            self.header,
+            utxos,
+            self.unstable_blocks.unwrap(),
+            self.tx_index,
+        )
+    }
+}
+
+fn tx_index_entry_to_proto(
+    (txid, (height, block_hash)): (&Txid, &(Height, BlockHash)),
+) -> proto::TxIndexEntry {
+    proto::TxIndexEntry {
+        txid: txid.to_vec(),
+        height: *height,
+        block_hash: block_hash.to_vec(),
+    }
+}
+
+fn tx_index_entry_from_proto(entry: &proto::TxIndexEntry) -> (Txid, (Height, BlockHash)) {
+    (
+        Txid::from_hash(Hash::from_slice(&entry.txid).unwrap()),
+        (
+            entry.height,
+            BlockHash::from_hash(Hash::from_slice(&entry.block_hash).unwrap()),
+        ),
+    )
 }
 
 #[cfg(test)]
@@ -177,7 +1099,7 @@ mod test {
     use bitcoin::blockdata::constants::genesis_block;
     use bitcoin::secp256k1::rand::rngs::OsRng;
     use bitcoin::secp256k1::Secp256k1;
-    use bitcoin::{consensus::Decodable, Address, BlockHash, Network, PublicKey};
+    use bitcoin::{consensus::Decodable, Address, BlockHash, Network, PublicKey, Transaction};
     use byteorder::{LittleEndian, ReadBytesExt};
     use maplit::hashset;
     use std::fs::File;
@@ -227,7 +1149,7 @@ mod test {
         println!("Built chain with length: {}", chain.len());
 
         for block in chain.into_iter() {
-            state.insert_block(block);
+            state.insert_block(block).unwrap();
         }
     }
 
@@ -243,7 +1165,7 @@ mod test {
             block = BlockBuilder::with_prev_header(block.header)
                 .with_transaction(TransactionBuilder::coinbase().build())
                 .build();
-            state.insert_block(block.clone());
+            state.insert_block(block.clone()).unwrap();
         }
 
         let state_proto = state.to_proto();
@@ -253,6 +1175,363 @@ mod test {
         assert_eq!(new_state, state);
     }
 
+    #[test]
+    fn serialize_deserialize() {
+        let mut block = BlockBuilder::genesis()
+            .with_transaction(TransactionBuilder::coinbase().build())
+            .build();
+        let mut state = State::new(2, Network::Bitcoin, block.clone());
+
+        for _ in 0..100 {
+            block = BlockBuilder::with_prev_header(block.header)
+                .with_transaction(TransactionBuilder::coinbase().build())
+                .build();
+            state.insert_block(block.clone()).unwrap();
+        }
+
+        // Use a batch size that doesn't divide the number of UTXOs to exercise partial batches.
+        for batch_size in [1, 7, UTXO_BATCH_SIZE].iter() {
+            let mut bytes = vec![];
+            state.serialize_in_batches(&mut bytes, *batch_size).unwrap();
+
+            let new_state = State::deserialize(&mut bytes.as_slice()).unwrap();
+            assert_eq!(new_state, state);
+        }
+    }
+
+    #[test]
+    fn serialize_deserialize_with_tx_index() {
+        let mut block = BlockBuilder::genesis().build();
+        let mut state = State::new_with_tx_index(2, Network::Bitcoin, block.clone());
+
+        for _ in 0..20 {
+            block = BlockBuilder::with_prev_header(block.header).build();
+            state.insert_block(block.clone()).unwrap();
+        }
+
+        for batch_size in [1, 7, UTXO_BATCH_SIZE].iter() {
+            let mut bytes = vec![];
+            state.serialize_in_batches(&mut bytes, *batch_size).unwrap();
+
+            let new_state = State::deserialize(&mut bytes.as_slice()).unwrap();
+            assert_eq!(new_state, state);
+        }
+
+        assert_eq!(State::from_proto(state.to_proto()), state);
+    }
+
+    #[test]
+    fn serialize_deserialize_in_steps() {
+        let mut block = BlockBuilder::genesis().build();
+        let mut state = State::new_with_tx_index(2, Network::Bitcoin, block.clone());
+        for _ in 0..20 {
+            block = BlockBuilder::with_prev_header(block.header).build();
+            state.insert_block(block.clone()).unwrap();
+        }
+
+        // The state is written and read a few batches at a time, suspending the stream in
+        // between as if every step were a message of its own.
+        let (batch_size, max_batches) = (3, 2);
+        let mut bytes = vec![];
+        let mut writer = StreamWriter::new(&mut bytes).unwrap();
+        let mut state_writer = StateWriter::new(&state, &mut writer, batch_size).unwrap();
+        let mut engine = writer.suspend();
+        let mut num_steps = 1;
+        loop {
+            let mut writer = StreamWriter::resume(&mut bytes, engine);
+            let done = state_writer
+                .write_next(&state, &mut writer, max_batches)
+                .unwrap();
+            if done {
+                writer.finish().unwrap();
+                break;
+            }
+            engine = writer.suspend();
+            num_steps += 1;
+        }
+        assert!(num_steps > 2);
+
+        let mut reader_bytes = bytes.as_slice();
+        let mut reader = StreamReader::new(&mut reader_bytes).unwrap();
+        let mut state_reader = StateReader::new(&mut reader).unwrap();
+        let mut engine = reader.suspend();
+        loop {
+            let mut reader = StreamReader::resume(&mut reader_bytes, engine);
+            let done = state_reader.read_next(&mut reader, max_batches).unwrap();
+            if done {
+                reader.finish().unwrap();
+                break;
+            }
+            engine = reader.suspend();
+        }
+        assert_eq!(state_reader.finish().unwrap(), state);
+
+        // The stream is the same as if the state were written at once.
+        let mut other_bytes = vec![];
+        state
+            .serialize_in_batches(&mut other_bytes, batch_size)
+            .unwrap();
+        assert_eq!(other_bytes, bytes);
+    }
+
+    #[test]
+    fn deserialize_invalid_state() {
+        let mut block = BlockBuilder::genesis().build();
+        let mut state = State::new(2, Network::Bitcoin, block.clone());
+        for _ in 0..5 {
+            block = BlockBuilder::with_prev_header(block.header).build();
+            state.insert_block(block.clone()).unwrap();
+        }
+
+        let mut bytes = vec![];
+        state.serialize(&mut bytes).unwrap();
+
+        // A corrupted state is rejected.
+        let mut corrupted_bytes = bytes.clone();
+        *corrupted_bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            State::deserialize(&mut corrupted_bytes.as_slice())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Other versions of the format are rejected.
+        let mut other_version_bytes = bytes.clone();
+        other_version_bytes[0] += 1;
+        assert_eq!(
+            State::deserialize(&mut other_version_bytes.as_slice())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // A truncated state is rejected.
+        assert_eq!(
+            State::deserialize(&mut &bytes[..bytes.len() - 1])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // A state without the header of its latest stable block is rejected.
+        let mut bytes_without_headers = vec![];
+        let mut writer = StreamWriter::new(&mut bytes_without_headers).unwrap();
+        writer
+            .write_message(&proto::StateHeader {
+                stable_headers: vec![],
+                ..state.header_to_proto()
+            })
+            .unwrap();
+        writer
+            .write_utxos(state.utxos.iter_proto(), UTXO_BATCH_SIZE)
+            .unwrap();
+        writer
+            .write_message(&state.unstable_blocks.to_proto())
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            State::deserialize(&mut bytes_without_headers.as_slice())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Data that follows the state isn't read.
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let mut reader = bytes.as_slice();
+        assert_eq!(State::deserialize(&mut reader).unwrap(), state);
+        assert_eq!(reader, &[1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "The stable headers must end with the header of the latest stable block"
+    )]
+    fn from_proto_without_stable_headers() {
+        let state = State::new(2, Network::Regtest, BlockBuilder::genesis().build());
+        State::from_proto(proto::State {
+            stable_headers: vec![],
+            ..state.to_proto()
+        });
+    }
+
+    #[test]
+    fn get_transaction_location() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+        let unknown_txid = BlockBuilder::genesis().build().txdata[0].txid();
+
+        let mut state = State::new_with_tx_index(1, Network::Regtest, block_0.clone());
+        let mut state_without_index = State::new(1, Network::Regtest, block_0.clone());
+        for block in [block_1.clone(), block_2.clone()].iter() {
+            state.insert_block(block.clone()).unwrap();
+            state_without_index.insert_block(block.clone()).unwrap();
+        }
+
+        // Block 1 is stable, block 2 isn't.
+        assert_eq!(state.stable_height(), 2);
+
+        // The genesis block is at height 0, whether the blocks are stable or not.
+        for (block, height) in [(&block_0, 0), (&block_1, 1), (&block_2, 2)].iter() {
+            assert_eq!(
+                state.get_transaction_location(&block.txdata[0].txid()),
+                Some((*height, block.block_hash()))
+            );
+        }
+        assert_eq!(state.get_transaction_location(&unknown_txid), None);
+
+        // The height of a block doesn't change when it becomes stable.
+        let block_3 = BlockBuilder::with_prev_header(block_2.header).build();
+        state.insert_block(block_3).unwrap();
+        assert_eq!(
+            state.get_transaction_location(&block_2.txdata[0].txid()),
+            Some((2, block_2.block_hash()))
+        );
+
+        // Without an index, only the transactions of unstable blocks are found.
+        assert_eq!(
+            state_without_index.get_transaction_location(&block_1.txdata[0].txid()),
+            None
+        );
+        assert_eq!(
+            state_without_index.get_transaction_location(&block_2.txdata[0].txid()),
+            Some((2, block_2.block_hash()))
+        );
+    }
+
+    #[test]
+    fn insert_block_with_unknown_predecessor() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+
+        let mut state = State::new(2, Network::Regtest, block_0);
+
+        // Block 2 is rejected because block 1 hasn't been inserted.
+        assert_eq!(
+            state.insert_block(block_2),
+            Err(InsertBlockError::PrevBlockNotFound(block_1.block_hash()))
+        );
+        assert_eq!(state.get_unstable_blocks(), Vec::<&Block>::new());
+    }
+
+    #[test]
+    fn insert_invalid_block() {
+        let block_0 = BlockBuilder::genesis().build();
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header).build();
+
+        // Find a nonce that doesn't satisfy the target.
+        while block_1
+            .header
+            .validate_pow(&block_1.header.target())
+            .is_ok()
+        {
+            block_1.header.nonce += 1;
+        }
+
+        let mut state = State::new(2, Network::Regtest, block_0);
+
+        assert_eq!(
+            state.insert_block(block_1),
+            Err(InsertBlockError::InvalidBlock(
+                BlockValidationError::InvalidProofOfWork
+            ))
+        );
+        assert_eq!(state.get_unstable_blocks(), Vec::<&Block>::new());
+    }
+
+    #[test]
+    fn insert_block_spending_missing_input() {
+        let block_0 = BlockBuilder::genesis().build();
+        let mut state = State::new(2, Network::Regtest, block_0.clone());
+
+        // A transaction spending an output that doesn't exist.
+        let tx = TransactionBuilder::with_input(OutPoint::new(
+            TransactionBuilder::coinbase().build().txid(),
+            0,
+        ))
+        .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(tx.clone())
+            .build();
+
+        assert_eq!(
+            state.insert_block(block_1),
+            Err(InsertBlockError::MissingInput(tx.input[0].previous_output))
+        );
+        assert_eq!(state.get_unstable_blocks(), Vec::<&Block>::new());
+    }
+
+    #[test]
+    fn insert_block_with_invalid_input_script() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let address = Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Regtest,
+        );
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+
+        // A transaction spending the output without a signature.
+        let tx = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0)).build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(tx.clone())
+            .build();
+
+        // The block is only rejected if script validation is enabled.
+        let mut state = State::new(2, Network::Regtest, block_0.clone());
+        state.set_script_validation(true);
+        assert_eq!(
+            state.insert_block(block_1.clone()),
+            Err(InsertBlockError::InvalidInputScript(
+                tx.input[0].previous_output
+            ))
+        );
+        assert_eq!(state.get_unstable_blocks(), Vec::<&Block>::new());
+
+        let mut state = State::new(2, Network::Regtest, block_0);
+        state.insert_block(block_1.clone()).unwrap();
+        assert_eq!(state.get_unstable_blocks(), vec![&block_1]);
+    }
+
+    #[test]
+    fn insert_block_double_spending_unstable_output() {
+        let coinbase_tx = TransactionBuilder::coinbase().build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let mut state = State::new(2, Network::Regtest, block_0.clone());
+
+        // Block 1 spends the output of the coinbase transaction.
+        let tx_1 = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0)).build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(tx_1)
+            .build();
+        state.insert_block(block_1.clone()).unwrap();
+
+        // Block 2 spends it again and is rejected.
+        let tx_2 = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0)).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header)
+            .with_transaction(tx_2)
+            .build();
+        assert_eq!(
+            state.insert_block(block_2),
+            Err(InsertBlockError::MissingInput(OutPoint::new(
+                coinbase_tx.txid(),
+                0
+            )))
+        );
+        assert_eq!(state.get_unstable_blocks(), vec![&block_1]);
+    }
+
     #[test]
     fn utxos_forks() {
         let secp = Secp256k1::new();
@@ -312,7 +1591,7 @@ mod test {
             .with_transaction(tx.clone())
             .build();
 
-        state.insert_block(block_1);
+        state.insert_block(block_1).unwrap();
 
         // address 2 should now have the UTXO while address 1 has no UTXOs.
         assert_eq!(
@@ -339,7 +1618,7 @@ mod test {
         let block_1_prime = BlockBuilder::with_prev_header(block_0.header)
             .with_transaction(tx.clone())
             .build();
-        state.insert_block(block_1_prime.clone());
+        state.insert_block(block_1_prime.clone()).unwrap();
 
         // Because block 1 and block 1' contest with each other, neither of them are included
         // in the UTXOs. Only the UTXOs of block 0 are returned.
@@ -356,7 +1635,7 @@ mod test {
         let block_2_prime = BlockBuilder::with_prev_header(block_1_prime.header)
             .with_transaction(tx.clone())
             .build();
-        state.insert_block(block_2_prime);
+        state.insert_block(block_2_prime).unwrap();
 
         // Address 1 has no UTXOs since they were spent on the current chain.
         assert_eq!(state.get_utxos(&address_1.to_string(), 0), hashset! {});
@@ -381,6 +1660,206 @@ mod test {
         );
     }
 
+    #[test]
+    fn fee_percentiles() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let address = Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Regtest,
+        );
+
+        // Five outputs in the genesis block, and a block spending each of them with a
+        // fee rate of 10, 20, ..., 50 satoshi per vbyte.
+        let coinbase_txs: Vec<Transaction> = (0..5)
+            .map(|i| {
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 100_000 + i)
+                    .build()
+            })
+            .collect();
+        let mut block_0 = BlockBuilder::genesis();
+        for tx in &coinbase_txs {
+            block_0 = block_0.with_transaction(tx.clone());
+        }
+        let block_0 = block_0.build();
+
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header);
+        for (i, coinbase_tx) in coinbase_txs.iter().enumerate() {
+            let outpoint = OutPoint::new(coinbase_tx.txid(), 0);
+            let tx = TransactionBuilder::with_input(outpoint)
+                .with_output(&address, 0)
+                .build();
+            let vsize = (tx.get_weight() as u64 + 3) / 4;
+            let fee = (i as u64 + 1) * 10 * vsize;
+            block_1 = block_1.with_transaction(
+                TransactionBuilder::with_input(outpoint)
+                    .with_output(&address, coinbase_tx.output[0].value - fee)
+                    .build(),
+            );
+        }
+        let block_1 = block_1.build();
+
+        let mut state = State::new(2, Network::Regtest, block_0);
+        assert_eq!(state.get_current_fee_percentiles(), Vec::<u64>::new());
+
+        state.insert_block(block_1).unwrap();
+        let percentiles = state.get_current_fee_percentiles();
+        assert_eq!(percentiles.len(), 101);
+        assert_eq!(percentiles[0], 10);
+        assert_eq!(percentiles[25], 20);
+        assert_eq!(percentiles[50], 30);
+        assert_eq!(percentiles[100], 50);
+    }
+
+    #[test]
+    fn utxos_spent_within_unstable_blocks() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+
+        let address_1 = Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Bitcoin,
+        );
+        let address_2 = Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Bitcoin,
+        );
+        let address_3 = Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Bitcoin,
+        );
+
+        // Create a genesis block where 1000 satoshis are given to address 1.
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let mut state = State::new(2, Network::Bitcoin, block_0.clone());
+
+        // Block 1 gives the 1000 satoshis to address 2, which gives them to address 3
+        // within the same block.
+        let tx_1 = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address_2, 1000)
+            .build();
+        let tx_2 = TransactionBuilder::with_input(OutPoint::new(tx_1.txid(), 0))
+            .with_output(&address_3, 1000)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header)
+            .with_transaction(tx_1)
+            .with_transaction(tx_2.clone())
+            .build();
+        state.insert_block(block_1.clone()).unwrap();
+
+        let tx_2_utxos = hashset! {
+            (
+                OutPoint::new(tx_2.txid(), 0),
+                TxOut {
+                    value: 1000,
+                    script_pubkey: address_3.script_pubkey(),
+                },
+                2
+            )
+        };
+        assert_eq!(state.get_utxos(&address_1.to_string(), 0), hashset! {});
+        assert_eq!(state.get_utxos(&address_2.to_string(), 0), hashset! {});
+        assert_eq!(state.get_utxos(&address_3.to_string(), 0), tx_2_utxos);
+
+        // Block 2 gives the satoshis back to address 1.
+        let tx_3 = TransactionBuilder::with_input(OutPoint::new(tx_2.txid(), 0))
+            .with_output(&address_1, 1000)
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header)
+            .with_transaction(tx_3.clone())
+            .build();
+        state.insert_block(block_2).unwrap();
+
+        let tx_3_utxos = hashset! {
+            (
+                OutPoint::new(tx_3.txid(), 0),
+                TxOut {
+                    value: 1000,
+                    script_pubkey: address_1.script_pubkey(),
+                },
+                3
+            )
+        };
+        assert_eq!(state.get_utxos(&address_1.to_string(), 0), tx_3_utxos);
+        assert_eq!(state.get_utxos(&address_3.to_string(), 0), hashset! {});
+
+        // Block 2 only has a single confirmation.
+        assert_eq!(state.get_utxos(&address_1.to_string(), 2), hashset! {});
+        assert_eq!(state.get_utxos(&address_3.to_string(), 2), tx_2_utxos);
+
+        // The deltas of the unstable blocks are recomputed when the state is restored.
+        let mut bytes = vec![];
+        state.serialize(&mut bytes).unwrap();
+        let new_state = State::deserialize(&mut bytes.as_slice()).unwrap();
+        assert_eq!(new_state, state);
+        assert_eq!(new_state.get_utxos(&address_1.to_string(), 0), tx_3_utxos);
+
+        assert_eq!(State::from_proto(state.to_proto()), state);
+    }
+
+    #[test]
+    fn get_utxos_by_script_of_non_standard_outputs() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let public_key = PublicKey::new(secp.generate_keypair(&mut rng).1);
+
+        // A coinbase transaction paying to a P2PK script, which has no address.
+        let p2pk_script = Script::new_p2pk(&public_key);
+        let coinbase_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: p2pk_script.clone(),
+            }],
+        };
+
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        let state = State::new(2, Network::Bitcoin, block_0);
+
+        assert_eq!(
+            state.get_utxos_by_script(&p2pk_script, 0),
+            hashset! {
+                (
+                    OutPoint::new(coinbase_tx.txid(), 0),
+                    TxOut {
+                        value: 1000,
+                        script_pubkey: p2pk_script.clone(),
+                    },
+                    1
+                )
+            }
+        );
+    }
+
+    #[test]
+    fn utxo_set_hash() {
+        let mut block = genesis_block(Network::Regtest);
+        let mut state = State::new(1, Network::Regtest, block.clone());
+
+        // The outputs of the genesis block aren't part of the hash.
+        let empty_hash = UtxoSet::new(true, Network::Regtest).hash();
+        assert_eq!(state.utxo_set_hash(), empty_hash);
+
+        // The hash only changes once a block becomes stable.
+        block = BlockBuilder::with_prev_header(block.header).build();
+        state.insert_block(block.clone()).unwrap();
+        assert_eq!(state.utxo_set_hash(), empty_hash);
+
+        block = BlockBuilder::with_prev_header(block.header).build();
+        state.insert_block(block).unwrap();
+        assert_ne!(state.utxo_set_hash(), empty_hash);
+    }
+
     #[test]
     fn process_100k_blocks() {
         let mut state = State::new(0, Network::Bitcoin, genesis_block(Network::Bitcoin));
@@ -529,7 +2008,7 @@ mod test {
                 .build();
 
             let mut state = State::new(2, *network, block_0);
-            state.insert_block(block_1);
+            state.insert_block(block_1).unwrap();
 
             // Address 1 should have no UTXOs at zero confirmations.
             assert_eq!(
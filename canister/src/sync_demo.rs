@@ -1,11 +1,15 @@
-use bitcoin::{blockdata::constants::genesis_block, Network, OutPoint, TxOut};
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    hashes::{hex::FromHex, sha256, Hash},
+    Network, OutPoint, TxOut,
+};
 use btc::{
     proto::{btc_adapter_client::BtcAdapterClient, GetSuccessorsRequest},
+    snapshot::{self, SnapshotLoader},
     store::State,
 };
-use prost::Message;
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::sync::{Arc, RwLock};
 use std::time::SystemTime;
 use tonic::Request;
@@ -27,20 +31,35 @@ async fn main() {
         genesis_block(Network::Bitcoin),
     )));
 
-    if args.len() > 1 {
-        // A state file was specified in the first argument. Load that state.
-        println!("Reading state from disk...");
+    if args.len() > 3 && args[1] == "--snapshot" {
+        // A snapshot file and its hash were specified. Bootstrap the state from the snapshot.
+        println!("Loading snapshot...");
         let now = SystemTime::now();
-        let state_from_disk = std::fs::read(&args[1]).unwrap();
+        let snapshot_hash = sha256::Hash::from_hex(&args[3]).expect("Invalid snapshot hash");
+        let mut loader = SnapshotLoader::new(DELTA, Network::Bitcoin, false, snapshot_hash);
+
+        // Feed the snapshot to the loader in chunks, as a canister would receive it.
+        let mut reader = BufReader::new(std::fs::File::open(&args[2]).unwrap());
+        loop {
+            let chunk = reader.fill_buf().unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            loader.push_chunk(chunk).unwrap();
+            let len = chunk.len();
+            reader.consume(len);
+        }
+        state = Arc::new(RwLock::new(loader.finish().unwrap()));
         println!(
             "Done. Duration: {} seconds",
             now.elapsed().unwrap().as_secs()
         );
-
-        println!("Deserializing State...");
+    } else if args.len() > 1 {
+        // A state file was specified in the first argument. Load that state.
+        println!("Reading state from disk...");
         let now = SystemTime::now();
-        let decoded_state = btc::proto::State::decode(&*state_from_disk).unwrap();
-        state = Arc::new(RwLock::new(State::from_proto(decoded_state)));
+        let mut reader = BufReader::new(std::fs::File::open(&args[1]).unwrap());
+        state = Arc::new(RwLock::new(State::deserialize(&mut reader).unwrap()));
         println!(
             "Done. Duration: {} seconds",
             now.elapsed().unwrap().as_secs()
@@ -83,9 +102,18 @@ async fn main() {
                     let response = tonic_response.into_inner();
 
                     for block_proto in response.blocks {
-                        let block = btc::block::from_proto(&block_proto);
-                        println!("Processing block with hash: {}", block.block_hash());
-                        state_write.insert_block(block);
+                        let block = match btc::block::from_proto(&block_proto) {
+                            Some(block) => block,
+                            None => {
+                                println!("Skipping malformed block");
+                                continue;
+                            }
+                        };
+                        let block_hash = block.block_hash();
+                        println!("Processing block with hash: {}", block_hash);
+                        if let Err(err) = state_write.insert_block(block) {
+                            println!("Rejected block {}: {:?}", block_hash, err);
+                        }
                         println!("New mainchain height: {}", state_write.main_chain_height());
                     }
                 }
@@ -129,16 +157,43 @@ async fn main() {
             let whole = balance / 100_000_000;
             println!("{}.{:0>8}", whole, decimals);
         }
+        // Command for getting the hash of the stable UTXOs, which can be compared with the
+        // output of `bitcoin-cli gettxoutsetinfo muhash` at the given height.
+        // e.g. "muhash"
+        else if input.contains("muhash") {
+            let state_read = state.read().expect("Cannot get read-only access to state");
+            // Core displays the hash in reverse byte order, and counts heights from 0.
+            let hash: String = state_read
+                .utxo_set_hash()
+                .into_inner()
+                .iter()
+                .rev()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            println!("Height: {}", state_read.stable_height() - 1);
+            println!("MuHash: {}", hash);
+        }
         // Command for saving the state.
         // e.g. "save file_name"
         else if input.contains("save") {
             let file_name = input.as_str().split(" ").collect::<Vec<&str>>()[1].trim();
             let state_read = state.read().expect("Cannot get read-only access to state");
 
-            let mut file = std::fs::File::create(file_name).expect("create failed");
-            file.write_all(&state_read.to_proto().encode_to_vec())
-                .expect("write failed");
+            let mut writer =
+                BufWriter::new(std::fs::File::create(file_name).expect("create failed"));
+            state_read.serialize(&mut writer).expect("write failed");
+            writer.flush().expect("write failed");
             println!("Save complete");
         }
+        // Command for saving a snapshot of the stable state, which prints the snapshot's hash.
+        // e.g. "snapshot file_name"
+        else if input.contains("snapshot") {
+            let file_name = input.as_str().split(" ").collect::<Vec<&str>>()[1].trim();
+            let state_read = state.read().expect("Cannot get read-only access to state");
+
+            let mut file = std::fs::File::create(file_name).expect("create failed");
+            let hash = snapshot::write(&state_read, &mut file).expect("write failed");
+            println!("Snapshot complete. Hash: {}", hash);
+        }
     }
 }
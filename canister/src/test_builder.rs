@@ -1,9 +1,34 @@
 use bitcoin::{
-    secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, util::uint::Uint256, Address, Block,
-    BlockHash, BlockHeader, Network, OutPoint, PublicKey, Script, Transaction, TxIn, TxMerkleNode,
-    TxOut,
+    bech32::u5, secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, util::address::Payload,
+    util::uint::Uint256, Address, Block, BlockHash, BlockHeader, Network, OutPoint, PublicKey,
+    Script, Transaction, TxIn, TxMerkleNode, TxOut,
 };
 
+/// Returns an address of every type that the canister supports, with random public keys.
+pub fn addresses_of_all_types(network: Network) -> Vec<Address> {
+    let public_key = || {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        PublicKey::new(secp.generate_keypair(&mut rng).1)
+    };
+    let script = Script::from(vec![0x51]);
+    vec![
+        Address::p2pkh(&public_key(), network),
+        Address::p2sh(&script, network),
+        Address::p2shwpkh(&public_key(), network).unwrap(),
+        Address::p2wpkh(&public_key(), network).unwrap(),
+        Address::p2wsh(&script, network),
+        // P2TR, with an arbitrary output key.
+        Address {
+            network,
+            payload: Payload::WitnessProgram {
+                version: u5::try_from_u8(1).unwrap(),
+                program: public_key().key.serialize()[1..].to_vec(),
+            },
+        },
+    ]
+}
+
 pub struct BlockBuilder {
     prev_header: Option<BlockHeader>,
     transactions: Vec<Transaction>,
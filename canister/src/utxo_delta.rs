@@ -0,0 +1,216 @@
+use bitcoin::{Block, OutPoint, Script, TxOut};
+use std::collections::{BTreeMap, HashMap};
+
+type Height = u32;
+
+/// The changes that a block makes to the UTXOs of its ancestors, grouped by script.
+///
+/// Computing the delta once when the block is inserted spares queries from re-applying
+/// all the transactions of the block, most of which are unrelated to the queried script.
+/// As the values of the spent outputs are needed anyway, the fee rates of the block's
+/// transactions are computed along with it.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct UtxoDelta {
+    // The outputs created by the block that aren't spent by the block itself.
+    added: BTreeMap<OutPoint, TxOut>,
+
+    // The outpoints of `added`, keyed by their script.
+    added_by_script: BTreeMap<Script, Vec<OutPoint>>,
+
+    // The outpoints spent by the block that were created before the block, keyed by their script.
+    removed_by_script: BTreeMap<Script, Vec<OutPoint>>,
+
+    // The fee rates of the block's transactions other than the coinbase, in satoshi per vbyte.
+    fee_rates: Vec<u64>,
+}
+
+impl UtxoDelta {
+    /// Computes the delta of a block.
+    ///
+    /// `get_spent_txout` returns the output of an outpoint created by one of the block's
+    /// ancestors. The block's transactions must have been checked against its ancestors,
+    /// so that all the outpoints it spends exist.
+    pub fn new<F>(block: &Block, get_spent_txout: F) -> Self
+    where
+        F: Fn(&OutPoint) -> Option<TxOut>,
+    {
+        let mut added = BTreeMap::new();
+        let mut removed_by_script: BTreeMap<Script, Vec<OutPoint>> = BTreeMap::new();
+        let mut fee_rates = vec![];
+
+        for tx in &block.txdata {
+            if !tx.is_coin_base() {
+                let mut input_value: u64 = 0;
+                for input in &tx.input {
+                    // Outputs that are created and spent within the block cancel out.
+                    let txout = match added.remove(&input.previous_output) {
+                        Some(txout) => txout,
+                        None => {
+                            let txout = get_spent_txout(&input.previous_output)
+                                .expect("The inputs of checked blocks must exist");
+                            removed_by_script
+                                .entry(txout.script_pubkey.clone())
+                                .or_default()
+                                .push(input.previous_output);
+                            txout
+                        }
+                    };
+                    input_value += txout.value;
+                }
+
+                let output_value: u64 = tx.output.iter().map(|txout| txout.value).sum();
+                fee_rates.push(fee_rate(input_value, output_value, tx.get_weight() as u64));
+            }
+
+            let txid = tx.txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                added.insert(OutPoint::new(txid, vout as u32), txout.clone());
+            }
+        }
+
+        let mut added_by_script: BTreeMap<Script, Vec<OutPoint>> = BTreeMap::new();
+        for (outpoint, txout) in &added {
+            added_by_script
+                .entry(txout.script_pubkey.clone())
+                .or_default()
+                .push(*outpoint);
+        }
+
+        Self {
+            added,
+            added_by_script,
+            removed_by_script,
+            fee_rates,
+        }
+    }
+
+    /// Returns the output of the given outpoint if it's created by the block and isn't
+    /// spent by the block itself.
+    pub fn get_added(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.added.get(outpoint)
+    }
+
+    /// Returns the fee rates of the block's transactions other than the coinbase, in satoshi
+    /// per vbyte, in the order of the transactions.
+    pub fn fee_rates(&self) -> &[u64] {
+        &self.fee_rates
+    }
+
+    /// Applies the changes to the UTXOs of the given script, where `height` is the height
+    /// of the block.
+    pub fn apply(
+        &self,
+        script: &Script,
+        height: Height,
+        utxos: &mut HashMap<OutPoint, (TxOut, Height)>,
+    ) {
+        for outpoint in self.removed_by_script.get(script).into_iter().flatten() {
+            utxos.remove(outpoint);
+        }
+
+        for outpoint in self.added_by_script.get(script).into_iter().flatten() {
+            utxos.insert(*outpoint, (self.added[outpoint].clone(), height));
+        }
+    }
+}
+
+// Returns the fee rate of a transaction in satoshi per vbyte, rounded down.
+//
+// Block validation doesn't check the values of transactions, so the outputs may be worth
+// more than the inputs, in which case the fee rate is zero.
+fn fee_rate(input_value: u64, output_value: u64, weight: u64) -> u64 {
+    // A vbyte is four weight units, rounded up.
+    let vsize = (weight + 3) / 4;
+    input_value.saturating_sub(output_value) / vsize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_builder::{BlockBuilder, TransactionBuilder};
+    use bitcoin::{
+        secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, Address, Network, PublicKey,
+    };
+
+    fn random_address() -> Address {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Regtest,
+        )
+    }
+
+    #[test]
+    fn outputs_spent_within_the_block_cancel_out() {
+        let address_1 = random_address();
+        let address_2 = random_address();
+
+        // A stable output of address 1.
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let stable_outpoint = OutPoint::new(coinbase_tx.txid(), 0);
+
+        // A block that spends the stable output to address 2, which in turn spends it
+        // back to address 1.
+        let tx_1 = TransactionBuilder::with_input(stable_outpoint)
+            .with_output(&address_2, 1000)
+            .build();
+        let tx_2 = TransactionBuilder::with_input(OutPoint::new(tx_1.txid(), 0))
+            .with_output(&address_1, 1000)
+            .build();
+        let block = BlockBuilder::genesis()
+            .with_transaction(tx_1)
+            .with_transaction(tx_2.clone())
+            .build();
+
+        let delta = UtxoDelta::new(&block, |outpoint| {
+            assert_eq!(*outpoint, stable_outpoint);
+            Some(coinbase_tx.output[0].clone())
+        });
+
+        let mut utxos = HashMap::new();
+        utxos.insert(stable_outpoint, (coinbase_tx.output[0].clone(), 1));
+        delta.apply(&address_1.script_pubkey(), 2, &mut utxos);
+        assert_eq!(
+            utxos,
+            maplit::hashmap! {
+                OutPoint::new(tx_2.txid(), 0) => (tx_2.output[0].clone(), 2)
+            }
+        );
+
+        let mut utxos = HashMap::new();
+        delta.apply(&address_2.script_pubkey(), 2, &mut utxos);
+        assert!(utxos.is_empty());
+    }
+
+    #[test]
+    fn fee_rates() {
+        let address = random_address();
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 10_000)
+            .build();
+
+        // A block with two transactions, the second of which spends the output of the first.
+        let tx_1 = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0))
+            .with_output(&address, 9_000)
+            .build();
+        let tx_2 = TransactionBuilder::with_input(OutPoint::new(tx_1.txid(), 0))
+            .with_output(&address, 4_000)
+            .build();
+        let block = BlockBuilder::genesis()
+            .with_transaction(tx_1.clone())
+            .with_transaction(tx_2.clone())
+            .build();
+
+        let delta = UtxoDelta::new(&block, |_| Some(coinbase_tx.output[0].clone()));
+
+        let vsize = |tx: &bitcoin::Transaction| (tx.get_weight() as u64 + 3) / 4;
+        assert_eq!(
+            delta.fee_rates(),
+            &[1_000 / vsize(&tx_1), 5_000 / vsize(&tx_2)]
+        );
+    }
+}
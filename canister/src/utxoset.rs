@@ -1,57 +1,234 @@
-use crate::proto;
-use bitcoin::hashes::Hash;
-use bitcoin::{Address, Network, OutPoint, Script, Transaction, TxOut, Txid};
+use crate::{compression, muhash::MuHash, proto};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Network, OutPoint, Script, Transaction, TxOut, Txid};
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::ops::Bound::{Excluded, Unbounded};
 use std::str::FromStr;
 
 type Height = u32;
 
+// Outputs with larger scripts can't be spent.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+const OP_RETURN: u8 = 0x6a;
+const OP_1: u8 = 0x51;
+
+// Encoded UTXOs up to this size are stored inline, which is the case for all the standard
+// types of outputs unless their amount is unusually large.
+const INLINE_CAPACITY: usize = 46;
+
 lazy_static::lazy_static! {
     static ref DUPLICATE_TX_IDS: [Txid; 2] = [
         Txid::from_str("d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599").unwrap(),
         Txid::from_str("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468").unwrap()
     ];
+
+    // The transaction of the genesis block, which is the same on all networks.
+    static ref GENESIS_TX_ID: Txid = genesis_block(Network::Bitcoin).txdata[0].txid();
+}
+
+/// Errors that can occur when inserting a transaction into a `UtxoSet`.
+#[derive(Debug, PartialEq)]
+pub enum InsertTxError {
+    /// An input of the transaction spends an outpoint that isn't in the set.
+    MissingInput(OutPoint),
+    /// An output of the transaction is already in the set.
+    DuplicateOutpoint(OutPoint),
 }
 
 #[cfg_attr(test, derive(Clone, Debug, PartialEq))]
 pub struct UtxoSet {
-    utxos: HashMap<OutPoint, (TxOut, Height)>,
+    // The UTXOs are encoded compactly, as they make up most of the state.
+    utxos: HashMap<OutPoint, CompactUtxo>,
     network: Network,
-    // An index for fast retrievals of an address's UTXOs.
-    address_to_outpoints: BTreeMap<String, Vec<OutPoint>>,
+    // An index for fast retrievals of the UTXOs of a script.
+    script_to_outpoints: BTreeMap<ScriptKey, Vec<OutPoint>>,
     // If true, a transaction's inputs must all be present in the UTXO for it to be accepted.
     strict: bool,
+    // A rolling hash of the UTXOs, which is updated as UTXOs are inserted and removed.
+    muhash: MuHash,
 }
 
 impl UtxoSet {
     pub fn new(strict: bool, network: Network) -> Self {
         Self {
             utxos: HashMap::default(),
-            address_to_outpoints: BTreeMap::default(),
+            script_to_outpoints: BTreeMap::default(),
             strict,
             network,
+            muhash: MuHash::new(),
         }
     }
 
-    /// Returns the `UtxoSet` of a given bitcoin address.
-    pub fn get_utxos(&self, address: &str) -> UtxoSet {
+    /// Returns the `UtxoSet` of a given script.
+    pub fn get_utxos(&self, script: &Script) -> UtxoSet {
         // Since we're returning a partial UTXO, we need not be strict.
         let mut utxos = Self::new(false, self.network);
-        for outpoint in self.address_to_outpoints.get(address).unwrap_or(&vec![]) {
-            let (tx_out, height) = self.utxos.get(outpoint).expect("outpoint must exist");
-            utxos.insert_outpoint(*outpoint, tx_out.clone(), *height);
+        let script_key = ScriptKey::new(script);
+        for outpoint in self.script_to_outpoints.get(&script_key).unwrap_or(&vec![]) {
+            let utxo = self.utxos.get(outpoint).expect("outpoint must exist");
+            utxos.insert_outpoint(*outpoint, script_key, utxo.clone());
         }
 
         utxos
     }
 
-    pub fn insert_tx(&mut self, tx: &Transaction, height: Height) {
+    /// Inserts a transaction into the set, removing the outputs it spends and adding the
+    /// outputs it creates.
+    ///
+    /// The transaction is checked before the set is modified, so on error the set is
+    /// left unchanged.
+    pub fn insert_tx(&mut self, tx: &Transaction, height: Height) -> Result<(), InsertTxError> {
+        self.check_tx(tx)?;
         self.remove_spent_txs(tx);
         self.insert_unspent_txs(tx, height);
+        Ok(())
+    }
+
+    /// Returns true if the outpoint is in the set.
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    /// Returns the output of the given outpoint, if it's in the set.
+    pub fn get(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.utxos.get(outpoint).map(|utxo| utxo.decode().0)
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Returns the number of UTXOs in the set.
+    pub fn num_utxos(&self) -> usize {
+        self.utxos.len()
+    }
+
+    /// Returns the number of distinct scripts that the UTXOs are indexed by.
+    pub fn num_scripts(&self) -> usize {
+        self.script_to_outpoints.len()
     }
 
     pub fn into_set(self) -> HashSet<(OutPoint, TxOut, Height)> {
-        self.utxos.into_iter().map(|(k, v)| (k, v.0, v.1)).collect()
+        self.utxos
+            .into_iter()
+            .map(|(outpoint, utxo)| {
+                let (txout, height, _) = utxo.decode();
+                (outpoint, txout, height)
+            })
+            .collect()
+    }
+
+    /// Returns the hash of the set, which is the same as the MuHash of the UTXO set that
+    /// Bitcoin Core returns in `gettxoutsetinfo`, with its bytes in reverse order.
+    ///
+    /// Like in Core, the outputs of the genesis block and outputs that can't be spent aren't
+    /// part of the hash.
+    pub fn hash(&self) -> sha256::Hash {
+        self.muhash.finalize()
+    }
+
+    /// Returns the state of the rolling hash of the set.
+    pub fn muhash(&self) -> &MuHash {
+        &self.muhash
+    }
+
+    /// Replaces the state of the rolling hash of the set.
+    ///
+    /// This is needed when the UTXOs are inserted with `insert_proto`, which doesn't update
+    /// the hash.
+    pub fn set_muhash(&mut self, muhash: MuHash) {
+        self.muhash = muhash;
+    }
+
+    /// Computes the hash of the set from scratch, which is needed for sets that were stored
+    /// without their hash.
+    pub fn recompute_hash(&mut self) {
+        let mut muhash = MuHash::new();
+        for (outpoint, utxo) in &self.utxos {
+            let (txout, height, is_coinbase) = utxo.decode();
+            if is_hashed(outpoint, &txout) {
+                muhash.insert(&muhash_element(outpoint, &txout, height, is_coinbase));
+            }
+        }
+        self.muhash = muhash;
+    }
+
+    /// Returns an estimate of the memory used by the UTXOs and their index, along with an
+    /// estimate of the memory they'd use in the original layout, which stored full `TxOut`s
+    /// and only indexed the UTXOs of addresses, keyed by the address strings.
+    ///
+    /// Map entries and heap allocations are counted, but not the slack of hash tables and
+    /// B-tree nodes.
+    #[cfg(test)]
+    pub fn estimate_memory_usage(&self) -> (usize, usize) {
+        use bitcoin::Address;
+        use std::mem::size_of;
+
+        let mut compact_size = self.utxos.len() * size_of::<(OutPoint, CompactUtxo)>();
+        let mut original_size = self.utxos.len() * size_of::<(OutPoint, (TxOut, Height))>();
+
+        // The number of UTXOs of each address, which the original index was keyed by.
+        let mut address_utxos: BTreeMap<String, usize> = BTreeMap::new();
+        for utxo in self.utxos.values() {
+            if let CompactUtxo::Heap(bytes) = utxo {
+                compact_size += bytes.len();
+            }
+            let script = utxo.decode().0.script_pubkey;
+            original_size += script.len();
+            if let Some(address) = Address::from_script(&script, self.network) {
+                *address_utxos.entry(address.to_string()).or_insert(0) += 1;
+            }
+        }
+        for outpoints in self.script_to_outpoints.values() {
+            compact_size += size_of::<(ScriptKey, Vec<OutPoint>)>()
+                + outpoints.capacity() * size_of::<OutPoint>();
+        }
+        for (address, num_utxos) in &address_utxos {
+            // The original index grew its vectors from empty, which reserves at least 4 entries.
+            let capacity = std::cmp::max(4, num_utxos.next_power_of_two());
+            original_size += size_of::<(String, Vec<OutPoint>)>()
+                + address.len()
+                + capacity * size_of::<OutPoint>();
+        }
+
+        (compact_size, original_size)
+    }
+
+    // Checks that a transaction can be inserted into the set.
+    fn check_tx(&self, tx: &Transaction) -> Result<(), InsertTxError> {
+        if !tx.is_coin_base() && self.strict {
+            let mut inputs = HashSet::new();
+            for input in &tx.input {
+                // Each input must spend a distinct outpoint that is in the set.
+                if !self.utxos.contains_key(&input.previous_output)
+                    || !inputs.insert(input.previous_output)
+                {
+                    return Err(InsertTxError::MissingInput(input.previous_output));
+                }
+            }
+        }
+
+        // NOTE: There was a bug where there were duplicate transactions. These transactions
+        // we overwrite.
+        //
+        // See: https://en.bitcoin.it/wiki/BIP_0030
+        //      https://bitcoinexplorer.org/tx/d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599
+        //      https://bitcoinexplorer.org/tx/e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468
+        let txid = tx.txid();
+        if !DUPLICATE_TX_IDS.contains(&txid) {
+            for vout in 0..tx.output.len() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                if self.utxos.contains_key(&outpoint) {
+                    return Err(InsertTxError::DuplicateOutpoint(outpoint));
+                }
+            }
+        }
+
+        Ok(())
     }
 
     // Iterates over transaction inputs and removes spent outputs.
@@ -61,36 +238,25 @@ impl UtxoSet {
         }
 
         for input in &tx.input {
-            // Verify that we've seen the outpoint before.
-            match self.utxos.remove(&input.previous_output) {
-                Some((txout, _)) => {
-                    if let Some(address) = Address::from_script(&txout.script_pubkey, self.network)
-                    {
-                        let address = address.to_string();
-                        let address_outpoints =
-                            self.address_to_outpoints.get_mut(&address).unwrap();
-
-                        let mut found = false;
-                        for (index, outpoint) in address_outpoints.iter().enumerate() {
-                            if outpoint == &input.previous_output {
-                                address_outpoints.remove(index);
-                                found = true;
-                                break;
-                            }
-                        }
-
-                        if !found && self.strict {
-                            panic!("Outpoint {:?} not found in index.", input.previous_output);
-                        }
-
-                        if address_outpoints.is_empty() {
-                            self.address_to_outpoints.remove(&address);
-                        }
-                    }
+            // Outpoints that aren't in the set are skipped. In strict mode, `check_tx`
+            // guarantees that all the outpoints are present.
+            if let Some(utxo) = self.utxos.remove(&input.previous_output) {
+                let (txout, height, is_coinbase) = utxo.decode();
+                if is_hashed(&input.previous_output, &txout) {
+                    self.muhash.remove(&muhash_element(
+                        &input.previous_output,
+                        &txout,
+                        height,
+                        is_coinbase,
+                    ));
                 }
-                None => {
-                    if self.strict {
-                        panic!("Outpoint {:?} not found.", input.previous_output);
+
+                let script_key = ScriptKey::new(&txout.script_pubkey);
+                if let Some(script_outpoints) = self.script_to_outpoints.get_mut(&script_key) {
+                    script_outpoints.retain(|outpoint| outpoint != &input.previous_output);
+
+                    if script_outpoints.is_empty() {
+                        self.script_to_outpoints.remove(&script_key);
                     }
                 }
             }
@@ -99,104 +265,351 @@ impl UtxoSet {
 
     // Iterates over transaction outputs and adds unspents.
     fn insert_unspent_txs(&mut self, tx: &Transaction, height: Height) {
+        let is_coinbase = tx.is_coin_base();
         for (vout, output) in tx.output.iter().enumerate() {
+            let outpoint = OutPoint::new(tx.txid(), vout as u32);
+
+            // The outputs of duplicate transactions overwrite the existing ones.
+            if let Some(old_utxo) = self.utxos.get(&outpoint) {
+                let (old_txout, old_height, old_is_coinbase) = old_utxo.decode();
+                if is_hashed(&outpoint, &old_txout) {
+                    self.muhash.remove(&muhash_element(
+                        &outpoint,
+                        &old_txout,
+                        old_height,
+                        old_is_coinbase,
+                    ));
+                }
+            }
+
+            if is_hashed(&outpoint, output) {
+                self.muhash
+                    .insert(&muhash_element(&outpoint, output, height, is_coinbase));
+            }
+
             self.insert_outpoint(
-                OutPoint::new(tx.txid(), vout as u32),
-                output.clone(),
-                height,
+                outpoint,
+                ScriptKey::new(&output.script_pubkey),
+                CompactUtxo::new(output, height, is_coinbase),
             );
         }
     }
 
-    fn insert_outpoint(&mut self, outpoint: OutPoint, output: TxOut, height: Height) {
-        // Verify that we haven't seen the outpoint before.
-        // NOTE: There was a bug where there were duplicate transactions. These transactions
-        // we overwrite.
-        //
-        // See: https://en.bitcoin.it/wiki/BIP_0030
-        //      https://bitcoinexplorer.org/tx/d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599
-        //      https://bitcoinexplorer.org/tx/e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468
-        if self.utxos.contains_key(&outpoint) && !DUPLICATE_TX_IDS.contains(&outpoint.txid) {
-            panic!(
-                "Cannot insert outpoint {:?} because it was already inserted. Block height: {}",
-                outpoint, height
+    // Inserts an outpoint into the set. An outpoint that is already in the set, which is only
+    // the case for the outputs of duplicate transactions, is overwritten.
+    fn insert_outpoint(&mut self, outpoint: OutPoint, script_key: ScriptKey, utxo: CompactUtxo) {
+        // Add the outpoint to the index of its script, unless it's already there. Most scripts
+        // only have a single UTXO, so no space is reserved for more.
+        if self.utxos.insert(outpoint, utxo).is_none() {
+            self.script_to_outpoints
+                .entry(script_key)
+                .or_insert_with(|| Vec::with_capacity(1))
+                .push(outpoint);
+        }
+    }
+
+    /// Returns an iterator over the UTXOs as protobuf structs, in no particular order.
+    pub fn iter_proto(&self) -> impl Iterator<Item = proto::Utxo> + '_ {
+        self.utxos
+            .iter()
+            .map(|(outpoint, utxo)| utxo_to_proto(outpoint, utxo))
+    }
+
+    /// Returns the UTXOs that follow the position of the cursor as protobuf structs, and
+    /// advances the cursor past them. The UTXOs of a script are returned together, so more
+    /// than `max_utxos` are returned if the last script has many. Once all the UTXOs were
+    /// returned, the result is empty.
+    ///
+    /// The UTXOs are returned in the order of their scripts, so the set must not change
+    /// while it's read with a cursor.
+    pub fn next_utxos_proto(&self, cursor: &mut UtxoCursor, max_utxos: usize) -> Vec<proto::Utxo> {
+        let scripts = match &cursor.last_script {
+            Some(last_script) => self
+                .script_to_outpoints
+                .range((Excluded(*last_script), Unbounded)),
+            None => self.script_to_outpoints.range(..),
+        };
+
+        let mut utxos = vec![];
+        for (script_key, outpoints) in scripts {
+            if utxos.len() >= max_utxos {
+                break;
+            }
+
+            utxos.extend(
+                outpoints
+                    .iter()
+                    .map(|outpoint| utxo_to_proto(outpoint, &self.utxos[outpoint])),
             );
+            cursor.last_script = Some(*script_key);
         }
+        utxos
+    }
 
-        // Insert the outpoint.
-        if let Some(address) = Address::from_script(&output.script_pubkey, self.network) {
-            // Add the address to the index if we can parse it.
-            self.address_to_outpoints
-                .entry(address.to_string())
-                .or_insert_with(Vec::new)
-                .push(outpoint);
+    /// Inserts a UTXO given as a protobuf struct.
+    ///
+    /// NOTE: The hash of the set isn't updated, as protobuf UTXOs are inserted when restoring
+    /// a set whose hash is restored separately.
+    pub fn insert_proto(&mut self, utxo: proto::Utxo) -> Result<(), InsertTxError> {
+        let (outpoint, tx_out) = utxo_from_proto(&utxo);
+        self.insert_utxo(outpoint, &tx_out, utxo.height, utxo.is_coinbase)
+    }
+
+    /// Inserts a UTXO given as a protobuf struct, and adds it to the hash of the set.
+    pub fn insert_proto_and_update_hash(&mut self, utxo: proto::Utxo) -> Result<(), InsertTxError> {
+        let (outpoint, tx_out) = utxo_from_proto(&utxo);
+        self.insert_utxo(outpoint, &tx_out, utxo.height, utxo.is_coinbase)?;
+
+        if is_hashed(&outpoint, &tx_out) {
+            self.muhash.insert(&muhash_element(
+                &outpoint,
+                &tx_out,
+                utxo.height,
+                utxo.is_coinbase,
+            ));
+        }
+        Ok(())
+    }
+
+    // Inserts a UTXO that isn't in the set yet, without updating the hash of the set.
+    fn insert_utxo(
+        &mut self,
+        outpoint: OutPoint,
+        tx_out: &TxOut,
+        height: Height,
+        is_coinbase: bool,
+    ) -> Result<(), InsertTxError> {
+        if self.utxos.contains_key(&outpoint) {
+            return Err(InsertTxError::DuplicateOutpoint(outpoint));
         }
 
-        self.utxos.insert(outpoint, (output, height));
+        self.insert_outpoint(
+            outpoint,
+            ScriptKey::new(&tx_out.script_pubkey),
+            CompactUtxo::new(tx_out, height, is_coinbase),
+        );
+        Ok(())
     }
 
     pub fn to_proto(&self) -> proto::UtxoSet {
         proto::UtxoSet {
-            utxos: self
-                .utxos
-                .iter()
-                .map(|(outpoint, (txout, height))| proto::Utxo {
-                    outpoint: Some(proto::OutPoint {
-                        txid: outpoint.txid.to_vec(),
-                        vout: outpoint.vout,
-                    }),
-                    txout: Some(proto::TxOut {
-                        value: txout.value,
-                        script_pubkey: txout.script_pubkey.to_bytes(),
-                    }),
-                    height: *height,
-                })
-                .collect(),
+            utxos: self.iter_proto().collect(),
+            ..self.to_proto_without_utxos()
+        }
+    }
+
+    /// Returns a protobuf struct with the parameters of the `UtxoSet`, but none of its UTXOs.
+    pub fn to_proto_without_utxos(&self) -> proto::UtxoSet {
+        proto::UtxoSet {
+            utxos: vec![],
             strict: self.strict,
-            network: match self.network {
-                Network::Bitcoin => 0,
-                Network::Testnet => 1,
-                Network::Signet => 2,
-                Network::Regtest => 3,
-            },
+            network: network_to_proto(self.network),
+            muhash: self.muhash.to_bytes(),
         }
     }
 
     pub fn from_proto(utxos_proto: proto::UtxoSet) -> Self {
+        let muhash = MuHash::from_bytes(&utxos_proto.muhash);
         let mut utxo_set = Self {
             utxos: HashMap::default(),
-            address_to_outpoints: BTreeMap::default(),
+            script_to_outpoints: BTreeMap::default(),
             strict: utxos_proto.strict,
-            network: match utxos_proto.network {
-                0 => Network::Bitcoin,
-                1 => Network::Testnet,
-                2 => Network::Signet,
-                3 => Network::Regtest,
-                _ => panic!("Invalid network ID"),
-            },
+            network: network_from_proto(utxos_proto.network).expect("Invalid network ID"),
+            muhash: MuHash::new(),
         };
 
         for utxo in utxos_proto.utxos.into_iter() {
-            let outpoint = utxo
-                .outpoint
-                .map(|o| OutPoint::new(Txid::from_hash(Hash::from_slice(&o.txid).unwrap()), o.vout))
-                .unwrap();
-
-            let tx_out = utxo
-                .txout
-                .map(|t| TxOut {
-                    value: t.value,
-                    script_pubkey: Script::from(t.script_pubkey),
-                })
-                .unwrap();
+            utxo_set
+                .insert_proto(utxo)
+                .expect("UTXOs in a UtxoSet must be unique");
+        }
 
-            utxo_set.insert_outpoint(outpoint, tx_out, utxo.height);
+        // Sets that were stored before their hash was introduced don't have one, so it's
+        // computed from their UTXOs. These sets don't record which UTXOs are coinbase outputs
+        // either, so the hash then differs from Bitcoin Core's if any of them is.
+        match muhash {
+            Some(muhash) => utxo_set.set_muhash(muhash),
+            None => utxo_set.recompute_hash(),
         }
 
         utxo_set
     }
 }
 
+/// A position in the UTXOs of a set, from which the following UTXOs are read with
+/// `UtxoSet::next_utxos_proto`.
+#[derive(Default)]
+pub struct UtxoCursor {
+    // The script whose UTXOs were read last, if any.
+    last_script: Option<ScriptKey>,
+}
+
+/// Converts a `Network` into its protobuf enum value.
+pub fn network_to_proto(network: Network) -> i32 {
+    match network {
+        Network::Bitcoin => 0,
+        Network::Testnet => 1,
+        Network::Signet => 2,
+        Network::Regtest => 3,
+    }
+}
+
+/// Converts a protobuf enum value into a `Network`, if it's valid.
+pub fn network_from_proto(network: i32) -> Option<Network> {
+    match network {
+        0 => Some(Network::Bitcoin),
+        1 => Some(Network::Testnet),
+        2 => Some(Network::Signet),
+        3 => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+// Returns the outpoint and the output of a UTXO given as a protobuf struct.
+fn utxo_to_proto(outpoint: &OutPoint, utxo: &CompactUtxo) -> proto::Utxo {
+    let (txout, height, is_coinbase) = utxo.decode();
+    proto::Utxo {
+        outpoint: Some(proto::OutPoint {
+            txid: outpoint.txid.to_vec(),
+            vout: outpoint.vout,
+        }),
+        txout: Some(proto::TxOut {
+            value: txout.value,
+            script_pubkey: txout.script_pubkey.to_bytes(),
+        }),
+        height,
+        is_coinbase,
+    }
+}
+
+fn utxo_from_proto(utxo: &proto::Utxo) -> (OutPoint, TxOut) {
+    let outpoint = utxo
+        .outpoint
+        .as_ref()
+        .map(|o| OutPoint::new(Txid::from_hash(Hash::from_slice(&o.txid).unwrap()), o.vout))
+        .unwrap();
+
+    let tx_out = utxo
+        .txout
+        .as_ref()
+        .map(|t| TxOut {
+            value: t.value,
+            script_pubkey: Script::from(t.script_pubkey.clone()),
+        })
+        .unwrap();
+
+    (outpoint, tx_out)
+}
+
+// The key of a script in the index of the UTXOs of each script. Standard scripts are keyed
+// by the hash they commit to, and other scripts by their SHA-256 hash, so that keys are at
+// most 32 bytes long regardless of the size of the script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ScriptKey {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    P2tr([u8; 32]),
+    Other([u8; 32]),
+}
+
+impl ScriptKey {
+    fn new(script: &Script) -> Self {
+        let bytes = script.as_bytes();
+        if script.is_p2pkh() {
+            Self::P2pkh(bytes[3..23].try_into().unwrap())
+        } else if script.is_p2sh() {
+            Self::P2sh(bytes[2..22].try_into().unwrap())
+        } else if script.is_v0_p2wpkh() {
+            Self::P2wpkh(bytes[2..22].try_into().unwrap())
+        } else if script.is_v0_p2wsh() {
+            Self::P2wsh(bytes[2..34].try_into().unwrap())
+        } else if bytes.len() == 34 && bytes[0] == OP_1 && bytes[1] == 32 {
+            Self::P2tr(bytes[2..34].try_into().unwrap())
+        } else {
+            Self::Other(sha256::Hash::hash(bytes).into_inner())
+        }
+    }
+}
+
+// A UTXO encoded like in Bitcoin Core's database: its height and whether it's a coinbase
+// output, followed by its compressed amount and its compressed script.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum CompactUtxo {
+    // The length of the encoding, followed by the encoding. Unlike a boxed encoding, this
+    // doesn't need an allocation of its own.
+    Inline(u8, [u8; INLINE_CAPACITY]),
+    Heap(Box<[u8]>),
+}
+
+impl CompactUtxo {
+    fn new(txout: &TxOut, height: Height, is_coinbase: bool) -> Self {
+        let mut bytes = Vec::with_capacity(INLINE_CAPACITY);
+        compression::write_varint(&mut bytes, height as u128 * 2 + is_coinbase as u128);
+        compression::write_varint(&mut bytes, compression::compress_amount(txout.value));
+        compression::write_script(&mut bytes, &txout.script_pubkey);
+
+        if bytes.len() <= INLINE_CAPACITY {
+            let mut inline_bytes = [0; INLINE_CAPACITY];
+            inline_bytes[..bytes.len()].copy_from_slice(&bytes);
+            Self::Inline(bytes.len() as u8, inline_bytes)
+        } else {
+            Self::Heap(bytes.into_boxed_slice())
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Inline(len, bytes) => &bytes[..*len as usize],
+            Self::Heap(bytes) => bytes,
+        }
+    }
+
+    // Returns the output, its height and whether it's a coinbase output.
+    fn decode(&self) -> (TxOut, Height, bool) {
+        let mut bytes = self.bytes();
+        let code = compression::read_varint(&mut bytes).expect("Invalid compact UTXO");
+        let value = compression::decompress_amount(
+            compression::read_varint(&mut bytes).expect("Invalid compact UTXO"),
+        );
+        let script_pubkey = compression::read_script(&mut bytes).expect("Invalid compact UTXO");
+
+        (
+            TxOut {
+                value,
+                script_pubkey,
+            },
+            (code / 2) as Height,
+            code % 2 == 1,
+        )
+    }
+}
+
+// Returns true if the UTXO is part of the hash of the set. The outputs of the genesis block
+// and outputs whose script can never be spent aren't, as Bitcoin Core doesn't add them to
+// its UTXO set.
+fn is_hashed(outpoint: &OutPoint, txout: &TxOut) -> bool {
+    let script = txout.script_pubkey.as_bytes();
+    outpoint.txid != *GENESIS_TX_ID
+        && script.first() != Some(&OP_RETURN)
+        && script.len() <= MAX_SCRIPT_SIZE
+}
+
+// Serializes a UTXO the way Bitcoin Core does when hashing its UTXO set.
+fn muhash_element(
+    outpoint: &OutPoint,
+    txout: &TxOut,
+    height: Height,
+    is_coinbase: bool,
+) -> Vec<u8> {
+    let mut element = serialize(outpoint);
+    element.extend_from_slice(&(height * 2 + is_coinbase as u32).to_le_bytes());
+    element.extend(serialize(txout));
+    element
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -219,7 +632,7 @@ mod test {
                 .build();
 
             let mut utxo = UtxoSet::new(true, *network);
-            utxo.insert_tx(&coinbase_tx, 0);
+            utxo.insert_tx(&coinbase_tx, 0).unwrap();
 
             let expected = maplit::hashset! {
                 (
@@ -236,7 +649,10 @@ mod test {
             };
 
             assert_eq!(utxo.clone().into_set(), expected);
-            assert_eq!(utxo.get_utxos(&address.to_string()).into_set(), expected);
+            assert_eq!(
+                utxo.get_utxos(&address.script_pubkey()).into_set(),
+                expected
+            );
         }
     }
 
@@ -255,7 +671,7 @@ mod test {
             let coinbase_tx = TransactionBuilder::coinbase()
                 .with_output(&address_1, 1000)
                 .build();
-            utxo.insert_tx(&coinbase_tx, 0);
+            utxo.insert_tx(&coinbase_tx, 0).unwrap();
             let expected = maplit::hashset! {
                 (
                     OutPoint {
@@ -270,11 +686,14 @@ mod test {
                 )
             };
 
-            assert_eq!(utxo.get_utxos(&address_1.to_string()).into_set(), expected);
             assert_eq!(
-                utxo.address_to_outpoints,
+                utxo.get_utxos(&address_1.script_pubkey()).into_set(),
+                expected
+            );
+            assert_eq!(
+                utxo.script_to_outpoints,
                 maplit::btreemap! {
-                    address_1.to_string() => vec![OutPoint {
+                    ScriptKey::new(&address_1.script_pubkey()) => vec![OutPoint {
                         txid: coinbase_tx.txid(),
                         vout: 0
                     }]
@@ -285,14 +704,14 @@ mod test {
             let tx = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0))
                 .with_output(&address_2, 1000)
                 .build();
-            utxo.insert_tx(&tx, 1);
+            utxo.insert_tx(&tx, 1).unwrap();
 
             assert_eq!(
-                utxo.get_utxos(&address_1.to_string()).into_set(),
+                utxo.get_utxos(&address_1.script_pubkey()).into_set(),
                 maplit::hashset! {}
             );
             assert_eq!(
-                utxo.get_utxos(&address_2.to_string()).into_set(),
+                utxo.get_utxos(&address_2.script_pubkey()).into_set(),
                 maplit::hashset! {
                     (
                         OutPoint {
@@ -308,9 +727,9 @@ mod test {
                 }
             );
             assert_eq!(
-                utxo.address_to_outpoints,
+                utxo.script_to_outpoints,
                 maplit::btreemap! {
-                    address_2.to_string() => vec![OutPoint {
+                    ScriptKey::new(&address_2.script_pubkey()) => vec![OutPoint {
                         txid: tx.txid(),
                         vout: 0
                     }]
@@ -318,4 +737,211 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn spending_missing_outpoint() {
+        let mut utxo = UtxoSet::new(true, Network::Bitcoin);
+
+        let coinbase_tx = TransactionBuilder::coinbase().build();
+        let tx = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0)).build();
+
+        // The coinbase transaction hasn't been inserted, so its output can't be spent.
+        assert_eq!(
+            utxo.insert_tx(&tx, 0),
+            Err(InsertTxError::MissingInput(OutPoint::new(
+                coinbase_tx.txid(),
+                0
+            )))
+        );
+        assert_eq!(utxo.into_set(), maplit::hashset! {});
+    }
+
+    #[test]
+    fn inserting_duplicate_outpoint() {
+        let mut utxo = UtxoSet::new(true, Network::Bitcoin);
+
+        let coinbase_tx = TransactionBuilder::coinbase().build();
+        utxo.insert_tx(&coinbase_tx, 0).unwrap();
+        let expected = utxo.clone();
+
+        // Inserting the same transaction again is rejected and doesn't modify the set.
+        assert_eq!(
+            utxo.insert_tx(&coinbase_tx, 1),
+            Err(InsertTxError::DuplicateOutpoint(OutPoint::new(
+                coinbase_tx.txid(),
+                0
+            )))
+        );
+        assert_eq!(utxo, expected);
+    }
+
+    #[test]
+    fn compact_utxos() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let public_key = PublicKey::new(secp.generate_keypair(&mut rng).1);
+        let mut uncompressed_public_key = public_key;
+        uncompressed_public_key.compressed = false;
+
+        for (script_pubkey, is_inline) in [
+            (
+                Address::p2pkh(&public_key, Network::Bitcoin).script_pubkey(),
+                true,
+            ),
+            (Script::new_p2pk(&public_key), true),
+            (Script::new_p2pk(&uncompressed_public_key), false),
+            (Script::new_v0_wsh(&Script::new().wscript_hash()), true),
+            (Script::new_op_return(&[0; 80]), false),
+        ]
+        .iter()
+        {
+            for (value, height, is_coinbase) in
+                [(0, 0, false), (5_000_000_000, 700_000, true)].iter()
+            {
+                let txout = TxOut {
+                    value: *value,
+                    script_pubkey: script_pubkey.clone(),
+                };
+                let utxo = CompactUtxo::new(&txout, *height, *is_coinbase);
+                assert_eq!(matches!(utxo, CompactUtxo::Inline(..)), *is_inline);
+                assert_eq!(utxo.decode(), (txout, *height, *is_coinbase));
+            }
+        }
+    }
+
+    #[test]
+    fn memory_usage() {
+        // UTXOs of distinct P2PKH addresses, which are the most common UTXOs after P2PK.
+        let mut utxos = UtxoSet::new(true, Network::Regtest);
+        for _ in 0..100 {
+            utxos
+                .insert_tx(&TransactionBuilder::coinbase().build(), 1)
+                .unwrap();
+        }
+        assert!(utxos
+            .script_to_outpoints
+            .values()
+            .all(|outpoints| outpoints.capacity() == 1));
+
+        let (compact_size, original_size) = utxos.estimate_memory_usage();
+        assert!(compact_size < original_size);
+    }
+
+    #[test]
+    fn read_with_cursor() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let address = Address::p2pkh(
+            &PublicKey::new(secp.generate_keypair(&mut rng).1),
+            Network::Regtest,
+        );
+
+        let mut utxos = UtxoSet::new(true, Network::Regtest);
+        for _ in 0..20 {
+            utxos
+                .insert_tx(&TransactionBuilder::coinbase().build(), 1)
+                .unwrap();
+        }
+
+        // An address with several UTXOs, whose UTXOs are read together.
+        for value in 1..=3 {
+            let tx = TransactionBuilder::coinbase()
+                .with_output(&address, value)
+                .build();
+            utxos.insert_tx(&tx, 1).unwrap();
+
+            // The outputs of duplicate transactions are only read once.
+            utxos.insert_unspent_txs(&tx, 2);
+        }
+
+        let mut cursor = UtxoCursor::default();
+        let mut read_utxos = vec![];
+        loop {
+            let batch = utxos.next_utxos_proto(&mut cursor, 2);
+            if batch.is_empty() {
+                break;
+            }
+            assert!(batch.len() <= 4);
+            read_utxos.extend(batch);
+        }
+
+        assert_eq!(read_utxos.len(), utxos.num_utxos());
+        for utxo in utxos.iter_proto() {
+            assert!(read_utxos.contains(&utxo));
+        }
+    }
+
+    #[test]
+    fn script_keys() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+        let public_key = PublicKey::new(secp.generate_keypair(&mut rng).1);
+
+        // Scripts that commit to the same hash have different keys.
+        let p2pkh = Address::p2pkh(&public_key, Network::Bitcoin).script_pubkey();
+        let p2wpkh = Address::p2wpkh(&public_key, Network::Bitcoin)
+            .unwrap()
+            .script_pubkey();
+        assert!(matches!(ScriptKey::new(&p2pkh), ScriptKey::P2pkh(_)));
+        assert!(matches!(ScriptKey::new(&p2wpkh), ScriptKey::P2wpkh(_)));
+        assert_ne!(ScriptKey::new(&p2pkh), ScriptKey::new(&p2wpkh));
+
+        // Other scripts are keyed by their hash.
+        let p2pk = Script::new_p2pk(&public_key);
+        assert_eq!(
+            ScriptKey::new(&p2pk),
+            ScriptKey::Other(sha256::Hash::hash(p2pk.as_bytes()).into_inner())
+        );
+    }
+
+    #[test]
+    fn hash() {
+        let network = Network::Regtest;
+        let coinbase_tx = TransactionBuilder::coinbase().build();
+        let tx = TransactionBuilder::with_input(OutPoint::new(coinbase_tx.txid(), 0)).build();
+
+        let mut utxo = UtxoSet::new(true, network);
+        let empty_hash = utxo.hash();
+        utxo.insert_tx(&coinbase_tx, 0).unwrap();
+        assert_ne!(utxo.hash(), empty_hash);
+        utxo.insert_tx(&tx, 1).unwrap();
+
+        // The hash only depends on the UTXOs, and not on how the set came to contain them.
+        let mut other_utxo = UtxoSet::new(false, network);
+        other_utxo.insert_tx(&tx, 1).unwrap();
+        assert_eq!(utxo.hash(), other_utxo.hash());
+
+        // The hash depends on the heights of the UTXOs.
+        let mut other_utxo = UtxoSet::new(false, network);
+        other_utxo.insert_tx(&tx, 2).unwrap();
+        assert_ne!(utxo.hash(), other_utxo.hash());
+
+        // The hash is restored along with the UTXOs.
+        assert_eq!(UtxoSet::from_proto(utxo.to_proto()).hash(), utxo.hash());
+
+        // Sets stored without a hash get theirs recomputed.
+        let mut utxo_proto = utxo.to_proto();
+        utxo_proto.muhash = vec![];
+        assert_eq!(UtxoSet::from_proto(utxo_proto).hash(), utxo.hash());
+
+        // Inserting the UTXOs one by one while updating the hash results in the same hash.
+        let mut other_utxo = UtxoSet::new(true, network);
+        for utxo_proto in utxo.iter_proto() {
+            other_utxo.insert_proto_and_update_hash(utxo_proto).unwrap();
+        }
+        assert_eq!(other_utxo.hash(), utxo.hash());
+    }
+
+    #[test]
+    fn hash_excludes_unspendable_outputs() {
+        let mut utxo = UtxoSet::new(true, Network::Regtest);
+        let empty_hash = utxo.hash();
+
+        let mut coinbase_tx = TransactionBuilder::coinbase().build();
+        coinbase_tx.output[0].script_pubkey = Script::new_op_return(&[1, 2, 3]);
+        utxo.insert_tx(&coinbase_tx, 0).unwrap();
+
+        assert_eq!(utxo.num_utxos(), 1);
+        assert_eq!(utxo.hash(), empty_hash);
+    }
 }
@@ -0,0 +1,375 @@
+//! Validation of blocks before they're accepted into the state.
+use bitcoin::{consensus::params::Params, util::uint::Uint256, Block, BlockHeader, Network};
+
+type Height = u32;
+
+// The number of blocks used to compute the median time past.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Errors that can occur when validating a block.
+#[derive(Debug, PartialEq)]
+pub enum BlockValidationError {
+    /// The block's hash doesn't satisfy the target in its header.
+    InvalidProofOfWork,
+    /// The target in the block's header doesn't match the target expected by the
+    /// difficulty adjustment rules of the network.
+    IncorrectTarget { expected: u32, got: u32 },
+    /// The block's timestamp isn't greater than the median time of the previous blocks.
+    TimestampTooOld { median_time_past: u32, got: u32 },
+    /// The merkle root in the block's header doesn't match its transactions.
+    InvalidMerkleRoot,
+    /// The block doesn't contain any transactions.
+    NoTransactions,
+    /// Not enough ancestors of the block are known to validate it.
+    MissingAncestors,
+}
+
+/// Validates a block given its ancestors.
+///
+/// `prev_height` is the height of the block's parent, and `ancestors` iterates over the
+/// headers of the block's ancestors, starting with its parent. At least a difficulty
+/// adjustment interval worth of ancestors (or all of them, down to genesis) are needed.
+pub fn validate_block<'a, I>(
+    network: Network,
+    block: &Block,
+    prev_height: Height,
+    ancestors: I,
+) -> Result<(), BlockValidationError>
+where
+    I: Iterator<Item = &'a BlockHeader> + Clone,
+{
+    validate_header(network, &block.header, prev_height, ancestors)?;
+
+    if block.txdata.is_empty() {
+        return Err(BlockValidationError::NoTransactions);
+    }
+
+    if !block.check_merkle_root() {
+        return Err(BlockValidationError::InvalidMerkleRoot);
+    }
+
+    Ok(())
+}
+
+fn validate_header<'a, I>(
+    network: Network,
+    header: &BlockHeader,
+    prev_height: Height,
+    ancestors: I,
+) -> Result<(), BlockValidationError>
+where
+    I: Iterator<Item = &'a BlockHeader> + Clone,
+{
+    if header.validate_pow(&header.target()).is_err() {
+        return Err(BlockValidationError::InvalidProofOfWork);
+    }
+
+    // NOTE: The target isn't checked against the network's proof-of-work limit directly.
+    // The target of each block is derived from the targets of its ancestors, and the
+    // retargeting rules below never exceed the limit.
+    let expected_bits = expected_bits(
+        &Params::new(network),
+        header,
+        prev_height,
+        ancestors.clone(),
+    )?;
+    if header.bits != expected_bits {
+        return Err(BlockValidationError::IncorrectTarget {
+            expected: expected_bits,
+            got: header.bits,
+        });
+    }
+
+    let median_time_past = median_time_past(ancestors);
+    if header.time <= median_time_past {
+        return Err(BlockValidationError::TimestampTooOld {
+            median_time_past,
+            got: header.time,
+        });
+    }
+
+    Ok(())
+}
+
+// Returns the target, in compact form, that a block extending the given ancestors must have.
+//
+// This mirrors `GetNextWorkRequired` in Bitcoin Core.
+fn expected_bits<'a, I>(
+    params: &Params,
+    header: &BlockHeader,
+    prev_height: Height,
+    mut ancestors: I,
+) -> Result<u32, BlockValidationError>
+where
+    I: Iterator<Item = &'a BlockHeader> + Clone,
+{
+    let interval = params.pow_target_timespan / params.pow_target_spacing;
+    let height = prev_height as u64 + 1;
+    let prev_header = ancestors
+        .clone()
+        .next()
+        .ok_or(BlockValidationError::MissingAncestors)?;
+
+    if height % interval != 0 {
+        if params.allow_min_difficulty_blocks {
+            let pow_limit_bits = BlockHeader::compact_target_from_u256(&params.pow_limit);
+
+            // A block is allowed to have the minimum difficulty if it was mined more than
+            // twice the target spacing after its parent.
+            if header.time as u64 > prev_header.time as u64 + 2 * params.pow_target_spacing {
+                return Ok(pow_limit_bits);
+            }
+
+            // Otherwise, it has the target of the last block that isn't a min-difficulty
+            // block, or the target at the start of the difficulty adjustment interval.
+            let mut bits = prev_header.bits;
+            for (i, ancestor) in ancestors.enumerate() {
+                bits = ancestor.bits;
+                let ancestor_height = prev_height as u64 - i as u64;
+                if ancestor_height % interval == 0 || ancestor.bits != pow_limit_bits {
+                    break;
+                }
+            }
+            return Ok(bits);
+        }
+
+        return Ok(prev_header.bits);
+    }
+
+    if params.no_pow_retargeting {
+        return Ok(prev_header.bits);
+    }
+
+    // The first block of the difficulty adjustment interval that just ended.
+    let first_header = ancestors
+        .nth(interval as usize - 1)
+        .ok_or(BlockValidationError::MissingAncestors)?;
+
+    let timespan = params.pow_target_timespan as i64;
+    let actual_timespan = (prev_header.time as i64 - first_header.time as i64)
+        .max(timespan / 4)
+        .min(timespan * 4);
+
+    let mut target = BlockHeader::u256_from_compact_target(prev_header.bits)
+        * Uint256::from_u64(actual_timespan as u64).unwrap()
+        / Uint256::from_u64(timespan as u64).unwrap();
+
+    if target > params.pow_limit {
+        target = params.pow_limit;
+    }
+
+    Ok(BlockHeader::compact_target_from_u256(&target))
+}
+
+// Returns the median timestamp of the most recent ancestors.
+fn median_time_past<'a, I>(ancestors: I) -> u32
+where
+    I: Iterator<Item = &'a BlockHeader>,
+{
+    let mut times: Vec<u32> = ancestors.take(MEDIAN_TIME_SPAN).map(|h| h.time).collect();
+    times.sort_unstable();
+    times.get(times.len() / 2).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_builder::BlockBuilder;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::TxMerkleNode;
+
+    // Builds a chain of `len` blocks on top of the given block, returned in reverse order
+    // (i.e. the tip first), which is the order expected by `validate_block`.
+    fn build_chain(genesis: Block, len: usize) -> Vec<BlockHeader> {
+        let mut headers = vec![genesis.header];
+        for _ in 0..len {
+            let prev_header = *headers.last().unwrap();
+            headers.push(BlockBuilder::with_prev_header(prev_header).build().header);
+        }
+        headers.reverse();
+        headers
+    }
+
+    #[test]
+    fn valid_block() {
+        let genesis = BlockBuilder::genesis().build();
+        let block = BlockBuilder::with_prev_header(genesis.header).build();
+
+        for network in [Network::Bitcoin, Network::Regtest, Network::Testnet].iter() {
+            assert_eq!(
+                validate_block(*network, &block, 0, vec![&genesis.header].into_iter()),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_proof_of_work() {
+        let genesis = BlockBuilder::genesis().build();
+        let mut block = BlockBuilder::with_prev_header(genesis.header).build();
+
+        // Find a nonce that doesn't satisfy the target.
+        while block.header.validate_pow(&block.header.target()).is_ok() {
+            block.header.nonce += 1;
+        }
+
+        assert_eq!(
+            validate_block(
+                Network::Regtest,
+                &block,
+                0,
+                vec![&genesis.header].into_iter()
+            ),
+            Err(BlockValidationError::InvalidProofOfWork)
+        );
+    }
+
+    #[test]
+    fn invalid_merkle_root() {
+        let genesis = BlockBuilder::genesis().build();
+        let mut block = BlockBuilder::with_prev_header(genesis.header).build();
+
+        // Swap the transactions with those of another block.
+        block.txdata = BlockBuilder::with_prev_header(genesis.header)
+            .build()
+            .txdata;
+
+        assert_eq!(
+            validate_block(
+                Network::Regtest,
+                &block,
+                0,
+                vec![&genesis.header].into_iter()
+            ),
+            Err(BlockValidationError::InvalidMerkleRoot)
+        );
+    }
+
+    #[test]
+    fn timestamp_too_old() {
+        let genesis = BlockBuilder::genesis().build();
+        let headers = build_chain(genesis, 20);
+
+        // A block with the same timestamp as the median of the last 11 blocks.
+        let mut block = BlockBuilder::with_prev_header(headers[0]).build();
+        block.header.time = headers[5].time;
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        assert_eq!(
+            validate_block(Network::Regtest, &block, 20, headers.iter()),
+            Err(BlockValidationError::TimestampTooOld {
+                median_time_past: headers[5].time,
+                got: headers[5].time
+            })
+        );
+    }
+
+    #[test]
+    fn incorrect_target() {
+        let genesis = BlockBuilder::genesis().build();
+        let mut block = BlockBuilder::with_prev_header(genesis.header).build();
+
+        // Double the difficulty.
+        let target = block.header.target() / Uint256::from_u64(2).unwrap();
+        block.header.bits = BlockHeader::compact_target_from_u256(&target);
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        assert_eq!(
+            validate_block(
+                Network::Bitcoin,
+                &block,
+                0,
+                vec![&genesis.header].into_iter()
+            ),
+            Err(BlockValidationError::IncorrectTarget {
+                expected: genesis.header.bits,
+                got: block.header.bits
+            })
+        );
+    }
+
+    #[test]
+    fn testnet_min_difficulty_block() {
+        let pow_limit_bits =
+            BlockHeader::compact_target_from_u256(&Params::new(Network::Testnet).pow_limit);
+        let genesis = genesis_block(Network::Testnet);
+
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: genesis.header.block_hash(),
+            merkle_root: TxMerkleNode::default(),
+            time: genesis.header.time + 60 * 30,
+            bits: pow_limit_bits,
+            nonce: 0,
+        };
+
+        // Blocks that come more than 20 minutes after their parent can have the minimum
+        // difficulty on testnet, but not on mainnet.
+        assert_eq!(
+            expected_bits(
+                &Params::new(Network::Testnet),
+                &header,
+                0,
+                vec![&genesis.header].into_iter()
+            ),
+            Ok(pow_limit_bits)
+        );
+        assert_eq!(
+            expected_bits(
+                &Params::new(Network::Bitcoin),
+                &header,
+                0,
+                vec![&genesis_block(Network::Bitcoin).header].into_iter()
+            ),
+            Ok(genesis_block(Network::Bitcoin).header.bits)
+        );
+    }
+
+    #[test]
+    fn retargeting() {
+        let params = Params::new(Network::Bitcoin);
+        let interval = (params.pow_target_timespan / params.pow_target_spacing) as usize;
+
+        // Build a chain of a full difficulty adjustment interval where blocks are mined
+        // eight times faster than the target spacing.
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let mut headers = vec![genesis];
+        for _ in 1..interval {
+            let prev_header = *headers.last().unwrap();
+            headers.push(BlockHeader {
+                prev_blockhash: prev_header.block_hash(),
+                time: prev_header.time + params.pow_target_spacing as u32 / 8,
+                ..prev_header
+            });
+        }
+        headers.reverse();
+
+        let next_header = BlockHeader {
+            prev_blockhash: headers[0].block_hash(),
+            time: headers[0].time + params.pow_target_spacing as u32,
+            ..headers[0]
+        };
+
+        // The adjustment is capped at a factor of four.
+        let expected_target = genesis.target() / Uint256::from_u64(4).unwrap();
+        assert_eq!(
+            expected_bits(&params, &next_header, interval as u32 - 1, headers.iter()),
+            Ok(BlockHeader::compact_target_from_u256(&expected_target))
+        );
+
+        // On regtest, there is no retargeting.
+        assert_eq!(
+            expected_bits(
+                &Params::new(Network::Regtest),
+                &next_header,
+                interval as u32 - 1,
+                headers.iter()
+            ),
+            Ok(headers[0].bits)
+        );
+    }
+}
@@ -4,11 +4,13 @@ use bitcoin::{
     blockdata::script::Builder,
     hashes::Hash,
     secp256k1::{Message, Secp256k1},
+    util::bip143::SigHashCache,
     Address, AddressType, Network, OutPoint, PrivateKey, Script, SigHashType, Transaction, TxIn,
     TxOut, Txid,
 };
 use ic_btc_types::Utxo;
 use ic_cdk::print;
+use types::SignTransactionError;
 
 // The signature hash type that is always used.
 const SIG_HASH_TYPE: SigHashType = SigHashType::All;
@@ -85,27 +87,46 @@ pub fn build_transaction(
 
 /// Sign a bitcoin transaction given the private key and the source address of the funds.
 ///
+/// `spent_values` are the values of the outputs spent by the transaction's inputs, in the
+/// same order. They're only needed for P2WPKH addresses, whose signatures commit to them.
+///
 /// Constraints:
 /// * All the inputs are referencing outpoints that are owned by `src_address`.
-/// * `src_address` is a P2PKH address.
+/// * `src_address` is a P2PKH or a P2WPKH address.
 pub fn sign_transaction(
     mut transaction: Transaction,
     private_key: PrivateKey,
     src_address: Address,
-) -> Transaction {
-    // Verify that the address is P2PKH. The signature algorithm below is specific to P2PKH.
-    match src_address.address_type() {
+    spent_values: &[u64],
+) -> Result<Transaction, SignTransactionError> {
+    let address_type = src_address.address_type();
+    match address_type {
         Some(AddressType::P2pkh) => {}
-        _ => panic!("This demo supports signing p2pkh addresses only."),
+        Some(AddressType::P2wpkh) => {
+            if spent_values.len() != transaction.input.len() {
+                return Err(SignTransactionError::SpentValuesMismatch);
+            }
+        }
+        _ => return Err(SignTransactionError::UnsupportedSourceAddressType),
     };
 
     let secp = Secp256k1::new();
     let txclone = transaction.clone();
+    let mut sighash_cache = SigHashCache::new(&txclone);
     let public_key = private_key.public_key(&Secp256k1::new());
 
     for (index, input) in transaction.input.iter_mut().enumerate() {
-        let sighash =
-            txclone.signature_hash(index, &src_address.script_pubkey(), SIG_HASH_TYPE.as_u32());
+        let sighash = if address_type == Some(AddressType::P2pkh) {
+            txclone.signature_hash(index, &src_address.script_pubkey(), SIG_HASH_TYPE.as_u32())
+        } else {
+            // The script code of P2WPKH is the P2PKH script of the public key (BIP 143).
+            sighash_cache.signature_hash(
+                index,
+                &Script::new_p2pkh(&public_key.pubkey_hash()),
+                spent_values[index],
+                SIG_HASH_TYPE,
+            )
+        };
 
         let signature = secp
             .sign(
@@ -116,12 +137,84 @@ pub fn sign_transaction(
 
         let mut sig_with_hashtype = signature.to_vec();
         sig_with_hashtype.push(SIG_HASH_TYPE.as_u32() as u8);
-        input.script_sig = Builder::new()
-            .push_slice(sig_with_hashtype.as_slice())
-            .push_slice(public_key.to_bytes().as_slice())
-            .into_script();
-        input.witness.clear();
+        if address_type == Some(AddressType::P2pkh) {
+            input.script_sig = Builder::new()
+                .push_slice(sig_with_hashtype.as_slice())
+                .push_slice(public_key.to_bytes().as_slice())
+                .into_script();
+            input.witness.clear();
+        } else {
+            input.script_sig = Script::new();
+            input.witness = vec![sig_with_hashtype, public_key.to_bytes()];
+        }
     }
 
-    transaction
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::Signature;
+
+    fn private_key() -> PrivateKey {
+        PrivateKey::from_wif("L2C1QgyKqNgfV7BpEPAm6PVn2xW8zpXq6MojSbWdH18nGQF2wGsT").unwrap()
+    }
+
+    #[test]
+    fn sign_p2wpkh() {
+        let private_key = private_key();
+        let public_key = private_key.public_key(&Secp256k1::new());
+        let address = Address::p2wpkh(&public_key, Network::Regtest).unwrap();
+        let tx = Transaction {
+            input: vec![TxIn::default(), TxIn::default()],
+            output: vec![],
+            lock_time: 0,
+            version: 2,
+        };
+
+        assert!(matches!(
+            sign_transaction(tx.clone(), private_key, address.clone(), &[1000]),
+            Err(SignTransactionError::SpentValuesMismatch)
+        ));
+
+        let signed = sign_transaction(tx.clone(), private_key, address, &[1000, 2000]).unwrap();
+        let mut sighash_cache = SigHashCache::new(&tx);
+        for (index, value) in [1000, 2000].iter().enumerate() {
+            let input = &signed.input[index];
+            assert!(input.script_sig.is_empty());
+            assert_eq!(input.witness[1], public_key.to_bytes());
+
+            let (sighash_type, signature) = input.witness[0].split_last().unwrap();
+            assert_eq!(*sighash_type, SIG_HASH_TYPE.as_u32() as u8);
+            let sighash = sighash_cache.signature_hash(
+                index,
+                &Script::new_p2pkh(&public_key.pubkey_hash()),
+                *value,
+                SIG_HASH_TYPE,
+            );
+            Secp256k1::new()
+                .verify(
+                    &Message::from_slice(&sighash[..]).unwrap(),
+                    &Signature::from_der(signature).unwrap(),
+                    &public_key.key,
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn sign_unsupported_address_type() {
+        let tx = Transaction {
+            input: vec![TxIn::default()],
+            output: vec![],
+            lock_time: 0,
+            version: 2,
+        };
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        assert!(matches!(
+            sign_transaction(tx, private_key(), address, &[1000]),
+            Err(SignTransactionError::UnsupportedSourceAddressType)
+        ));
+    }
 }
@@ -82,12 +82,14 @@ fn build_transaction(
     Ok((tx.serialize(), used_utxo_indices))
 }
 
+// Signs the transaction, where `spent_values` are the values of the outputs spent by its inputs.
 #[query]
 #[candid_method(query)]
 fn sign_transaction(
     private_key_wif: String,
     serialized_transaction: Vec<u8>,
     source_address: String,
+    spent_values: Vec<u64>,
 ) -> Result<Vec<u8>, SignTransactionError> {
     let private_key = PrivateKey::from_wif(&private_key_wif)
         .map_err(|_| SignTransactionError::InvalidPrivateKeyWif)?;
@@ -95,7 +97,8 @@ fn sign_transaction(
         .map_err(|_| SignTransactionError::MalformedSourceAddress)?;
     let tx: Transaction = deserialize(serialized_transaction.as_slice())
         .map_err(|_| SignTransactionError::MalformedTransaction)?;
-    Ok(example_common::sign_transaction(tx, private_key, source_address).serialize())
+    example_common::sign_transaction(tx, private_key, source_address, &spent_values)
+        .map(|tx| tx.serialize())
 }
 
 fn main() {}
@@ -7,9 +7,11 @@ pub enum BuildTransactionError {
     MalformedSourceAddress,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Debug, Deserialize)]
 pub enum SignTransactionError {
     InvalidPrivateKeyWif,
     MalformedSourceAddress,
     MalformedTransaction,
+    UnsupportedSourceAddressType,
+    SpentValuesMismatch,
 }
@@ -14,7 +14,11 @@ use ic_cdk::{
     print, trap,
 };
 use ic_cdk_macros::{init, query, update};
-use std::{cell::RefCell, collections::HashSet, str::FromStr};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
 // A private key in WIF (wallet import format). This is only for demonstrational purposes.
 // When the Bitcoin integration is released on mainnet, canisters will have the ability
@@ -57,33 +61,47 @@ pub fn btc_address_str() -> String {
 #[update]
 pub async fn get_utxos() -> Vec<Utxo> {
     let btc_canister_id = BTC_CANISTER_ID.with(|id| *id.borrow());
-    #[allow(clippy::type_complexity)]
-    let res: Result<
-        (Result<GetUtxosResponse, Option<GetUtxosError>>,),
-        (RejectionCode, String),
-    > = call(
-        btc_canister_id,
-        "get_utxos",
-        (GetUtxosRequest {
-            address: btc_address_str(),
-            min_confirmations: Some(0),
-        },),
-    )
-    .await;
-
-    match res {
-        // Return the UTXOs to the caller.
-        Ok((Ok(data),)) => data.utxos,
-
-        // The call to `get_utxos` returned an error.
-        Ok((Err(err),)) => trap(&format!("Received error from Bitcoin canister: {:?}", err)),
-
-        // The call to `get_utxos` was rejected.
-        // This is only likely to happen if there's a bug in the bitcoin canister.
-        Err((rejection_code, message)) => trap(&format!(
-            "Received a reject from Bitcoin canister.\nRejection Code: {:?}\nMessage: '{}'",
-            rejection_code, message
-        )),
+    let mut utxos = vec![];
+    let mut page = None;
+
+    // The UTXOs are returned in pages. Keep requesting pages until there are no more.
+    loop {
+        #[allow(clippy::type_complexity)]
+        let res: Result<
+            (Result<GetUtxosResponse, Option<GetUtxosError>>,),
+            (RejectionCode, String),
+        > = call(
+            btc_canister_id,
+            "get_utxos",
+            (GetUtxosRequest {
+                address: btc_address_str(),
+                min_confirmations: Some(0),
+                page,
+                page_size: None,
+            },),
+        )
+        .await;
+
+        match res {
+            // Collect the UTXOs and request the next page, if any.
+            Ok((Ok(data),)) => {
+                utxos.extend(data.utxos);
+                match data.next_page {
+                    Some(next_page) => page = Some(next_page),
+                    None => return utxos,
+                }
+            }
+
+            // The call to `get_utxos` returned an error.
+            Ok((Err(err),)) => trap(&format!("Received error from Bitcoin canister: {:?}", err)),
+
+            // The call to `get_utxos` was rejected.
+            // This is only likely to happen if there's a bug in the bitcoin canister.
+            Err((rejection_code, message)) => trap(&format!(
+                "Received a reject from Bitcoin canister.\nRejection Code: {:?}\nMessage: '{}'",
+                rejection_code, message
+            )),
+        }
     }
 }
 
@@ -143,6 +161,10 @@ pub async fn send(amount: u64, destination: String) {
     let utxos = utxos
         .into_iter()
         .filter(|utxo| SPENT_TXOS.with(|spent_txos| !spent_txos.borrow().contains(&utxo.outpoint)))
+        .collect::<Vec<_>>();
+    let values: HashMap<OutPoint, u64> = utxos
+        .iter()
+        .map(|utxo| (utxo.outpoint.clone(), utxo.value))
         .collect();
 
     let spending_transaction = build_transaction(utxos, btc_address(), destination, amount, fees)
@@ -151,13 +173,19 @@ pub async fn send(amount: u64, destination: String) {
         });
 
     // Cache the spent outputs to not use them for future transactions.
+    let mut spent_values = vec![];
     for tx_in in spending_transaction.input.iter() {
+        let outpoint = OutPoint {
+            txid: tx_in.previous_output.txid.to_vec(),
+            vout: tx_in.previous_output.vout,
+        };
+        spent_values.push(values[&outpoint]);
         SPENT_TXOS.with(|spent_txos| {
-            print(&format!("Caching {:?}", tx_in.previous_output.txid.to_vec()));
-            spent_txos.borrow_mut().insert(OutPoint {
-                txid: tx_in.previous_output.txid.to_vec(),
-                vout: tx_in.previous_output.vout,
-            })
+            print(&format!(
+                "Caching {:?}",
+                tx_in.previous_output.txid.to_vec()
+            ));
+            spent_txos.borrow_mut().insert(outpoint)
         });
     }
 
@@ -168,7 +196,15 @@ pub async fn send(amount: u64, destination: String) {
 
     // Sign transaction
     let private_key = BTC_PRIVATE_KEY.with(|private_key| *private_key.borrow());
-    let signed_transaction = sign_transaction(spending_transaction, private_key, btc_address());
+    let signed_transaction = sign_transaction(
+        spending_transaction,
+        private_key,
+        btc_address(),
+        &spent_values,
+    )
+    .unwrap_or_else(|err| {
+        trap(&format!("Error signing transaction: {:?}", err));
+    });
 
     let signed_transaction_bytes = signed_transaction.serialize();
     print(&format!(
@@ -4,6 +4,18 @@ use ic_cdk::export::candid::{CandidType, Deserialize};
 
 pub type Satoshi = u64;
 
+/// The supported Bitcoin networks.
+///
+/// Note that this is identical to `Network` that's defined in the Bitcoin
+/// crate, with the only difference being that it derives a `CandidType`.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum Network {
+    Bitcoin,
+    Regtest,
+    Testnet,
+    Signet,
+}
+
 /// A reference to a transaction output.
 #[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
 pub struct OutPoint {
@@ -21,23 +33,46 @@ pub struct Utxo {
 }
 
 /// A request for getting the UTXOs for a given address.
+///
+/// UTXOs are returned in pages. To retrieve the next page, `page` is set to the
+/// `next_page` token of the previous response.
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct GetUtxosRequest {
     pub address: String,
     pub min_confirmations: Option<u32>,
+    pub page: Option<Vec<u8>>,
+    pub page_size: Option<u32>,
 }
 
-/// Errors when processing a `get_utxos` request.
+/// A request for getting the UTXOs for a given script_pubkey.
+///
+/// Pagination works the same way as with `GetUtxosRequest`.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct GetUtxosByScriptRequest {
+    pub script_pubkey: Vec<u8>,
+    pub min_confirmations: Option<u32>,
+    pub page: Option<Vec<u8>>,
+    pub page_size: Option<u32>,
+}
+
+/// The response of a `get_utxos` request.
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct GetUtxosResponse {
     pub utxos: Vec<Utxo>,
     pub total_count: u32,
+    pub next_page: Option<Vec<u8>>,
 }
 
 /// Errors when processing a `get_utxos` request.
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub enum GetUtxosError {
     MalformedAddress,
+    /// The address is for a different network than the one the canister is configured for.
+    AddressNetworkMismatch {
+        expected: Network,
+        got: Network,
+    },
+    MalformedPage,
 }
 
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
@@ -49,16 +84,63 @@ pub struct GetBalanceRequest {
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub enum GetBalanceError {
     MalformedAddress,
+    /// The address is for a different network than the one the canister is configured for.
+    AddressNetworkMismatch {
+        expected: Network,
+        got: Network,
+    },
 }
 
+/// Errors of the endpoints that take a batch of requests.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum BatchError {
+    /// The batch has more than `max` requests.
+    TooManyRequests { max: u32 },
+}
+
+/// Converts the error of a `get_utxos` request without a page, e.g. one made to compute a
+/// balance.
+///
+/// # Panics
+///
+/// Panics on `MalformedPage`, as balance requests don't have a page.
 impl From<GetUtxosError> for GetBalanceError {
     fn from(err: GetUtxosError) -> Self {
         match err {
             GetUtxosError::MalformedAddress => Self::MalformedAddress,
+            GetUtxosError::AddressNetworkMismatch { expected, got } => {
+                Self::AddressNetworkMismatch { expected, got }
+            }
+            GetUtxosError::MalformedPage => {
+                unreachable!("A request without a page can't have a malformed page")
+            }
         }
     }
 }
 
+/// A request for getting the balance of a given address, broken down by confirmations.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct GetBalanceBreakdownRequest {
+    pub address: String,
+}
+
+/// The balance of an address, broken down by the confirmations of its UTXOs.
+///
+/// The balance with at least `k` confirmations is `stable` plus the entries of
+/// `unstable_by_confirmations` from index `k - 1` onwards.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct BalanceBreakdown {
+    /// The value of the UTXOs created by stable blocks.
+    pub stable: Satoshi,
+    /// The value of the UTXOs created by the unstable blocks of the current chain.
+    pub unstable: Satoshi,
+    /// The value of the UTXOs created by the unstable blocks of the current chain, where
+    /// the `i`-th entry holds the value of the UTXOs with `i + 1` confirmations.
+    pub unstable_by_confirmations: Vec<Satoshi>,
+    /// The height of the main chain that the breakdown is computed for.
+    pub main_chain_height: u32,
+}
+
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub struct SendTransactionRequest {
     pub transaction: Vec<u8>,
@@ -67,4 +149,150 @@ pub struct SendTransactionRequest {
 #[derive(CandidType, Debug, Deserialize, PartialEq)]
 pub enum SendTransactionError {
     MalformedTransaction,
+    /// An input spends an outpoint that isn't unspent on the current main chain.
+    UnknownInput(OutPoint),
+    /// The outputs are worth more than the inputs, i.e. the implied fee of
+    /// `input_value - output_value` is negative.
+    InsufficientInputValue {
+        input_value: Satoshi,
+        output_value: Satoshi,
+    },
+    /// The transaction has already been sent and is neither mined nor expired.
+    AlreadyQueued,
+}
+
+/// The header of a block.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct BlockHeader {
+    pub hash: Vec<u8>,
+    pub prev_hash: Vec<u8>,
+    pub time: u32,
+    pub bits: u32,
+}
+
+/// The response of a `get_current_chain` request.
+///
+/// `headers` holds the headers of the unstable blocks of the current chain, starting with
+/// the block that follows the anchor, i.e. the latest stable block.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct GetCurrentChainResponse {
+    pub anchor_hash: Vec<u8>,
+    pub stable_height: u32,
+    pub main_chain_height: u32,
+    pub headers: Vec<BlockHeader>,
+}
+
+/// A request for getting the status of a transaction.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct GetTransactionStatusRequest {
+    pub txid: Vec<u8>,
+}
+
+/// The status of a transaction.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum TransactionStatus {
+    /// The transaction is in a block of the current main chain.
+    Confirmed {
+        block_hash: Vec<u8>,
+        height: u32,
+        confirmations: u32,
+    },
+    /// The transaction was sent with `send_transaction`, but isn't in a block yet.
+    Pending,
+    /// The transaction isn't known.
+    Unknown,
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum GetTransactionStatusError {
+    MalformedTxid,
+}
+
+/// The state of a transaction submitted with `send_transaction`.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum SentTransactionState {
+    /// The transaction hasn't been sent to the Bitcoin network yet.
+    Pending,
+    /// The transaction has been sent to the Bitcoin network `count` times.
+    Broadcast { count: u32 },
+    /// The transaction has been observed in a stable block.
+    Mined,
+    /// The transaction wasn't observed in a stable block within 24 hours, and is no longer
+    /// sent.
+    Expired,
+}
+
+/// A transaction submitted with `send_transaction`.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct SentTransaction {
+    pub txid: Vec<u8>,
+    pub state: SentTransactionState,
+}
+
+/// Errors that can occur when uploading a snapshot.
+///
+/// On any error other than `NotExpected` and `Unauthorized`, the upload starts over.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum UploadSnapshotError {
+    /// The canister wasn't initialized with a snapshot, or it was already loaded.
+    NotExpected,
+    /// The caller isn't the principal that installed the canister.
+    Unauthorized,
+    /// The snapshot couldn't be decoded.
+    Malformed,
+    /// The snapshot is of a different network than the canister's.
+    NetworkMismatch,
+    /// The upload was finished before the whole snapshot was uploaded.
+    Incomplete,
+    /// The hash of the snapshot differs from the one the canister was initialized with.
+    HashMismatch,
+}
+
+/// Errors that can occur when preparing an upgrade.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum PrepareUpgradeError {
+    /// The caller isn't the principal that installed or last upgraded the canister.
+    Unauthorized,
+    /// A snapshot is being uploaded, or the state isn't read completely since the last upgrade.
+    Busy,
+}
+
+/// Statistics about the state of the canister.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct Metrics {
+    pub stable_height: u32,
+    pub main_chain_height: u32,
+    /// The number of unstable blocks, including those not on the main chain.
+    pub unstable_blocks: u64,
+    /// The number of trees that the unstable blocks form.
+    pub unstable_trees: u64,
+    /// The number of stable UTXOs.
+    pub utxos: u64,
+    /// The number of scripts that the stable UTXOs are indexed by.
+    pub indexed_scripts: u64,
+    /// The number of transactions submitted with `send_transaction` that are still being sent.
+    pub outgoing_transactions: u64,
+    /// The size of the canister's heap in bytes.
+    pub heap_size: u64,
+    /// The number of instructions used to process the last response of the adapter, if
+    /// the canister can count instructions and a response was processed.
+    pub last_sync_instructions: Option<u64>,
+}
+
+/// The hash of the stable UTXOs.
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct UtxoSetHash {
+    /// The MuHash of the UTXOs, as computed by Bitcoin Core's `gettxoutsetinfo muhash`,
+    /// with its bytes in reverse order.
+    pub hash: Vec<u8>,
+    /// The height of the latest stable block.
+    pub stable_height: u32,
+}
+
+/// A block that the canister received but didn't insert.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct InvalidBlock {
+    pub block_hash: Vec<u8>,
+    /// A description of why the block was rejected.
+    pub reason: String,
 }